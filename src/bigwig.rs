@@ -0,0 +1,764 @@
+//! Minimal bigWig writer (bedGraph sections, zlib compressed, with zoom levels), following
+//! the layout written by UCSC's bedGraphToBigWig: header, zoom headers, total summary,
+//! chromosome B+ tree, data sections with their R-tree index, then each zoom level.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const BPT_MAGIC: u32 = 0x78CA_8C91;
+const CIRTREE_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const SUMMARY_SIZE: u64 = 40;
+/// UCSC reserves this many zoom header slots up front, before the data is seen.
+const MAX_ZOOM_LEVELS: usize = 10;
+const ITEMS_PER_SLOT: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+/// Section type for bedGraph-style (start, end, value) items.
+const SECTION_BEDGRAPH: u8 = 1;
+
+/// Running min/max/sum/sum-of-squares over covered bases.
+#[derive(Clone, Copy)]
+struct Summary {
+    bases: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl Summary {
+    fn new() -> Self {
+        Summary {
+            bases: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            sum_squares: 0.0,
+        }
+    }
+
+    fn add(&mut self, value: f64, bases: u32) {
+        let n = bases as f64;
+        self.bases += bases as u64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * n;
+        self.sum_squares += value * value * n;
+    }
+}
+
+/// One zoom record: the summary of a `reduction`-sized window.
+struct ZoomRecord {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    summary: Summary,
+}
+
+struct ZoomLevel {
+    reduction: u32,
+    current: Option<ZoomRecord>,
+    records: Vec<ZoomRecord>,
+}
+
+impl ZoomLevel {
+    fn add(&mut self, chrom_id: u32, start: u32, end: u32, value: f64) {
+        let mut pos = start;
+        while pos < end {
+            let bucket = pos - pos % self.reduction;
+            let stop = end.min(bucket.saturating_add(self.reduction));
+            let reduction = self.reduction;
+            let same_bucket = self.current.as_ref().is_some_and(|z| {
+                z.chrom_id == chrom_id && z.start - z.start % reduction == bucket
+            });
+            if !same_bucket {
+                self.records.extend(self.current.take());
+                self.current = Some(ZoomRecord {
+                    chrom_id,
+                    start: pos,
+                    end: stop,
+                    summary: Summary::new(),
+                });
+            }
+            let zoom = self.current.as_mut().unwrap();
+            zoom.end = stop;
+            zoom.summary.add(value, stop - pos);
+            pos = stop;
+        }
+    }
+}
+
+/// An R-tree leaf entry: the genomic extent of one compressed block and where it lives.
+struct BlockIndex {
+    start_chrom: u32,
+    start: u32,
+    end_chrom: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// Streaming bigWig writer. Intervals must be added in chromosome order (the order of
+/// `chroms`) and by increasing start within a chromosome.
+pub struct BigWigWriter {
+    out: BufWriter<File>,
+    chrom_ids: HashMap<String, u32>,
+    chrom_sizes: Vec<u32>,
+    data_offset: u64,
+    section: Vec<(u32, u32, f32)>,
+    section_chrom: u32,
+    last: Option<(u32, u32)>,
+    blocks: Vec<BlockIndex>,
+    summary: Summary,
+    zooms: Vec<ZoomLevel>,
+    max_block_size: usize,
+}
+
+impl BigWigWriter {
+    /// Create `path` for the given `(name, size)` chromosomes. Zoom levels start at
+    /// `initial_reduction` bases and grow fourfold, up to the longest chromosome.
+    pub fn create(
+        path: &Path,
+        chroms: &[(String, u32)],
+        initial_reduction: u32,
+    ) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // Header, zoom headers and the total summary are patched in by `finish`
+        let preamble = HEADER_SIZE + ZOOM_HEADER_SIZE * MAX_ZOOM_LEVELS as u64 + SUMMARY_SIZE;
+        out.write_all(&vec![0u8; preamble as usize])?;
+        write_chrom_tree(&mut out, chroms)?;
+        let data_offset = out.stream_position()?;
+        out.write_all(&0u64.to_le_bytes())?;
+
+        let longest = chroms.iter().map(|(_, size)| *size).max().unwrap_or(0);
+        let mut zooms = Vec::new();
+        let mut reduction = initial_reduction.max(1);
+        while zooms.len() < MAX_ZOOM_LEVELS && reduction <= longest {
+            zooms.push(ZoomLevel {
+                reduction,
+                current: None,
+                records: Vec::new(),
+            });
+            reduction = match reduction.checked_mul(4) {
+                Some(r) => r,
+                None => break,
+            };
+        }
+
+        Ok(BigWigWriter {
+            out,
+            chrom_ids: chroms
+                .iter()
+                .enumerate()
+                .map(|(i, (name, _))| (name.clone(), i as u32))
+                .collect(),
+            chrom_sizes: chroms.iter().map(|(_, size)| *size).collect(),
+            data_offset,
+            section: Vec::with_capacity(ITEMS_PER_SLOT),
+            section_chrom: 0,
+            last: None,
+            blocks: Vec::new(),
+            summary: Summary::new(),
+            zooms,
+            max_block_size: 0,
+        })
+    }
+
+    /// Add one interval with its value.
+    pub fn add(&mut self, chrom: &str, start: u32, end: u32, value: f32) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let chrom_id = *self
+            .chrom_ids
+            .get(chrom)
+            .ok_or_else(|| invalid(format!("chromosome {} is not in the chrom sizes", chrom)))?;
+        if start >= end || end > self.chrom_sizes[chrom_id as usize] {
+            return Err(invalid(format!("invalid interval {}:{}-{}", chrom, start, end)));
+        }
+        if self.last.is_some_and(|last| (chrom_id, start) < last) {
+            return Err(invalid(format!("interval {}:{}-{} is out of order", chrom, start, end)));
+        }
+        self.last = Some((chrom_id, end));
+
+        if !self.section.is_empty()
+            && (self.section_chrom != chrom_id || self.section.len() == ITEMS_PER_SLOT)
+        {
+            self.flush_section()?;
+        }
+        self.section_chrom = chrom_id;
+        self.section.push((start, end, value));
+
+        self.summary.add(value as f64, end - start);
+        for zoom in &mut self.zooms {
+            zoom.add(chrom_id, start, end, value as f64);
+        }
+        Ok(())
+    }
+
+    fn flush_section(&mut self) -> io::Result<()> {
+        let (first, last) = (self.section[0], self.section[self.section.len() - 1]);
+        let mut block = Vec::with_capacity(24 + 12 * self.section.len());
+        for v in [self.section_chrom, first.0, last.1, 0, 0] {
+            block.extend_from_slice(&v.to_le_bytes());
+        }
+        block.push(SECTION_BEDGRAPH);
+        block.push(0);
+        block.extend_from_slice(&(self.section.len() as u16).to_le_bytes());
+        for (start, end, value) in &self.section {
+            block.extend_from_slice(&start.to_le_bytes());
+            block.extend_from_slice(&end.to_le_bytes());
+            block.extend_from_slice(&value.to_le_bytes());
+        }
+        let (offset, size) = self.write_block(&block)?;
+        self.blocks.push(BlockIndex {
+            start_chrom: self.section_chrom,
+            start: first.0,
+            end_chrom: self.section_chrom,
+            end: last.1,
+            offset,
+            size,
+        });
+        self.section.clear();
+        Ok(())
+    }
+
+    /// Compress and write one block, returning its offset and compressed size.
+    fn write_block(&mut self, block: &[u8]) -> io::Result<(u64, u64)> {
+        self.max_block_size = self.max_block_size.max(block.len());
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(block)?;
+        let compressed = encoder.finish()?;
+        let offset = self.out.stream_position()?;
+        self.out.write_all(&compressed)?;
+        Ok((offset, compressed.len() as u64))
+    }
+
+    /// Write the index and zoom levels and fill in the header.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.section.is_empty() {
+            self.flush_section()?;
+        }
+        let section_count = self.blocks.len() as u64;
+        let index_offset = self.out.stream_position()?;
+        let blocks = std::mem::take(&mut self.blocks);
+        write_rtree(&mut self.out, &blocks, index_offset)?;
+
+        let mut zoom_headers = Vec::new();
+        for mut zoom in std::mem::take(&mut self.zooms) {
+            zoom.records.extend(zoom.current.take());
+            if zoom.records.is_empty() {
+                continue;
+            }
+            let zoom_data = self.out.stream_position()?;
+            self.out.write_all(&(zoom.records.len() as u32).to_le_bytes())?;
+            let mut zoom_blocks = Vec::new();
+            for chunk in zoom.records.chunks(ITEMS_PER_SLOT) {
+                let mut block = Vec::with_capacity(32 * chunk.len());
+                for z in chunk {
+                    for v in [z.chrom_id, z.start, z.end, z.summary.bases as u32] {
+                        block.extend_from_slice(&v.to_le_bytes());
+                    }
+                    for v in [z.summary.min, z.summary.max, z.summary.sum, z.summary.sum_squares] {
+                        block.extend_from_slice(&(v as f32).to_le_bytes());
+                    }
+                }
+                let (offset, size) = self.write_block(&block)?;
+                let (first, last) = (&chunk[0], &chunk[chunk.len() - 1]);
+                zoom_blocks.push(BlockIndex {
+                    start_chrom: first.chrom_id,
+                    start: first.start,
+                    end_chrom: last.chrom_id,
+                    end: last.end,
+                    offset,
+                    size,
+                });
+            }
+            let zoom_index = self.out.stream_position()?;
+            write_rtree(&mut self.out, &zoom_blocks, zoom_index)?;
+            zoom_headers.push((zoom.reduction, zoom_data, zoom_index));
+        }
+
+        let summary_offset = HEADER_SIZE + ZOOM_HEADER_SIZE * MAX_ZOOM_LEVELS as u64;
+        let chrom_tree_offset = summary_offset + SUMMARY_SIZE;
+        let out = &mut self.out;
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(zoom_headers.len() as u16).to_le_bytes())?;
+        out.write_all(&chrom_tree_offset.to_le_bytes())?;
+        out.write_all(&self.data_offset.to_le_bytes())?;
+        out.write_all(&index_offset.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?; // field count
+        out.write_all(&0u16.to_le_bytes())?; // defined field count
+        out.write_all(&0u64.to_le_bytes())?; // autoSql offset
+        out.write_all(&summary_offset.to_le_bytes())?;
+        out.write_all(&(self.max_block_size as u32).to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?; // extension offset
+        for (reduction, data, index) in &zoom_headers {
+            out.write_all(&reduction.to_le_bytes())?;
+            out.write_all(&0u32.to_le_bytes())?;
+            out.write_all(&data.to_le_bytes())?;
+            out.write_all(&index.to_le_bytes())?;
+        }
+
+        let s = &self.summary;
+        let (min, max) = if s.bases == 0 { (0.0, 0.0) } else { (s.min, s.max) };
+        out.seek(SeekFrom::Start(summary_offset))?;
+        out.write_all(&s.bases.to_le_bytes())?;
+        for v in [min, max, s.sum, s.sum_squares] {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        out.seek(SeekFrom::Start(self.data_offset))?;
+        out.write_all(&section_count.to_le_bytes())?;
+        out.flush()
+    }
+}
+
+/// Write the chromosome name -> (id, size) B+ tree, keys sorted bytewise.
+fn write_chrom_tree<W: Write + Seek>(out: &mut W, chroms: &[(String, u32)]) -> io::Result<()> {
+    let mut items: Vec<(&[u8], u32, u32)> = chroms
+        .iter()
+        .enumerate()
+        .map(|(i, (name, size))| (name.as_bytes(), i as u32, *size))
+        .collect();
+    items.sort();
+    let key_size = items.iter().map(|(k, _, _)| k.len()).max().unwrap_or(1).max(1);
+    let block_size = items.len().clamp(1, BLOCK_SIZE);
+    let item_size = key_size as u64 + 8;
+
+    out.write_all(&BPT_MAGIC.to_le_bytes())?;
+    out.write_all(&(block_size as u32).to_le_bytes())?;
+    out.write_all(&(key_size as u32).to_le_bytes())?;
+    out.write_all(&8u32.to_le_bytes())?;
+    out.write_all(&(items.len() as u64).to_le_bytes())?;
+    out.write_all(&0u64.to_le_bytes())?;
+
+    let key = |k: &[u8]| {
+        let mut padded = k.to_vec();
+        padded.resize(key_size, 0);
+        padded
+    };
+
+    // levels[0] is the leaves; each upper level holds the first key of each child node
+    let mut levels: Vec<Vec<&[u8]>> = vec![items.iter().map(|(k, _, _)| *k).collect()];
+    while levels.last().unwrap().len() > block_size {
+        let upper = levels
+            .last()
+            .unwrap()
+            .chunks(block_size)
+            .map(|chunk| chunk[0])
+            .collect();
+        levels.push(upper);
+    }
+
+    let mut offset = out.stream_position()?;
+    // Offsets of the first node of each level, written root first
+    let mut level_offsets = vec![0u64; levels.len()];
+    for depth in (0..levels.len()).rev() {
+        level_offsets[depth] = offset;
+        let nodes = levels[depth].len().div_ceil(block_size) as u64;
+        offset += nodes * 4 + levels[depth].len() as u64 * item_size;
+    }
+
+    for depth in (0..levels.len()).rev() {
+        for (n, chunk) in levels[depth].chunks(block_size).enumerate() {
+            out.write_all(&[(depth == 0) as u8, 0])?;
+            out.write_all(&(chunk.len() as u16).to_le_bytes())?;
+            for (i, k) in chunk.iter().enumerate() {
+                out.write_all(&key(k))?;
+                if depth == 0 {
+                    let (_, id, size) = items[n * block_size + i];
+                    out.write_all(&id.to_le_bytes())?;
+                    out.write_all(&size.to_le_bytes())?;
+                } else {
+                    let child = n * block_size + i;
+                    let items_before = (child * block_size).min(levels[depth - 1].len()) as u64;
+                    let child_offset =
+                        level_offsets[depth - 1] + child as u64 * 4 + items_before * item_size;
+                    out.write_all(&child_offset.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the R-tree (UCSC cirTree) indexing `blocks`, starting at `start`.
+fn write_rtree<W: Write>(out: &mut W, blocks: &[BlockIndex], start: u64) -> io::Result<()> {
+    const HEADER: u64 = 48;
+    const LEAF_ITEM: u64 = 32;
+    const NODE_ITEM: u64 = 24;
+
+    let first = blocks.first();
+    let last = blocks.last();
+    let end_file_offset = last.map_or(start, |b| b.offset + b.size);
+    out.write_all(&CIRTREE_MAGIC.to_le_bytes())?;
+    out.write_all(&(BLOCK_SIZE as u32).to_le_bytes())?;
+    out.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    out.write_all(&first.map_or(0, |b| b.start_chrom).to_le_bytes())?;
+    out.write_all(&first.map_or(0, |b| b.start).to_le_bytes())?;
+    out.write_all(&last.map_or(0, |b| b.end_chrom).to_le_bytes())?;
+    out.write_all(&last.map_or(0, |b| b.end).to_le_bytes())?;
+    out.write_all(&end_file_offset.to_le_bytes())?;
+    out.write_all(&(ITEMS_PER_SLOT as u32).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    // levels[0] holds the leaf entries; each upper level bounds BLOCK_SIZE children
+    let mut levels: Vec<Vec<(u32, u32, u32, u32)>> = vec![blocks
+        .iter()
+        .map(|b| (b.start_chrom, b.start, b.end_chrom, b.end))
+        .collect()];
+    while levels.last().unwrap().len() > BLOCK_SIZE {
+        let upper = levels
+            .last()
+            .unwrap()
+            .chunks(BLOCK_SIZE)
+            .map(|chunk| {
+                let end = chunk.iter().map(|c| (c.2, c.3)).max().unwrap();
+                (chunk[0].0, chunk[0].1, end.0, end.1)
+            })
+            .collect();
+        levels.push(upper);
+    }
+
+    let mut offset = start + HEADER;
+    let mut level_offsets = vec![0u64; levels.len()];
+    for depth in (0..levels.len()).rev() {
+        level_offsets[depth] = offset;
+        let item = if depth == 0 { LEAF_ITEM } else { NODE_ITEM };
+        let nodes = levels[depth].len().div_ceil(BLOCK_SIZE).max(1) as u64;
+        offset += nodes * 4 + levels[depth].len() as u64 * item;
+    }
+
+    for depth in (0..levels.len()).rev() {
+        if levels[depth].is_empty() {
+            // An empty tree still needs a root to read
+            out.write_all(&[1, 0, 0, 0])?;
+            continue;
+        }
+        for (n, chunk) in levels[depth].chunks(BLOCK_SIZE).enumerate() {
+            out.write_all(&[(depth == 0) as u8, 0])?;
+            out.write_all(&(chunk.len() as u16).to_le_bytes())?;
+            for (i, bounds) in chunk.iter().enumerate() {
+                for v in [bounds.0, bounds.1, bounds.2, bounds.3] {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+                let index = n * BLOCK_SIZE + i;
+                if depth == 0 {
+                    out.write_all(&blocks[index].offset.to_le_bytes())?;
+                    out.write_all(&blocks[index].size.to_le_bytes())?;
+                } else {
+                    let below = &levels[depth - 1];
+                    let items_before = (index * BLOCK_SIZE).min(below.len()) as u64;
+                    let item = if depth == 1 { LEAF_ITEM } else { NODE_ITEM };
+                    let child = level_offsets[depth - 1] + index as u64 * 4 + items_before * item;
+                    out.write_all(&child.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    /// One data item as `(chrom, start, end, value)`.
+    type Interval = (String, u32, u32, f32);
+
+    /// A bigWig read back by [`read_bigwig`], which checks its structure on the way.
+    struct BigWig {
+        /// `(name, id, size)` of each chromosome, in B+ tree key order
+        chroms: Vec<(String, u32, u32)>,
+        /// Every data item as `(chrom, start, end, value)`, in file order
+        intervals: Vec<Interval>,
+        /// Bases covered and the sum of values over them, from the total summary
+        bases: u64,
+        sum: f64,
+        /// Reduction, bases covered and sum of values of each zoom level
+        zooms: Vec<(u32, u64, f64)>,
+    }
+
+    /// `N` little-endian bytes of `bytes` at `at`.
+    fn le<const N: usize>(bytes: &[u8], at: usize) -> [u8; N] {
+        bytes[at..at + N].try_into().unwrap()
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(le(bytes, at))
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> usize {
+        u64::from_le_bytes(le(bytes, at)) as usize
+    }
+
+    fn f32_at(bytes: &[u8], at: usize) -> f32 {
+        f32::from_le_bytes(le(bytes, at))
+    }
+
+    /// Leaves of the chromosome B+ tree node at `at`, as `(name, id, size)`.
+    fn chrom_tree_leaves(
+        bytes: &[u8],
+        at: usize,
+        key_size: usize,
+        out: &mut Vec<(String, u32, u32)>,
+    ) {
+        let leaf = bytes[at] == 1;
+        let count = u16::from_le_bytes(le(bytes, at + 2)) as usize;
+        for i in 0..count {
+            let item = at + 4 + i * (key_size + 8);
+            let key = &bytes[item..item + key_size];
+            let name = String::from_utf8(key.iter().copied().take_while(|&b| b != 0).collect());
+            let name = name.unwrap();
+            if leaf {
+                let id = u32_at(bytes, item + key_size);
+                let size = u32_at(bytes, item + key_size + 4);
+                out.push((name, id, size));
+            } else {
+                // An inner key is the first key below it
+                let first = out.len();
+                chrom_tree_leaves(bytes, u64_at(bytes, item + key_size), key_size, out);
+                assert_eq!(out[first].0, name);
+            }
+        }
+    }
+
+    /// Leaves of the cirTree node at `at`, as `(start chrom, start, end chrom, end, offset,
+    /// size)`; each inner node's bounds must be those of the leaves below it.
+    fn rtree_leaves(bytes: &[u8], at: usize, out: &mut Vec<(u32, u32, u32, u32, usize, usize)>) {
+        let leaf = bytes[at] == 1;
+        let count = u16::from_le_bytes(le(bytes, at + 2)) as usize;
+        for i in 0..count {
+            let item = at + 4 + i * if leaf { 32 } else { 24 };
+            let bounds = [0, 4, 8, 12].map(|field| u32_at(bytes, item + field));
+            if leaf {
+                let (offset, size) = (u64_at(bytes, item + 16), u64_at(bytes, item + 24));
+                out.push((bounds[0], bounds[1], bounds[2], bounds[3], offset, size));
+            } else {
+                let first = out.len();
+                rtree_leaves(bytes, u64_at(bytes, item + 16), out);
+                let below = &out[first..];
+                assert_eq!((below[0].0, below[0].1), (bounds[0], bounds[1]));
+                let end = below.iter().map(|l| (l.2, l.3)).max().unwrap();
+                assert_eq!(end, (bounds[2], bounds[3]));
+            }
+        }
+    }
+
+    /// Leaves of the cirTree at `at`, checking its header.
+    fn rtree(bytes: &[u8], at: usize) -> Vec<(u32, u32, u32, u32, usize, usize)> {
+        assert_eq!(u32_at(bytes, at), CIRTREE_MAGIC);
+        let mut leaves = Vec::new();
+        rtree_leaves(bytes, at + 48, &mut leaves);
+        assert_eq!(leaves.len(), u64_at(bytes, at + 8));
+        if let (Some(first), Some(last)) = (leaves.first(), leaves.last()) {
+            assert_eq!((u32_at(bytes, at + 16), u32_at(bytes, at + 20)), (first.0, first.1));
+            assert_eq!((u32_at(bytes, at + 24), u32_at(bytes, at + 28)), (last.2, last.3));
+        }
+        leaves
+    }
+
+    /// The decompressed block a cirTree leaf points to, no larger than the header's buffer
+    /// size.
+    fn block(bytes: &[u8], offset: usize, size: usize) -> Vec<u8> {
+        let mut block = Vec::new();
+        ZlibDecoder::new(&bytes[offset..offset + size])
+            .read_to_end(&mut block)
+            .unwrap();
+        assert!(block.len() <= u32_at(bytes, 52) as usize);
+        block
+    }
+
+    /// Read a bigWig of bedGraph sections, checking the header, the chromosome tree, the data
+    /// and zoom indexes against the blocks they point to, and the zoom summaries.
+    fn read_bigwig(path: &Path) -> BigWig {
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(u32_at(&bytes, 0), BIGWIG_MAGIC);
+        assert!(u16::from_le_bytes(le(&bytes, 4)) >= VERSION);
+        let zoom_levels = u16::from_le_bytes(le(&bytes, 6)) as usize;
+        let (chrom_tree, data) = (u64_at(&bytes, 8), u64_at(&bytes, 16));
+        let index = u64_at(&bytes, 24);
+
+        assert_eq!(u32_at(&bytes, chrom_tree), BPT_MAGIC);
+        let key_size = u32_at(&bytes, chrom_tree + 8) as usize;
+        assert_eq!(u32_at(&bytes, chrom_tree + 12), 8);
+        let mut chroms = Vec::new();
+        chrom_tree_leaves(&bytes, chrom_tree + 32, key_size, &mut chroms);
+        assert_eq!(chroms.len(), u64_at(&bytes, chrom_tree + 16));
+        assert!(chroms.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let names: HashMap<u32, String> =
+            chroms.iter().map(|(name, id, _)| (*id, name.clone())).collect();
+
+        let leaves = rtree(&bytes, index);
+        assert_eq!(leaves.len(), u64_at(&bytes, data));
+        let mut intervals = Vec::new();
+        for (chrom, start, end_chrom, end, offset, size) in leaves {
+            let section = block(&bytes, offset, size);
+            let header = [0, 4, 8].map(|field| u32_at(&section, field));
+            assert_eq!(header, [chrom, start, end]);
+            assert_eq!(chrom, end_chrom);
+            assert_eq!(section[20], SECTION_BEDGRAPH);
+            let count = u16::from_le_bytes(le(&section, 22)) as usize;
+            assert_eq!(section.len(), 24 + 12 * count);
+            for item in (0..count).map(|i| 24 + 12 * i) {
+                let (start, end) = (u32_at(&section, item), u32_at(&section, item + 4));
+                intervals.push((names[&chrom].clone(), start, end, f32_at(&section, item + 8)));
+            }
+        }
+
+        let summary = u64_at(&bytes, 44);
+        let bases = u64::from_le_bytes(le(&bytes, summary));
+        let sum = f64::from_le_bytes(le(&bytes, summary + 24));
+
+        let mut zooms = Vec::new();
+        for level in 0..zoom_levels {
+            let header = HEADER_SIZE as usize + ZOOM_HEADER_SIZE as usize * level;
+            let reduction = u32_at(&bytes, header);
+            let (data, index) = (u64_at(&bytes, header + 8), u64_at(&bytes, header + 16));
+            let (mut records, mut zoom_bases, mut zoom_sum) = (0, 0, 0.0);
+            for (_, _, _, _, offset, size) in rtree(&bytes, index) {
+                let block = block(&bytes, offset, size);
+                assert_eq!(block.len() % 32, 0);
+                for record in block.chunks(32) {
+                    assert!(u32_at(record, 8) - u32_at(record, 4) <= reduction);
+                    zoom_bases += u32_at(record, 12) as u64;
+                    zoom_sum += f32_at(record, 24) as f64;
+                    records += 1;
+                }
+            }
+            assert_eq!(records, u32_at(&bytes, data));
+            zooms.push((reduction, zoom_bases, zoom_sum));
+        }
+        assert!(zooms.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        BigWig {
+            chroms,
+            intervals,
+            bases,
+            sum,
+            zooms,
+        }
+    }
+
+    /// Over 256 chromosomes and data blocks, so both of the file's trees need a second level,
+    /// and one chromosome long enough to fill several sections. Returns the chromosomes and
+    /// the intervals, in chromosome order.
+    fn fixture() -> (Vec<(String, u32)>, Vec<Interval>) {
+        let mut chroms = vec![("chrBig".to_string(), 120_000)];
+        let mut intervals = Vec::new();
+        for i in 0..3000 {
+            let start = i * 40;
+            let value = (i % 7) as f32 + 0.5;
+            intervals.push(("chrBig".to_string(), start, start + 40 - i % 3, value));
+        }
+        for i in 0..300 {
+            chroms.push((format!("chr{}", i), 1000));
+            let start = i * 7 % 900;
+            intervals.push((format!("chr{}", i), start, start + 80, (i % 5 + 1) as f32));
+        }
+        (chroms, intervals)
+    }
+
+    fn write(path: &Path, chroms: &[(String, u32)], intervals: &[Interval]) {
+        let mut writer = BigWigWriter::create(path, chroms, 500).unwrap();
+        for (chrom, start, end, value) in intervals {
+            writer.add(chrom, *start, *end, *value).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// Bases covered by `intervals` and the sum of their values over them.
+    fn coverage_totals(intervals: &[Interval]) -> (u64, f64) {
+        let bases = intervals.iter().map(|i| (i.2 - i.1) as u64).sum();
+        let sum = intervals.iter().map(|i| i.3 as f64 * (i.2 - i.1) as f64).sum();
+        (bases, sum)
+    }
+
+    #[test]
+    fn reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("many.bw");
+        let (chroms, intervals) = fixture();
+        write(&path, &chroms, &intervals);
+
+        let bigwig = read_bigwig(&path);
+        assert_eq!(bigwig.intervals, intervals);
+        // Chromosome ids are the order given, keys sorted bytewise
+        let mut listed: Vec<(String, u32, u32)> = chroms
+            .iter()
+            .enumerate()
+            .map(|(id, (name, size))| (name.clone(), id as u32, *size))
+            .collect();
+        listed.sort();
+        assert_eq!(bigwig.chroms, listed);
+
+        let (bases, sum) = coverage_totals(&intervals);
+        assert_eq!(bigwig.bases, bases);
+        assert!((bigwig.sum - sum).abs() < 1e-9 * sum);
+        assert!(bigwig.zooms.len() > 1);
+        for (_, zoom_bases, zoom_sum) in &bigwig.zooms {
+            assert_eq!(*zoom_bases, bases);
+            // Zoom records store single precision sums
+            assert!((zoom_sum - sum).abs() < 1e-4 * sum);
+        }
+    }
+
+    #[test]
+    fn matches_bedgraphtobigwig() {
+        let available = Command::new("bedGraphToBigWig")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        if !available {
+            eprintln!("skipping: bedGraphToBigWig not found");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (chroms, intervals) = fixture();
+        let native = dir.path().join("native.bw");
+        write(&native, &chroms, &intervals);
+
+        // bedGraphToBigWig wants its input sorted by name
+        let mut sorted = intervals.clone();
+        sorted.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        let bedgraph: String = sorted
+            .iter()
+            .map(|(chrom, start, end, value)| format!("{}\t{}\t{}\t{}\n", chrom, start, end, value))
+            .collect();
+        let sizes: String = chroms
+            .iter()
+            .map(|(name, size)| format!("{}\t{}\n", name, size))
+            .collect();
+        let bedgraph_path = dir.path().join("in.bedGraph");
+        let sizes_path = dir.path().join("chrom.sizes");
+        std::fs::write(&bedgraph_path, bedgraph).unwrap();
+        std::fs::write(&sizes_path, sizes).unwrap();
+        let ucsc = dir.path().join("ucsc.bw");
+        let status = Command::new("bedGraphToBigWig")
+            .args([&bedgraph_path, &sizes_path, &ucsc])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // The native writer stores the same chromosomes, intervals and values
+        let (mut native, ucsc) = (read_bigwig(&native), read_bigwig(&ucsc));
+        native.intervals.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        assert_eq!(native.intervals, ucsc.intervals);
+        let chroms = |bigwig: &BigWig| -> Vec<(String, u32)> {
+            bigwig.chroms.iter().map(|(name, _, size)| (name.clone(), *size)).collect()
+        };
+        assert_eq!(chroms(&native), chroms(&ucsc));
+        assert_eq!(native.bases, ucsc.bases);
+        assert!((native.sum - ucsc.sum).abs() < 1e-9 * ucsc.sum);
+    }
+}
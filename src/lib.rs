@@ -0,0 +1,7545 @@
+//! Downsample fragment BED or BAM files to a common depth and write binned coverage
+//! bigWigs. [`Pipeline`] runs the whole workflow; the `bedfragment_ds` binary is a thin
+//! command line wrapper around it.
+
+mod bigwig;
+
+use bigwig::BigWigWriter;
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Error returned by the pipeline and by each sample's processing.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Reading or writing a file failed
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// An external tool could not be started
+    #[error("cannot run {tool}: {source}")]
+    ToolNotFound {
+        tool: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// An external tool ran but exited unsuccessfully, with the last lines it wrote to stderr
+    #[error("{step} failed ({status}){}", indented_lines(.stderr))]
+    ToolFailed {
+        step: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// An input file or tool output could not be parsed
+    #[error("{0}")]
+    Parse(String),
+    /// The pipeline settings are invalid or incomplete
+    #[error("{0}")]
+    Config(String),
+    /// Fragments could not be counted in some inputs
+    #[error("could not count fragments in {} file(s)", .0.len())]
+    Counting(Vec<(PathBuf, Error)>),
+    /// External tools the run needs cannot be started
+    #[error("Required external tools not found:{0}")]
+    MissingTools(String),
+    /// The worker thread pool could not be created
+    #[error("cannot start the thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    /// Every sample was excluded by QC
+    #[error("No samples pass the QC cutoff")]
+    NoSamplesPassQc,
+    /// The sample was skipped because an earlier one failed with fail-fast set
+    #[error("not run: aborted after an earlier failure (--fail-fast)")]
+    Aborted,
+    /// Reading a BAM file with htslib failed
+    #[cfg(feature = "htslib")]
+    #[error("{}: {source}", path.display())]
+    Htslib {
+        path: PathBuf,
+        #[source]
+        source: rust_htslib::errors::Error,
+    },
+}
+
+impl Error {
+    /// Wrap an I/O error with the path it concerns, for use with `map_err`.
+    fn io(path: &Path) -> impl Fn(std::io::Error) -> Error + '_ {
+        move |source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    Bed,
+    Bam,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QcMethod {
+    /// Mean +/- k standard deviations
+    Zscore,
+    /// Median +/- k scaled median absolute deviations
+    Mad,
+    /// Tukey fences: Q1 - k*IQR and Q3 + k*IQR
+    Iqr,
+}
+
+impl QcMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QcMethod::Zscore => "zscore",
+            QcMethod::Mad => "mad",
+            QcMethod::Iqr => "iqr",
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SdType {
+    /// Divide the squared deviations by N
+    Population,
+    /// Divide the squared deviations by N - 1 (Bessel's correction)
+    Sample,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum QcMode {
+    /// Exclude only libraries below the lower cutoff
+    Lower,
+    /// Also exclude libraries above the upper cutoff
+    Both,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum CoverageMode {
+    /// Each fragment counts in every bin it overlaps
+    Fragment,
+    /// Each fragment counts once, in the bin holding its center
+    Midpoint,
+    /// Each fragment counts at both Tn5 cut sites (its 5' ends)
+    Ends,
+}
+
+/// bamCoverage `--normalizeUsing` method (BAM input only).
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Raw read counts per bin
+    None,
+    /// Reads per kilobase per million mapped reads
+    Rpkm,
+    /// Counts per million mapped reads
+    Cpm,
+    /// Bins per million mapped reads
+    Bpm,
+    /// Reads per genomic content (1x coverage); needs the effective genome size
+    Rpgc,
+}
+
+impl Normalization {
+    fn as_arg(&self) -> &'static str {
+        match self {
+            Normalization::None => "None",
+            Normalization::Rpkm => "RPKM",
+            Normalization::Cpm => "CPM",
+            Normalization::Bpm => "BPM",
+            Normalization::Rpgc => "RPGC",
+        }
+    }
+}
+
+/// Genome builds with a known effective genome size (`--genome`).
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum Genome {
+    /// Human GRCh38
+    Hg38,
+    /// Human GRCh37
+    Hg19,
+    /// Mouse GRCm38
+    Mm10,
+    /// Mouse GRCm39
+    Mm39,
+    /// Drosophila BDGP6
+    Dm6,
+}
+
+impl Genome {
+    /// The build's non-N bases, as listed in the deepTools documentation.
+    pub fn effective_genome_size(self) -> u64 {
+        match self {
+            Genome::Hg38 => 2_913_022_398,
+            Genome::Hg19 => 2_864_785_220,
+            Genome::Mm10 => 2_652_783_500,
+            Genome::Mm39 => 2_654_621_783,
+            Genome::Dm6 => 142_573_017,
+        }
+    }
+}
+
+/// Library-size scaling of BED-mode bin counts.
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum Scale {
+    /// Raw fragment counts per bin
+    None,
+    /// Counts per million sampled fragments
+    Cpm,
+    /// Counts scaled to the downsampling target, for samples left below it
+    Target,
+}
+
+/// File format of the binned coverage tracks (`--output-format`).
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// A bigWig (.bw)
+    Bigwig,
+    /// A four-column bedGraph (.bedGraph)
+    Bedgraph,
+    /// Both a bigWig and a bedGraph
+    Both,
+}
+
+impl OutputFormat {
+    /// Extensions of the files written for one track.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Bigwig => &["bw"],
+            OutputFormat::Bedgraph => &["bedGraph"],
+            OutputFormat::Both => &["bw", "bedGraph"],
+        }
+    }
+}
+
+/// How `--correlation` compares the samples' tracks.
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum CorrelationMethod {
+    /// Linear correlation of the values
+    Pearson,
+    /// Correlation of the ranks of the values, robust to a few very high bins
+    Spearman,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum PoolMode {
+    /// Every input file is its own sample
+    None,
+    /// Files in the same --group-pattern group are pooled into one sample
+    Sum,
+}
+
+/// What to do with a BED fragment that is empty (start equal to end) or runs past the end of
+/// its chromosome in the chrom sizes.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum BadCoords {
+    /// Drop the fragment
+    Skip,
+    /// Cut the fragment at the chromosome end; empty ones and ones starting past the end are
+    /// dropped
+    Clamp,
+    /// Stop with an error naming the file and line
+    Error,
+}
+
+/// Executables for the external tools, so every invocation uses the configured path.
+struct Tools {
+    samtools: PathBuf,
+    bam_coverage: PathBuf,
+    bedgraph_to_bigwig: PathBuf,
+    /// Times a run that exits nonzero is retried (`--retries`)
+    retries: u32,
+    /// FASTA that CRAM input is decoded with (`--reference-fasta`)
+    reference: Option<PathBuf>,
+}
+
+impl Default for Tools {
+    /// Each tool looked up on `PATH` under its usual name.
+    fn default() -> Self {
+        Tools {
+            samtools: PathBuf::from("samtools"),
+            bam_coverage: PathBuf::from("bamCoverage"),
+            bedgraph_to_bigwig: PathBuf::from("bedGraphToBigWig"),
+            retries: 0,
+            reference: None,
+        }
+    }
+}
+
+impl Tools {
+    /// Tools needed for the given input mode, paired with their canonical names. Without
+    /// `tracks` only those needed for counting.
+    fn required(
+        &self,
+        input_type: InputType,
+        native_bigwig: bool,
+        tracks: bool,
+    ) -> Vec<(&'static str, &Path)> {
+        match input_type {
+            InputType::Bed if native_bigwig || !tracks => vec![],
+            InputType::Bed => vec![("bedGraphToBigWig", &self.bedgraph_to_bigwig)],
+            InputType::Bam if !tracks => vec![("samtools", &self.samtools)],
+            InputType::Bam => vec![
+                ("samtools", &self.samtools),
+                ("bamCoverage", &self.bam_coverage),
+            ],
+        }
+    }
+
+    /// Run an external command to completion and return its output, turning spawn errors
+    /// and nonzero exits into errors naming the step. A nonzero exit is retried up to
+    /// `retries` times, after a pause that doubles from half a second. A command that can't
+    /// be started fails at once, since retrying won't make a missing tool appear.
+    fn run(&self, cmd: &mut Command, step: &str) -> Result<Output, Error> {
+        let mut attempt = 0;
+        loop {
+            let (output, stderr) = run_capturing_stderr(cmd, step)?;
+            if output.status.success() {
+                return Ok(output);
+            }
+            if attempt == self.retries {
+                return Err(Error::ToolFailed {
+                    step: step.to_string(),
+                    status: output.status,
+                    stderr,
+                });
+            }
+            attempt += 1;
+            let pause = Duration::from_millis(500 << (attempt - 1).min(6));
+            warn!(
+                "{} failed ({}), retrying in {:.1}s ({} of {} retries){}",
+                step,
+                output.status,
+                pause.as_secs_f64(),
+                attempt,
+                self.retries,
+                indented_lines(&stderr)
+            );
+            std::thread::sleep(pause);
+        }
+    }
+
+    /// A samtools `subcommand` that reads alignments, passed the `--reference-fasta` FASTA
+    /// as `--reference` when set so it can decode CRAMs. BAMs are read as before.
+    fn samtools_reading(&self, subcommand: &str) -> Command {
+        let mut cmd = Command::new(&self.samtools);
+        cmd.arg(subcommand);
+        if let Some(reference) = &self.reference {
+            cmd.arg("--reference").arg(reference);
+        }
+        cmd
+    }
+
+    /// [`Tools::run`] for a command whose output goes to files.
+    fn run_step(&self, cmd: &mut Command, step: &str) -> Result<(), Error> {
+        self.run(cmd, step).map(|_| ())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReportFormat {
+    Json,
+    Tsv,
+}
+
+impl ReportFormat {
+    fn from_path(path: &Path) -> Result<Self, Error> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Ok(ReportFormat::Json),
+            Some("tsv") => Ok(ReportFormat::Tsv),
+            _ => Err(Error::Config(format!(
+                "Unsupported report extension for {} (expected .json or .tsv)",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    /// Below the lower z-score cutoff
+    Low,
+    /// Above the upper z-score cutoff
+    High,
+    /// Below the absolute --min-fragments floor
+    MinFragments,
+}
+
+impl Exclusion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Exclusion::Low => "low",
+            Exclusion::High => "high",
+            Exclusion::MinFragments => "min_fragments",
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct SampleQc {
+    pub file: PathBuf,
+    pub sample: String,
+    pub fragments: usize,
+    pub pass: bool,
+    pub excluded: Option<Exclusion>,
+    /// Share of the sample's fragments on a mitochondrial chromosome
+    pub mito_fraction: f64,
+    /// Share of the sample's fragments overlapping the blacklist, when one is given
+    pub blacklist_fraction: Option<f64>,
+    /// Share of the sample's fragments dropped as duplicates, with `--dedup`
+    pub duplicate_fraction: Option<f64>,
+    /// Fragments on the spike-in chromosomes, when `--spikein-chroms` is given
+    pub spikein_fragments: Option<usize>,
+}
+
+/// Settings of the library-size outlier QC.
+#[derive(Clone)]
+pub struct QcParams {
+    /// Multiplier k for the outlier fences
+    pub exclude_sd: f64,
+    pub method: QcMethod,
+    pub mode: QcMode,
+    /// Absolute minimum fragment count, applied before the outlier test
+    pub min_fragments: Option<usize>,
+    /// Variance the `zscore` method's standard deviation uses
+    pub sd_type: SdType,
+}
+
+impl Default for QcParams {
+    fn default() -> Self {
+        QcParams {
+            exclude_sd: 1.5,
+            method: QcMethod::Zscore,
+            mode: QcMode::Lower,
+            min_fragments: None,
+            sd_type: SdType::Population,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct QcResult {
+    /// True when there were too few samples for a meaningful cutoff, or they all had the same
+    /// count, and all were kept
+    pub skipped: bool,
+    pub method: QcMethod,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub sd_type: SdType,
+    pub median: f64,
+    pub cutoff: f64,
+    pub upper_cutoff: Option<f64>,
+    pub samples: Vec<SampleQc>,
+}
+
+fn mean(values: &[usize]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64)
+}
+
+/// Standard deviation around `mean`; `None` without values, or with a single one for the
+/// sample SD.
+fn std_dev(values: &[usize], mean: f64, sd_type: SdType) -> Option<f64> {
+    let n = match sd_type {
+        SdType::Population => values.len(),
+        SdType::Sample => values.len().checked_sub(1)?,
+    };
+    if n == 0 {
+        return None;
+    }
+    let var = values.iter().map(|&v| {
+        let diff = v as f64 - mean;
+        diff * diff
+    }).sum::<f64>() / n as f64;
+    Some(var.sqrt())
+}
+
+/// Pearson correlation of two equally long slices; NaN when either is constant.
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (dx, dy) = (x - mean_a, y - mean_b);
+        cov += dx * dy;
+        var_a += dx * dx;
+        var_b += dy * dy;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+/// Ranks from 1, with tied values sharing the mean of their ranks.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&i, &j| values[i].total_cmp(&values[j]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for &i in &order[start..end] {
+            ranks[i] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+/// Linear-interpolated quantile of an ascending-sorted slice.
+fn quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let pos = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lo = pos.floor() as usize;
+    let hi = pos.ceil() as usize;
+    Some(sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64))
+}
+
+fn sorted_f64(values: &[usize]) -> Vec<f64> {
+    let mut sorted: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+    sorted.sort_by(f64::total_cmp);
+    sorted
+}
+
+fn median(values: &[usize]) -> Option<f64> {
+    quantile(&sorted_f64(values), 0.5)
+}
+
+/// Median absolute deviation, scaled by 1.4826 so it estimates the SD for normal data.
+fn mad(values: &[usize], median: f64) -> Option<f64> {
+    let mut deviations: Vec<f64> = values.iter().map(|&v| (v as f64 - median).abs()).collect();
+    deviations.sort_by(f64::total_cmp);
+    quantile(&deviations, 0.5).map(|d| d * 1.4826)
+}
+
+/// First and third quartiles.
+fn quartiles(values: &[usize]) -> Option<(f64, f64)> {
+    let sorted = sorted_f64(values);
+    Some((quantile(&sorted, 0.25)?, quantile(&sorted, 0.75)?))
+}
+
+/// Lower and upper outlier fences for the chosen method, with `k` as the multiplier.
+fn qc_fences(values: &[usize], method: QcMethod, k: f64, sd_type: SdType) -> (f64, f64) {
+    match method {
+        QcMethod::Zscore => {
+            let m = mean(values).unwrap_or(0.0);
+            let sd = std_dev(values, m, sd_type).unwrap_or(0.0);
+            (m - k * sd, m + k * sd)
+        }
+        QcMethod::Mad => {
+            let med = median(values).unwrap_or(0.0);
+            let spread = mad(values, med).unwrap_or(0.0);
+            (med - k * spread, med + k * spread)
+        }
+        QcMethod::Iqr => {
+            let (q1, q3) = quartiles(values).unwrap_or((0.0, 0.0));
+            let iqr = q3 - q1;
+            (q1 - k * iqr, q3 + k * iqr)
+        }
+    }
+}
+
+/// Input extensions stripped to get a sample's stem, longest first.
+const KNOWN_EXTENSIONS: &[&str] = &[".bed.gz", ".bed", ".bam", ".cram"];
+
+/// File name of `file` without its directory or any known input extension.
+fn sample_stem(file: &Path) -> String {
+    let name = file
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    KNOWN_EXTENSIONS
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .filter(|stem| !stem.is_empty())
+        .map(str::to_string)
+        .unwrap_or(name)
+}
+
+/// Sample name for each input: its `--files-from` name, the first capture group of
+/// `pattern` matched against the file name, or the sample stem when there is no pattern or
+/// it doesn't match.
+fn sample_names(
+    files: &[PathBuf],
+    pattern: Option<&Regex>,
+    listed: &HashMap<PathBuf, String>,
+) -> HashMap<PathBuf, String> {
+    files
+        .iter()
+        .map(|file| {
+            if let Some(name) = listed.get(file) {
+                return (file.clone(), name.clone());
+            }
+            let Some(pattern) = pattern else {
+                return (file.clone(), sample_stem(file));
+            };
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            let name = pattern
+                .captures(&file_name)
+                .and_then(|caps| caps.get(1))
+                .map(|m| m.as_str().to_string())
+                .filter(|name| !name.is_empty());
+            let name = name.unwrap_or_else(|| {
+                warn!(
+                    "--name-pattern does not match {}, using {}",
+                    file_name,
+                    sample_stem(file)
+                );
+                sample_stem(file)
+            });
+            (file.clone(), name)
+        })
+        .collect()
+}
+
+/// Intermediate paths handed out so far, so an interrupted run can remove them.
+#[derive(Clone, Default)]
+pub struct CleanupRegistry {
+    paths: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl CleanupRegistry {
+    fn register(&self, path: PathBuf) {
+        self.paths.lock().unwrap().push(path);
+    }
+
+    /// Remove every registered file, and registered directories with their contents.
+    pub fn remove_all(&self) {
+        for path in self.paths.lock().unwrap().drain(..) {
+            if path.is_dir() {
+                let _ = std::fs::remove_dir_all(&path);
+            } else {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+/// Group input files into samples, each named and listed in the order its first file was
+/// given. With a group pattern, files whose first capture group is the same key are pooled
+/// into one sample named after the key; files the pattern doesn't match stay on their own
+/// under their sample name.
+fn pool_samples(
+    files: &[PathBuf],
+    group_pattern: Option<&Regex>,
+    listed: &HashMap<PathBuf, String>,
+    names: &HashMap<PathBuf, String>,
+) -> Vec<(String, Vec<PathBuf>)> {
+    let mut groups: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for file in files {
+        let key = listed.get(file).cloned().or_else(|| {
+            let pattern = group_pattern?;
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            let key = pattern.captures(&file_name)?.get(1)?.as_str().to_string();
+            Some(key).filter(|key| !key.is_empty())
+        });
+        let Some(key) = key else {
+            if group_pattern.is_some() {
+                warn!(
+                    "--group-pattern does not match {}, keeping it as its own sample",
+                    file.display()
+                );
+            }
+            groups.push((names[file].clone(), vec![file.clone()]));
+            continue;
+        };
+        match index.get(&key) {
+            Some(&i) => groups[i].1.push(file.clone()),
+            None => {
+                index.insert(key.clone(), groups.len());
+                groups.push((key, vec![file.clone()]));
+            }
+        }
+    }
+    groups
+}
+
+/// Decides where each sample's files go. Without `--outdir` final outputs sit beside the
+/// input; with it they go into the output directory. Intermediates always go into the
+/// run's temp directory. File names are `<sample name>_<suffix>`; inputs that share a
+/// sample name keep their full file name and their relative parent path beneath either
+/// directory, so they can't overwrite each other.
+struct OutputLayout {
+    outdir: Option<PathBuf>,
+    tmpdir: PathBuf,
+    names: HashMap<PathBuf, String>,
+    colliding: HashSet<String>,
+    cleanup: CleanupRegistry,
+}
+
+impl OutputLayout {
+    fn new(
+        outdir: Option<PathBuf>,
+        tmpdir: PathBuf,
+        names: HashMap<PathBuf, String>,
+        cleanup: CleanupRegistry,
+    ) -> Self {
+        let mut seen = HashSet::new();
+        let mut colliding = HashSet::new();
+        for name in names.values() {
+            if !seen.insert(name) {
+                colliding.insert(name.clone());
+            }
+        }
+        OutputLayout {
+            outdir,
+            tmpdir,
+            names,
+            colliding,
+            cleanup,
+        }
+    }
+
+    /// Sample name of `file`, as given by `sample_names`.
+    fn sample_name(&self, file: &Path) -> String {
+        self.names
+            .get(file)
+            .cloned()
+            .unwrap_or_else(|| sample_stem(file))
+    }
+
+    /// Relative parent path used to disambiguate inputs with a shared sample stem.
+    fn subdir(&self, file: &Path) -> PathBuf {
+        if !self.colliding.contains(&self.sample_name(file)) {
+            return PathBuf::new();
+        }
+        file.parent()
+            .unwrap_or(Path::new(""))
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn dir_for(&self, file: &Path) -> PathBuf {
+        match &self.outdir {
+            None => file.parent().unwrap_or(Path::new("")).to_path_buf(),
+            Some(outdir) => outdir.join(self.subdir(file)),
+        }
+    }
+
+    fn tmp_dir_for(&self, file: &Path) -> PathBuf {
+        self.tmpdir.join(self.subdir(file))
+    }
+
+    fn name(&self, file: &Path, suffix: &str) -> String {
+        let name = self.sample_name(file);
+        if self.colliding.contains(&name) {
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            format!("{}_{}", file_name, suffix)
+        } else {
+            format!("{}_{}", name, suffix)
+        }
+    }
+
+    /// Path for a final output with the given suffix belonging to the sample read from `file`.
+    fn path(&self, file: &Path, suffix: &str) -> PathBuf {
+        self.dir_for(file).join(self.name(file, suffix))
+    }
+
+    /// Path for an intermediate file with the given suffix belonging to the sample read from
+    /// `file`. The path is registered for removal on Ctrl-C.
+    fn tmp_path(&self, file: &Path, suffix: &str) -> PathBuf {
+        let path = self.tmp_dir_for(file).join(self.name(file, suffix));
+        self.cleanup.register(path.clone());
+        path
+    }
+}
+
+/// Flag libraries below the absolute `min_fragments` floor, then those whose fragment count
+/// falls below the lower fence of the QC method (and, in `QcMode::Both`, above the upper
+/// fence). The fences are computed only over libraries that clear the floor.
+/// With fewer than two such samples, or when they all have the same count, there is nothing
+/// to compare against, so all are kept.
+fn run_qc(
+    counts: &[(PathBuf, usize)],
+    params: &QcParams,
+    names: &HashMap<PathBuf, String>,
+) -> QcResult {
+    let floor = params.min_fragments.unwrap_or(0);
+    let counts_only: Vec<_> = counts
+        .iter()
+        .map(|(_, c)| *c)
+        .filter(|&c| c >= floor)
+        .collect();
+    // Compared as integers, so equal counts are never split by rounding in the SD
+    let identical = counts_only.windows(2).all(|pair| pair[0] == pair[1]);
+    let skipped = counts_only.len() < 2 || identical;
+    let mean_val = mean(&counts_only).unwrap_or(0.0);
+    let sd_val = std_dev(&counts_only, mean_val, params.sd_type).unwrap_or(0.0);
+    let median_val = median(&counts_only).unwrap_or(0.0);
+    let (lower, upper) = qc_fences(&counts_only, params.method, params.exclude_sd, params.sd_type);
+    let cutoff = if skipped { 0.0 } else { lower.max(0.0) };
+    let upper_cutoff = if !skipped && params.mode == QcMode::Both {
+        Some(upper)
+    } else {
+        None
+    };
+    let samples = counts
+        .iter()
+        .map(|(f, c)| {
+            let count = *c as f64;
+            let excluded = if *c < floor {
+                Some(Exclusion::MinFragments)
+            } else if skipped {
+                None
+            } else if count < cutoff - cutoff_tolerance(cutoff) {
+                Some(Exclusion::Low)
+            } else if upper_cutoff.is_some_and(|u| count > u + cutoff_tolerance(u)) {
+                Some(Exclusion::High)
+            } else {
+                None
+            };
+            SampleQc {
+                file: f.clone(),
+                sample: names.get(f).cloned().unwrap_or_else(|| sample_stem(f)),
+                fragments: *c,
+                pass: excluded.is_none(),
+                excluded,
+                mito_fraction: 0.0,
+                blacklist_fraction: None,
+                duplicate_fraction: None,
+                spikein_fragments: None,
+            }
+        })
+        .collect();
+    QcResult {
+        skipped,
+        method: params.method,
+        mean: mean_val,
+        std_dev: sd_val,
+        sd_type: params.sd_type,
+        median: median_val,
+        cutoff,
+        upper_cutoff,
+        samples,
+    }
+}
+
+/// Slack when comparing a count with a fence, so a count that equals the fence in exact
+/// arithmetic isn't excluded by the rounding error in computing it.
+fn cutoff_tolerance(cutoff: f64) -> f64 {
+    1e-9 * cutoff.abs().max(1.0)
+}
+
+fn print_qc(qc: &QcResult) {
+    let compared = (qc.samples.iter())
+        .filter(|s| s.excluded != Some(Exclusion::MinFragments))
+        .count();
+    if qc.skipped && compared < 2 {
+        info!("QC: fewer than two samples, skipping the outlier cutoff");
+    } else if qc.skipped {
+        info!("QC: every sample has {} fragments, skipping the outlier cutoff", qc.mean);
+    } else {
+        let stats = match qc.method {
+            QcMethod::Zscore => format!("QC: Mean={}, SD={}", qc.mean, qc.std_dev),
+            QcMethod::Mad => format!("QC (mad): Median={}", qc.median),
+            QcMethod::Iqr => format!("QC (iqr): Median={}", qc.median),
+        };
+        match qc.upper_cutoff {
+            Some(upper) => info!("{}, cutoff={}, upper cutoff={}", stats, qc.cutoff, upper),
+            None => info!("{}, cutoff={}", stats, qc.cutoff),
+        }
+    }
+    for s in &qc.samples {
+        let blacklist = s
+            .blacklist_fraction
+            .map(|f| format!(", {:.2}% in blacklist", f * 100.0))
+            .unwrap_or_default();
+        let duplicates = s
+            .duplicate_fraction
+            .map(|f| format!(", {:.2}% duplicates", f * 100.0))
+            .unwrap_or_default();
+        let spikein = s
+            .spikein_fragments
+            .map(|n| format!(", {} spike-in fragments", n))
+            .unwrap_or_default();
+        info!(
+            "  {}: {:.2}% mitochondrial{}{}{}",
+            s.sample,
+            s.mito_fraction * 100.0,
+            blacklist,
+            duplicates,
+            spikein
+        );
+    }
+    let groups = [
+        (Exclusion::MinFragments, "Excluded samples below --min-fragments:"),
+        (Exclusion::Low, "Excluded samples with low fragment counts:"),
+        (Exclusion::High, "Excluded samples with high fragment counts:"),
+    ];
+    for (reason, heading) in groups {
+        let excluded: Vec<_> = qc
+            .samples
+            .iter()
+            .filter(|s| s.excluded == Some(reason))
+            .collect();
+        if !excluded.is_empty() {
+            warn!("{}", heading);
+            for s in &excluded {
+                warn!("  {} => {}", s.file.display(), s.fragments);
+            }
+        }
+    }
+}
+
+fn write_qc_report(path: &Path, qc: &QcResult) -> Result<(), Error> {
+    let format = ReportFormat::from_path(path)?;
+    write_atomic(path, |partial| {
+        write_qc_report_as(partial, format, qc).map_err(Error::io(path))
+    })
+}
+
+/// One sample's row set in the per-chromosome report.
+#[derive(Serialize)]
+struct ChromReport<'a> {
+    file: &'a Path,
+    sample: &'a str,
+    chroms: Vec<ChromShare<'a>>,
+}
+
+#[derive(Serialize)]
+struct ChromShare<'a> {
+    chrom: &'a str,
+    fragments: usize,
+    fraction: f64,
+}
+
+/// Write every sample's fragments per chromosome, and their share of its total, to a
+/// `.json` or `.tsv` file.
+fn write_chrom_report(
+    path: &Path,
+    counts: &[(PathBuf, FragmentCount)],
+    names: &HashMap<PathBuf, String>,
+) -> Result<(), Error> {
+    let format = ReportFormat::from_path(path)?;
+    let reports: Vec<ChromReport> = counts
+        .iter()
+        .map(|(file, count)| {
+            let total = count.per_chrom.iter().map(|(_, n)| n).sum::<usize>().max(1);
+            ChromReport {
+                file,
+                sample: &names[file],
+                chroms: count
+                    .per_chrom
+                    .iter()
+                    .map(|(chrom, n)| ChromShare {
+                        chrom,
+                        fragments: *n,
+                        fraction: *n as f64 / total as f64,
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    write_atomic(path, |partial| {
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(partial)?);
+            match format {
+                ReportFormat::Json => {
+                    serde_json::to_writer_pretty(&mut writer, &reports)?;
+                    writeln!(writer)?;
+                }
+                ReportFormat::Tsv => {
+                    writeln!(writer, "file\tsample\tchrom\tfragments\tfraction")?;
+                    for report in &reports {
+                        for c in &report.chroms {
+                            writeln!(
+                                writer,
+                                "{}\t{}\t{}\t{}\t{:.6}",
+                                report.file.display(),
+                                report.sample,
+                                c.chrom,
+                                c.fragments,
+                                c.fraction
+                            )?;
+                        }
+                    }
+                }
+            }
+            writer.flush()
+        };
+        write().map_err(Error::io(path))
+    })
+}
+
+/// Fragment-length histograms of the downsampled samples, filled in by the workers, for
+/// `--length-histogram`.
+struct LengthHistograms {
+    /// Bin width in bp
+    width: u64,
+    samples: Mutex<HashMap<PathBuf, LengthHistogram>>,
+}
+
+impl LengthHistograms {
+    fn new(width: u64) -> Self {
+        LengthHistograms {
+            width,
+            samples: Mutex::default(),
+        }
+    }
+
+    fn insert(&self, sample: &Path, histogram: LengthHistogram) {
+        self.samples.lock().unwrap().insert(sample.to_path_buf(), histogram);
+    }
+
+    fn take(&self) -> HashMap<PathBuf, LengthHistogram> {
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+}
+
+/// Write each sample's fragment-length histogram as a tidy TSV of sample, the lower bound
+/// of each `width`-bp length bin and its count. Empty bins between a sample's shortest and
+/// longest fragments are written as zeros, so the rows plot as a continuous line.
+fn write_length_histogram(
+    path: &Path,
+    histograms: &[(&Path, &LengthHistogram)],
+    names: &HashMap<PathBuf, String>,
+    width: u64,
+) -> Result<(), Error> {
+    write_atomic(path, |partial| {
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(partial)?);
+            writeln!(writer, "sample\tlength_bin\tcount")?;
+            for (file, histogram) in histograms {
+                let (Some((&first, _)), Some((&last, _))) =
+                    (histogram.first_key_value(), histogram.last_key_value())
+                else {
+                    continue;
+                };
+                for bin in (first..=last).step_by(width as usize) {
+                    let count = histogram.get(&bin).copied().unwrap_or(0);
+                    writeln!(writer, "{}\t{}\t{}", names[*file], bin, count)?;
+                }
+            }
+            writer.flush()
+        };
+        write().map_err(Error::io(path))
+    })
+}
+
+fn write_qc_report_as(path: &Path, format: ReportFormat, qc: &QcResult) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, qc)?;
+            writeln!(writer)?;
+        }
+        ReportFormat::Tsv => {
+            writeln!(
+                writer,
+                "file\tsample\tfragments\tmethod\tmean\tstd_dev\tmedian\tcutoff\tupper_cutoff\t\
+                 pass\texcluded\tmito_fraction\tblacklist_fraction\tduplicate_fraction\t\
+                 spikein_fragments"
+            )?;
+            for s in &qc.samples {
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}\t{}\t{}",
+                    s.file.display(),
+                    s.sample,
+                    s.fragments,
+                    qc.method.as_str(),
+                    qc.mean,
+                    qc.std_dev,
+                    qc.median,
+                    qc.cutoff,
+                    qc.upper_cutoff.map(|u| u.to_string()).unwrap_or_default(),
+                    s.pass,
+                    s.excluded.map(|e| e.as_str()).unwrap_or(""),
+                    s.mito_fraction,
+                    s.blacklist_fraction.map(|f| format!("{:.6}", f)).unwrap_or_default(),
+                    s.duplicate_fraction.map(|f| format!("{:.6}", f)).unwrap_or_default(),
+                    s.spikein_fragments.map(|n| n.to_string()).unwrap_or_default()
+                )?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// `--spikein-normalize` factor of each planned sample: the fewest spike-in fragments any
+/// sample keeps after downsampling, over the number this sample keeps (its spike-in count
+/// times its kept fraction). Tracks then show signal per spike-in fragment, with the
+/// sample holding the fewest at 1.
+fn spikein_factors(
+    plan: &DownsamplePlan,
+    counts: &[(PathBuf, FragmentCount)],
+) -> Result<HashMap<PathBuf, f64>, Error> {
+    let spikein: HashMap<&PathBuf, usize> = counts.iter().map(|(f, c)| (f, c.spikein)).collect();
+    let mut kept = Vec::with_capacity(plan.samples.len());
+    for s in &plan.samples {
+        let n = spikein.get(&s.file).copied().unwrap_or(0);
+        if n == 0 {
+            return Err(Error::Config(format!(
+                "{}: no fragments on the --spikein-chroms, so --spikein-normalize can't scale it",
+                s.sample
+            )));
+        }
+        kept.push((s, n as f64 * s.fraction));
+    }
+    let fewest = kept.iter().map(|(_, n)| *n).fold(f64::INFINITY, f64::min);
+    Ok(kept
+        .into_iter()
+        .map(|(s, n)| {
+            let factor = fewest / n;
+            info!("{}: spike-in scale factor {:.6}", s.sample, factor);
+            (s.file.clone(), factor)
+        })
+        .collect())
+}
+
+/// Rank of each chromosome in the sizes file, used to sort fragments into file order.
+fn chrom_order(chrom_list: &[(String, u32)]) -> HashMap<String, usize> {
+    chrom_list
+        .iter()
+        .enumerate()
+        .map(|(i, (chrom, _))| (chrom.clone(), i))
+        .collect()
+}
+
+/// `(chrom, length)` pairs from a chrom sizes file, in file order. Blank lines are skipped;
+/// a missing or non-numeric length or a repeated chromosome is an error naming the line.
+fn parse_chrom_sizes(chrom_sizes: &Path) -> Result<Vec<(String, u32)>, Error> {
+    let file = File::open(chrom_sizes).map_err(Error::io(chrom_sizes))?;
+    let mut sizes = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(Error::io(chrom_sizes))?;
+        let mut fields = line.split_whitespace();
+        let Some(chrom) = fields.next() else {
+            continue;
+        };
+        let bad_line = |msg: String| {
+            Error::Parse(format!("{}: line {}: {}", chrom_sizes.display(), i + 1, msg))
+        };
+        let len = fields.next().ok_or_else(|| bad_line(format!("no length for {}", chrom)))?;
+        let len = len
+            .parse()
+            .map_err(|_| bad_line(format!("invalid length '{}' for {}", len, chrom)))?;
+        if let Some(first) = seen.insert(chrom.to_string(), i + 1) {
+            return Err(bad_line(format!("{} is already listed on line {}", chrom, first)));
+        }
+        sizes.push((chrom.to_string(), len));
+    }
+    Ok(sizes)
+}
+
+/// Whether a chrom sizes file has columns past the length, as a `.fai` index does.
+fn has_extra_columns(chrom_sizes: &Path) -> Result<bool, Error> {
+    if chrom_sizes.extension().is_some_and(|ext| ext == "fai") {
+        return Ok(true);
+    }
+    let file = File::open(chrom_sizes).map_err(Error::io(chrom_sizes))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(Error::io(chrom_sizes))?;
+        if line.split_whitespace().nth(2).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Create `dir` and write and remove a probe file in it, so an unwritable output or temp
+/// directory fails the run before any sample is counted.
+fn check_writable(dir: &Path) -> Result<(), Error> {
+    let probe = dir.join(format!(".bedfragment_ds_probe.{}", std::process::id()));
+    std::fs::create_dir_all(dir)
+        .and_then(|_| File::create(&probe))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| Error::Config(format!("Cannot write to {}: {}", dir.display(), e)))
+}
+
+/// Mount point and free bytes of the filesystem holding `dir`, from `df -Pk`; `None` when
+/// df is missing or its output can't be read.
+fn free_space(dir: &Path) -> Option<(PathBuf, u64)> {
+    let output = Command::new("df").arg("-Pk").arg(dir).stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.lines().nth(1)?.split_whitespace().collect();
+    let kb: u64 = fields.get(3)?.parse().ok()?;
+    Some((PathBuf::from(fields.get(5)?), kb * 1024))
+}
+
+/// `bytes` as a short human-readable size.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// One interval of a `--regions` or `--blacklist` BED file.
+struct Region {
+    chrom: String,
+    start: u64,
+    end: u64,
+    /// The BED name column, or `chrom:start-end` when there is none
+    name: String,
+}
+
+/// Read a BED file of regions in file order, skipping blank, comment, `track` and `browser`
+/// lines. A line without a numeric start and end is an error naming the line.
+fn read_regions(path: &Path) -> Result<Vec<Region>, Error> {
+    let reader = open_bed(path).map_err(Error::io(path))?;
+    let mut regions = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(Error::io(path))?;
+        let trimmed = line.trim();
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with("track")
+            || trimmed.starts_with("browser")
+        {
+            continue;
+        }
+        let mut fields = trimmed.split_whitespace();
+        let region = match (fields.next(), fields.next(), fields.next()) {
+            (Some(chrom), Some(start), Some(end)) => {
+                start.parse::<u64>().ok().zip(end.parse::<u64>().ok()).map(|r| (chrom, r))
+            }
+            _ => None,
+        };
+        let Some((chrom, (start, end))) = region else {
+            return Err(Error::Parse(format!(
+                "{}: line {}: expected 'chrom start end', got '{}'",
+                path.display(),
+                i + 1,
+                trimmed
+            )));
+        };
+        let name = match fields.next() {
+            Some(name) => name.to_string(),
+            None => format!("{}:{}-{}", chrom, start, end),
+        };
+        regions.push(Region {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            name,
+        });
+    }
+    Ok(regions)
+}
+
+/// Count the fragments overlapping each region by at least 1 bp, as for bins, each adding
+/// its weight. Fragments of zero length are ignored.
+fn count_regions<S: AsRef<str>>(
+    fragments: impl IntoIterator<Item = (S, u64, u64, f64)>,
+    regions: &[Region],
+) -> Vec<f64> {
+    // Overlapping fragments start before the region ends, less those ending before it starts
+    type Bounds = Vec<(u64, f64)>;
+    let mut bounds: HashMap<String, (Bounds, Bounds)> = HashMap::new();
+    for (chrom, start, end, weight) in fragments {
+        if end > start {
+            let (starts, ends) = bounds.entry(chrom.as_ref().to_string()).or_default();
+            starts.push((start, weight));
+            ends.push((end, weight));
+        }
+    }
+    // Sorted positions, with the summed weights of all positions before each
+    let cumulative = |mut bounds: Bounds| {
+        bounds.sort_unstable_by_key(|&(pos, _)| pos);
+        let mut sum = 0.0;
+        let mut sums = vec![0.0];
+        sums.extend(bounds.iter().map(|&(_, weight)| {
+            sum += weight;
+            sum
+        }));
+        (bounds.into_iter().map(|(pos, _)| pos).collect::<Vec<_>>(), sums)
+    };
+    let bounds: HashMap<String, _> = bounds
+        .into_iter()
+        .map(|(chrom, (starts, ends))| (chrom, (cumulative(starts), cumulative(ends))))
+        .collect();
+    regions
+        .iter()
+        .map(|region| match bounds.get(&region.chrom) {
+            Some(((starts, started_sums), (ends, ended_sums))) => {
+                let started = starts.partition_point(|&start| start < region.end);
+                let ended = ends.partition_point(|&end| end <= region.start);
+                if started > ended {
+                    started_sums[started] - ended_sums[ended]
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        })
+        .collect()
+}
+
+/// Blacklisted regions from `--blacklist`, merged and sorted per chromosome.
+struct Blacklist {
+    regions: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Blacklist {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let mut regions: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for region in read_regions(path)? {
+            regions.entry(region.chrom).or_default().push((region.start, region.end));
+        }
+        for list in regions.values_mut() {
+            list.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(list.len());
+            for &(start, end) in list.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *list = merged;
+        }
+        Ok(Blacklist { regions })
+    }
+
+    /// Whether a BED line overlaps a blacklisted region by at least 1 bp.
+    fn overlaps(&self, line: &str) -> bool {
+        parse_interval(line)
+            .is_some_and(|(chrom, start, end)| self.overlaps_interval(chrom, start, end))
+    }
+
+    fn overlaps_interval(&self, chrom: &str, start: u64, end: u64) -> bool {
+        let Some(regions) = self.regions.get(chrom) else {
+            return false;
+        };
+        let i = regions.partition_point(|&(_, region_end)| region_end <= start);
+        regions.get(i).is_some_and(|&(region_start, _)| region_start < end)
+    }
+}
+
+/// Tn5 cut-site correction (`--atac-shift`). Stranded lines (column 6) move as a whole by
+/// the shift for their strand; unstranded fragments have their start shifted by `plus` and
+/// their end by `minus`, since each end is a cut site on the opposite strand.
+struct AtacShift {
+    plus: i64,
+    minus: i64,
+}
+
+impl AtacShift {
+    /// Shift one BED line, clamped to its chromosome. Returns `None` when nothing of the
+    /// fragment is left; lines whose coordinates don't parse are passed through unchanged.
+    fn apply(&self, line: &str, chrom_lengths: &HashMap<String, u32>) -> Option<String> {
+        let mut fields: Vec<&str> = line.split('\t').collect();
+        let coords = fields.get(1).zip(fields.get(2)).and_then(|(start, end)| {
+            Some((start.trim().parse::<i64>().ok()?, end.trim().parse::<i64>().ok()?))
+        });
+        let Some((start, end)) = coords else {
+            return Some(line.to_string());
+        };
+        let (start_shift, end_shift) = match fields.get(5).map(|s| s.trim()) {
+            Some("+") => (self.plus, self.plus),
+            Some("-") => (self.minus, self.minus),
+            _ => (self.plus, self.minus),
+        };
+        let chrom_len = chrom_lengths
+            .get(fields[0])
+            .map_or(i64::MAX, |&len| len as i64);
+        let start = (start + start_shift).clamp(0, chrom_len);
+        let end = (end + end_shift).clamp(0, chrom_len);
+        if end <= start {
+            return None;
+        }
+        let (start, end) = (start.to_string(), end.to_string());
+        fields[1] = &start;
+        fields[2] = &end;
+        Some(fields.join("\t"))
+    }
+}
+
+/// Extend a stranded BED line to `length` bp from its 5' end, as bamCoverage
+/// `--extendReads` does for single-end reads: plus-strand lines grow to the right and
+/// minus-strand lines to the left, stopping at the chromosome ends. Lines already that long,
+/// without a strand or whose coordinates don't parse are passed through unchanged.
+fn extend_read<'a>(
+    line: &'a str,
+    length: u64,
+    chrom_lengths: &HashMap<String, u32>,
+) -> Cow<'a, str> {
+    let mut fields: Vec<&str> = line.split('\t').collect();
+    let Some((chrom, start, end)) = parse_interval(line) else {
+        return Cow::Borrowed(line);
+    };
+    let chrom_len = chrom_lengths.get(chrom).map_or(u64::MAX, |&len| len as u64);
+    let (start, end) = match fields.get(5).map(|s| s.trim()) {
+        Some("+") => (start, end.max(start.saturating_add(length)).min(chrom_len)),
+        Some("-") => (start.min(end.saturating_sub(length)), end),
+        _ => return Cow::Borrowed(line),
+    };
+    let (start, end) = (start.to_string(), end.to_string());
+    fields[1] = &start;
+    fields[2] = &end;
+    Cow::Owned(fields.join("\t"))
+}
+
+/// Reduce a BED line to the 1 bp intervals `mode` counts: its center base for `Midpoint`,
+/// its first and last base for `Ends`. Lines whose coordinates don't parse are passed
+/// through unchanged.
+fn coverage_intervals(line: &str, mode: CoverageMode) -> Vec<String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let coords = fields.get(1).zip(fields.get(2)).and_then(|(start, end)| {
+        Some((start.trim().parse::<u64>().ok()?, end.trim().parse::<u64>().ok()?))
+    });
+    let points = match (mode, coords) {
+        (CoverageMode::Midpoint, Some((start, end))) if end > start => vec![(start + end) / 2],
+        (CoverageMode::Ends, Some((start, end))) if end > start => vec![start, end - 1],
+        _ => return vec![line.to_string()],
+    };
+    let rest: String = fields[3..].iter().map(|f| format!("\t{}", f)).collect();
+    points
+        .into_iter()
+        .map(|point| format!("{}\t{}\t{}{}", fields[0], point, point + 1, rest))
+        .collect()
+}
+
+/// Open a BED file for line reading, transparently decompressing gzip (detected by its
+/// magic bytes, so `.bed.gz` and bgzipped files both work).
+fn open_bed(path: &Path) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// A first line is a header unless its start and end columns parse as integers.
+fn is_header_line(line: &str) -> bool {
+    let mut fields = line.split('\t').skip(1);
+    let start = fields.next().map(|f| f.trim().parse::<u64>().is_ok());
+    let end = fields.next().map(|f| f.trim().parse::<u64>().is_ok());
+    !(start == Some(true) && end == Some(true))
+}
+
+/// Comment, `track` and `browser` lines, which are skipped wherever they appear.
+fn is_comment_line(line: &str) -> bool {
+    line.starts_with('#') || line.starts_with("track") || line.starts_with("browser")
+}
+
+/// Whether a data line lacks a chrom and integer start and end, or ends before it starts.
+fn is_malformed(line: &str) -> bool {
+    parse_interval(line).is_none_or(|(chrom, start, end)| chrom.is_empty() || end < start)
+}
+
+/// What a data line of a fragment file stands for.
+enum DataLine<'a> {
+    Fragment(Cow<'a, str>),
+    /// A BEDPE pair whose mates are on different chromosomes
+    Interchromosomal,
+    Malformed,
+}
+
+/// The fragment a BEDPE line's mates span, from the leftmost start to the rightmost end, as
+/// a BED line keeping the name and score columns. Mates on different chromosomes make no
+/// fragment; lines without six columns, or with mate coordinates that aren't integers or
+/// end before they start, are malformed.
+fn bedpe_fragment(line: &str) -> DataLine<'_> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 6 {
+        return DataLine::Malformed;
+    }
+    let mate = |at: usize| {
+        let start: u64 = fields[at + 1].trim().parse().ok()?;
+        let end: u64 = fields[at + 2].trim().parse().ok()?;
+        (!fields[at].is_empty() && start <= end).then_some((fields[at], start, end))
+    };
+    let (Some((chrom, start1, end1)), Some((chrom2, start2, end2))) = (mate(0), mate(3)) else {
+        return DataLine::Malformed;
+    };
+    if chrom != chrom2 {
+        return DataLine::Interchromosomal;
+    }
+    let mut fragment = format!("{}\t{}\t{}", chrom, start1.min(start2), end1.max(end2));
+    for field in fields.iter().skip(6).take(2) {
+        fragment.push('\t');
+        fragment.push_str(field);
+    }
+    DataLine::Fragment(Cow::Owned(fragment))
+}
+
+/// Read the first line and split it into `(header, first_data_line)`.
+fn read_header(
+    reader: &mut dyn BufRead,
+    no_header: bool,
+) -> std::io::Result<(Option<String>, Option<String>)> {
+    let mut first = String::new();
+    if reader.read_line(&mut first)? == 0 {
+        return Ok((None, None));
+    }
+    let first = first.trim_end().to_string();
+    if !no_header && is_header_line(&first) {
+        Ok((Some(first), None))
+    } else {
+        Ok((None, Some(first)))
+    }
+}
+
+/// Fragment length window from `--min-length`/`--max-length`, both bounds inclusive.
+#[derive(Clone, Copy, Default)]
+pub struct LengthFilter {
+    min: Option<u64>,
+    max: Option<u64>,
+}
+
+impl LengthFilter {
+    fn is_active(&self) -> bool {
+        self.min.is_some() || self.max.is_some()
+    }
+
+    fn keeps(&self, length: u64) -> bool {
+        self.min.is_none_or(|min| length >= min) && self.max.is_none_or(|max| length <= max)
+    }
+
+    /// Filter a BED line on `end - start`. Lines whose coordinates don't parse are kept
+    /// and dropped later when binning.
+    fn keeps_bed_line(&self, line: &str) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        fragment_length(line).is_none_or(|length| self.keeps(length))
+    }
+
+    /// `samtools view -e` expression on TLEN, which is negative for the reverse mate.
+    fn samtools_expr(&self) -> Option<String> {
+        match (self.min.unwrap_or(0), self.max) {
+            (0, None) => None,
+            (0, Some(max)) => Some(format!("tlen >= -{} && tlen <= {}", max, max)),
+            (min, None) => Some(format!("tlen >= {} || tlen <= -{}", min, min)),
+            (min, Some(max)) => Some(format!(
+                "(tlen >= {} && tlen <= {}) || (tlen <= -{} && tlen >= -{})",
+                min, max, min, max
+            )),
+        }
+    }
+}
+
+/// Chromosomes to process from `--chroms`/`--exclude-chroms`.
+#[derive(Clone, Default)]
+struct ChromFilter {
+    /// Only these chromosomes, when given
+    include: Option<HashSet<String>>,
+    exclude: HashSet<String>,
+}
+
+impl ChromFilter {
+    fn new(include: Option<Vec<String>>, exclude: Vec<String>) -> Self {
+        ChromFilter {
+            include: include.map(|chroms| chroms.into_iter().collect()),
+            exclude: exclude.into_iter().collect(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.include.is_some() || !self.exclude.is_empty()
+    }
+
+    fn keeps(&self, chrom: &str) -> bool {
+        self.include.as_ref().is_none_or(|include| include.contains(chrom))
+            && !self.exclude.contains(chrom)
+    }
+
+    fn keeps_bed_line(&self, line: &str) -> bool {
+        !self.is_active() || self.keeps(line.split('\t').next().unwrap_or(""))
+    }
+
+    /// `samtools view -e` expression on the reference name.
+    fn samtools_expr(&self) -> Option<String> {
+        fn sorted(chroms: &HashSet<String>) -> Vec<&String> {
+            let mut chroms: Vec<&String> = chroms.iter().collect();
+            chroms.sort();
+            chroms
+        }
+        let mut terms = Vec::new();
+        if let Some(include) = &self.include {
+            let any: Vec<String> = sorted(include)
+                .iter()
+                .map(|chrom| format!("rname == \"{}\"", chrom))
+                .collect();
+            terms.push(format!("({})", any.join(" || ")));
+        }
+        terms.extend(
+            sorted(&self.exclude)
+                .iter()
+                .map(|chrom| format!("rname != \"{}\"", chrom)),
+        );
+        (!terms.is_empty()).then(|| terms.join(" && "))
+    }
+}
+
+/// Chromosome names for `--chroms`/`--exclude-chroms`: the first column of `spec` if it
+/// names a file, otherwise a comma-separated list.
+pub fn read_chrom_list(spec: &str) -> Result<Vec<String>, Error> {
+    let path = Path::new(spec);
+    if !path.is_file() {
+        return Ok(spec
+            .split(',')
+            .map(str::trim)
+            .filter(|chrom| !chrom.is_empty())
+            .map(str::to_string)
+            .collect());
+    }
+    let file = File::open(path).map_err(Error::io(path))?;
+    let mut chroms = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(Error::io(path))?;
+        match line.split_whitespace().next() {
+            Some(chrom) if !chrom.starts_with('#') => chroms.push(chrom.to_string()),
+            _ => {}
+        }
+    }
+    Ok(chroms)
+}
+
+/// Inputs listed in a `--files-from` file, one path per line, and the sample name or group
+/// of those with a second, tab-separated column. Paths may contain spaces, so only tabs
+/// separate columns.
+fn read_file_list(path: &Path) -> Result<(Vec<PathBuf>, HashMap<PathBuf, String>), Error> {
+    let file = File::open(path).map_err(Error::io(path))?;
+    let mut files = Vec::new();
+    let mut groups = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(Error::io(path))?;
+        let line = line.trim_end();
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t').map(str::trim);
+        let file = PathBuf::from(fields.next().unwrap_or_default());
+        if let Some(group) = fields.next().filter(|group| !group.is_empty()) {
+            groups.insert(file.clone(), group.to_string());
+        }
+        files.push(file);
+    }
+    debug!("{}: {} input files listed", path.display(), files.len());
+    Ok((files, groups))
+}
+
+/// Input file extensions a directory input is searched for.
+fn input_extensions(input_type: InputType) -> &'static [&'static str] {
+    match input_type {
+        InputType::Bed => &[".bed", ".bed.gz"],
+        InputType::Bam => &[".bam", ".cram"],
+    }
+}
+
+/// Expand glob patterns and directories among `inputs` into the files they stand for.
+/// Paths that exist as files are kept as they are. A pattern like `data/*.bed` (or
+/// `data/**/*.bed`) gives the files it matches; a directory gives the input files directly
+/// inside it, or below it at any depth with `recursive`. Each expansion is sorted, and a
+/// file reached twice is only kept the first time. A pattern or directory that yields no
+/// files is an error.
+fn expand_inputs(
+    inputs: &[PathBuf],
+    input_type: InputType,
+    recursive: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let extensions = input_extensions(input_type);
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for input in inputs {
+        let spec = input.to_string_lossy();
+        let mut expanded = if input.is_dir() {
+            let depth = if recursive { usize::MAX } else { 1 };
+            let mut found = Vec::new();
+            for entry in WalkDir::new(input).max_depth(depth).follow_links(true) {
+                let entry = entry.map_err(|e| Error::Io {
+                    path: e.path().unwrap_or(input).to_path_buf(),
+                    source: e.into(),
+                })?;
+                let name = entry.file_name().to_string_lossy();
+                if entry.file_type().is_file() && extensions.iter().any(|ext| name.ends_with(ext)) {
+                    found.push(entry.into_path());
+                }
+            }
+            if found.is_empty() {
+                return Err(Error::Config(format!(
+                    "No {} files in directory {}{}",
+                    extensions.join(" or "),
+                    input.display(),
+                    if recursive { "" } else { " (use --recursive to search subdirectories)" }
+                )));
+            }
+            found
+        } else if !input.exists() && spec.contains(['*', '?', '[']) {
+            let paths = glob::glob(&spec).map_err(|e| {
+                Error::Config(format!("Invalid input pattern '{}': {}", spec, e.msg))
+            })?;
+            let found: Vec<PathBuf> =
+                paths.filter_map(Result::ok).filter(|path| path.is_file()).collect();
+            if found.is_empty() {
+                return Err(Error::Config(format!("Input pattern '{}' matched no files", spec)));
+            }
+            found
+        } else {
+            vec![input.clone()]
+        };
+        if expanded.len() > 1 {
+            expanded.sort();
+            debug!("{}: {} input files", spec, expanded.len());
+        }
+        files.extend(expanded.into_iter().filter(|file| seen.insert(file.clone())));
+    }
+    Ok(files)
+}
+
+/// A named fragment length window from `--size-classes`, written to its own bigWig.
+#[derive(Clone)]
+pub struct SizeClass {
+    name: String,
+    lengths: LengthFilter,
+}
+
+/// Parse `[NAME=]MIN-MAX` (bounds inclusive); an unnamed class is called `MIN-MAX`.
+pub fn parse_size_class(s: &str) -> Result<SizeClass, String> {
+    let (name, range) = match s.split_once('=') {
+        Some((name, range)) => (name.trim(), range.trim()),
+        None => (s.trim(), s.trim()),
+    };
+    let (min, max) = range
+        .split_once('-')
+        .ok_or_else(|| format!("expected [NAME=]MIN-MAX, got '{}'", s))?;
+    let min: u64 = min
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid minimum length '{}'", min))?;
+    let max: u64 = max
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid maximum length '{}'", max))?;
+    if min > max {
+        return Err(format!("minimum {} exceeds maximum {} in '{}'", min, max, s));
+    }
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!(
+            "class name '{}' may only contain letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+    Ok(SizeClass {
+        name: name.to_string(),
+        lengths: LengthFilter {
+            min: Some(min),
+            max: Some(max),
+        },
+    })
+}
+
+/// The strand a `--stranded` track holds.
+#[derive(Clone, Copy)]
+enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Strand {
+    /// Part of the track's output names.
+    fn suffix(self) -> &'static str {
+        match self {
+            Strand::Forward => "fwd",
+            Strand::Reverse => "rev",
+        }
+    }
+
+    /// Value of the BED strand column.
+    fn symbol(self) -> &'static str {
+        match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+        }
+    }
+
+    /// bamCoverage `--filterRNAstrand` value.
+    fn as_arg(self) -> &'static str {
+        match self {
+            Strand::Forward => "forward",
+            Strand::Reverse => "reverse",
+        }
+    }
+}
+
+/// One track written for each sample: every fragment, or those of one size class and/or
+/// one strand.
+#[derive(Clone, Copy)]
+struct Track<'a> {
+    class: Option<&'a SizeClass>,
+    strand: Option<Strand>,
+}
+
+impl<'a> Track<'a> {
+    /// The tracks each sample gets: one per size class, each split by strand with
+    /// `--stranded`.
+    fn all(size_classes: &'a [SizeClass], stranded: bool) -> Vec<Track<'a>> {
+        let classes: Vec<Option<&SizeClass>> = if size_classes.is_empty() {
+            vec![None]
+        } else {
+            size_classes.iter().map(Some).collect()
+        };
+        let strands = if stranded {
+            vec![Some(Strand::Forward), Some(Strand::Reverse)]
+        } else {
+            vec![None]
+        };
+        classes
+            .into_iter()
+            .flat_map(|class| strands.iter().map(move |&strand| Track { class, strand }))
+            .collect()
+    }
+
+    /// Prefix of the track's output suffixes, e.g. `mono_fwd`; `None` for a sample's only
+    /// track.
+    fn name(&self) -> Option<String> {
+        match (self.class, self.strand) {
+            (None, None) => None,
+            (Some(class), None) => Some(class.name.clone()),
+            (None, Some(strand)) => Some(strand.suffix().to_string()),
+            (Some(class), Some(strand)) => Some(format!("{}_{}", class.name, strand.suffix())),
+        }
+    }
+
+    /// Whether a sampled BED line belongs in the track. Lines without a strand are in no
+    /// stranded track.
+    fn keeps_bed_line(&self, line: &str) -> bool {
+        self.class.is_none_or(|class| class.lengths.keeps_bed_line(line))
+            && self.strand.is_none_or(|strand| {
+                line.split('\t').nth(5).map(str::trim) == Some(strand.symbol())
+            })
+    }
+}
+
+/// Fragments counted for one sample, split by whether they pass the chromosome and length
+/// filters.
+#[derive(Clone, Default)]
+struct FragmentCount {
+    kept: usize,
+    length_filtered: usize,
+    chrom_filtered: usize,
+    /// Every fragment by chromosome, before filtering, for `--per-chrom-report`
+    per_chrom: Vec<(String, usize)>,
+    /// Fragments on a mitochondrial chromosome, before filtering
+    mito: usize,
+    /// Fragments overlapping the blacklist, before filtering
+    blacklisted: usize,
+    /// Lines skipped for lacking a chrom and valid start and end
+    malformed: usize,
+    /// Exact duplicates of an earlier fragment, left out of every other count (`--dedup`)
+    duplicates: usize,
+    /// Fragments on a spike-in chromosome, before filtering
+    spikein: usize,
+    /// Fragments that were empty or ran past their chromosome's end, dropped or clamped
+    /// by `--on-bad-coord`
+    bad_coords: usize,
+    /// BEDPE pairs with mates on different chromosomes, which are dropped (`--bedpe`)
+    interchromosomal: usize,
+    /// Kept fragments by length, for `--length-histogram` over the full library
+    lengths: LengthHistogram,
+}
+
+/// Regions whose share of each sample's fragments goes into the QC report.
+#[derive(Default)]
+struct QcRegions<'a> {
+    /// Mitochondrial chromosome names (`--mito-chroms`)
+    mito: &'a [String],
+    /// Spike-in chromosome names (`--spikein-chroms`)
+    spikein: &'a [String],
+    blacklist: Option<&'a Blacklist>,
+    /// The blacklist file, for `samtools view -L`
+    #[cfg_attr(feature = "htslib", allow(dead_code))]
+    blacklist_path: Option<&'a Path>,
+}
+
+/// Usual names of the mitochondrial chromosome, looked for when `--mito-name` isn't given.
+const MITO_NAMES: [&str; 4] = ["chrM", "MT", "chrMT", "M"];
+
+/// The mitochondrial chromosome names: `names` when given, otherwise whichever of
+/// [`MITO_NAMES`] the reference's chromosomes include. When none of them is there, all are
+/// kept with a warning, so fragments on an unlisted `chrM` still count.
+fn mito_names(names: &[String], reference: Option<&[String]>) -> Vec<String> {
+    let Some(reference) = reference else {
+        return match names {
+            [] => MITO_NAMES.map(String::from).to_vec(),
+            names => names.to_vec(),
+        };
+    };
+    if !names.is_empty() {
+        if !names.iter().any(|name| reference.contains(name)) {
+            warn!("--mito-name: {} is not in the reference's chromosomes", names.join(", "));
+        }
+        return names.to_vec();
+    }
+    let found: Vec<String> = MITO_NAMES
+        .iter()
+        .filter(|name| reference.iter().any(|chrom| chrom == *name))
+        .map(|name| name.to_string())
+        .collect();
+    if found.is_empty() {
+        warn!(
+            "None of {} is in the reference's chromosomes; name the mitochondrial chromosome \
+             with --mito-name",
+            MITO_NAMES.join(", ")
+        );
+        return MITO_NAMES.map(String::from).to_vec();
+    }
+    debug!("Mitochondrial chromosome: {}", found.join(", "));
+    found
+}
+
+impl QcRegions<'_> {
+    fn is_mito(&self, chrom: &str) -> bool {
+        self.mito.iter().any(|m| m == chrom)
+    }
+
+    fn is_spikein(&self, chrom: &str) -> bool {
+        self.spikein.iter().any(|m| m == chrom)
+    }
+
+    /// `samtools view -e` expression selecting the mitochondrial chromosomes.
+    #[cfg_attr(feature = "htslib", allow(dead_code))]
+    fn mito_expr(&self) -> Option<String> {
+        rname_expr(self.mito)
+    }
+
+    /// `samtools view -e` expression selecting the spike-in chromosomes.
+    #[cfg_attr(feature = "htslib", allow(dead_code))]
+    fn spikein_expr(&self) -> Option<String> {
+        rname_expr(self.spikein)
+    }
+}
+
+/// `samtools view -e` expression matching reads on any of `chroms`, `None` when empty.
+#[cfg_attr(feature = "htslib", allow(dead_code))]
+fn rname_expr(chroms: &[String]) -> Option<String> {
+    let names: Vec<String> = chroms.iter().map(|m| format!("rname == \"{}\"", m)).collect();
+    (!names.is_empty()).then(|| names.join(" || "))
+}
+
+impl FragmentCount {
+    fn total(&self) -> usize {
+        self.kept + self.length_filtered + self.chrom_filtered
+    }
+}
+
+impl std::ops::AddAssign for FragmentCount {
+    fn add_assign(&mut self, other: Self) {
+        self.kept += other.kept;
+        self.length_filtered += other.length_filtered;
+        self.chrom_filtered += other.chrom_filtered;
+        self.mito += other.mito;
+        self.blacklisted += other.blacklisted;
+        self.malformed += other.malformed;
+        self.duplicates += other.duplicates;
+        self.spikein += other.spikein;
+        self.bad_coords += other.bad_coords;
+        self.interchromosomal += other.interchromosomal;
+        for (chrom, n) in other.per_chrom {
+            tally_chrom(&mut self.per_chrom, &chrom, n);
+        }
+        for (bin, n) in other.lengths {
+            *self.lengths.entry(bin).or_default() += n;
+        }
+    }
+}
+
+/// Add `n` to `chrom`'s entry, appending it if new. Sorted input hits the last entry.
+fn tally_chrom(per_chrom: &mut Vec<(String, usize)>, chrom: &str, n: usize) {
+    let found = match per_chrom.last() {
+        Some((last, _)) if last == chrom => Some(per_chrom.len() - 1),
+        _ => per_chrom.iter().position(|(c, _)| c == chrom),
+    };
+    match found {
+        Some(i) => per_chrom[i].1 += n,
+        None => per_chrom.push((chrom.to_string(), n)),
+    }
+}
+
+/// Fragment counts keyed by the lower bound of their length bin, for `--length-histogram`.
+type LengthHistogram = BTreeMap<u64, usize>;
+
+/// Add a fragment of `length` bp to its `width`-bp bin.
+fn tally_length(histogram: &mut LengthHistogram, length: u64, width: u64) {
+    *histogram.entry(length / width * width).or_default() += 1;
+}
+
+/// Which lines of a fragment BED file are read as fragments, shared by counting and
+/// sampling so both see the same ones.
+struct BedLineFilter<'a> {
+    no_header: bool,
+    chroms: &'a ChromFilter,
+    lengths: &'a LengthFilter,
+    /// Skip exact duplicates of an earlier fragment in the same file (`--dedup`)
+    dedup: bool,
+    /// Chromosome lengths from the chrom sizes, bounding every fragment
+    chrom_lengths: &'a HashMap<String, u32>,
+    on_bad_coord: BadCoords,
+    /// Require a numeric score column (`--weighted`)
+    weighted: bool,
+    /// Lines are BEDPE mate pairs, each read as the fragment it spans (`--bedpe`)
+    bedpe: bool,
+}
+
+impl BedLineFilter<'_> {
+    /// The fragment a data line holds: the line itself, or the span of a BEDPE pair.
+    fn read<'l>(&self, line: &'l str) -> DataLine<'l> {
+        if self.bedpe {
+            bedpe_fragment(line)
+        } else if is_malformed(line) {
+            DataLine::Malformed
+        } else {
+            DataLine::Fragment(Cow::Borrowed(line))
+        }
+    }
+
+    /// Whether a fragment is empty or runs past its chromosome's end. Chromosomes missing
+    /// from the chrom sizes aren't checked here.
+    fn has_bad_coords(&self, line: &str) -> bool {
+        parse_interval(line).is_some_and(|(chrom, start, end)| {
+            start == end || self.chrom_lengths.get(chrom).is_some_and(|&len| end > len as u64)
+        })
+    }
+
+    /// Whether a fragment with bad coordinates is kept, to be clamped after sampling.
+    fn keeps_bad_coords(&self, line: &str) -> bool {
+        self.on_bad_coord == BadCoords::Clamp && clamp_to_chrom(line, self.chrom_lengths).is_some()
+    }
+}
+
+/// `line` with its end cut to the chromosome length, or `None` when nothing is left of it.
+fn clamp_to_chrom<'a>(line: &'a str, chrom_lengths: &HashMap<String, u32>) -> Option<Cow<'a, str>> {
+    let (chrom, start, end) = parse_interval(line)?;
+    let len = chrom_lengths.get(chrom).map_or(end, |&len| len as u64);
+    if start >= end.min(len) {
+        return None;
+    }
+    if end <= len {
+        return Some(Cow::Borrowed(line));
+    }
+    let rest = line.splitn(4, '\t').nth(3);
+    let clamped = format!("{}\t{}\t{}", chrom, start, len);
+    Some(Cow::Owned(match rest {
+        Some(rest) => format!("{}\t{}", clamped, rest),
+        None => clamped,
+    }))
+}
+
+/// Fragments seen so far in one file, as hashes of chrom, start, end and, on BED6 or wider
+/// lines, strand. Hashes keep this to 8 bytes per distinct fragment; a collision would drop
+/// one fragment in billions.
+#[derive(Default)]
+struct SeenFragments(HashSet<u64>);
+
+impl SeenFragments {
+    /// Whether `line` repeats a fragment seen before; otherwise it is remembered.
+    fn is_duplicate(&mut self, line: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        let mut fields = line.split('\t');
+        for field in fields.by_ref().take(3) {
+            field.trim().hash(&mut hasher);
+        }
+        fields.nth(2).map(str::trim).hash(&mut hasher);
+        !self.0.insert(hasher.finish())
+    }
+}
+
+fn count_fragments(
+    path: &Path,
+    filter: &BedLineFilter,
+    per_chrom: bool,
+    regions: &QcRegions,
+    strict: bool,
+    histogram_bin: Option<u64>,
+) -> Result<FragmentCount, Error> {
+    let mut reader = open_bed(path).map_err(Error::io(path))?;
+    let (_, first) = read_header(&mut reader, filter.no_header).map_err(Error::io(path))?;
+    let mut seen = SeenFragments::default();
+    // Line numbers start after a header line
+    let first_line = if first.is_some() { 1 } else { 2 };
+    let mut count = FragmentCount::default();
+    for (i, line) in first.into_iter().map(Ok).chain(reader.lines()).enumerate() {
+        let line = line.map_err(Error::io(path))?;
+        if line.trim().is_empty() || is_comment_line(&line) {
+            continue;
+        }
+        let line = match filter.read(&line) {
+            DataLine::Fragment(fragment) => fragment,
+            DataLine::Interchromosomal => {
+                count.interchromosomal += 1;
+                continue;
+            }
+            DataLine::Malformed if strict => {
+                return Err(Error::Parse(format!(
+                    "{}: line {}: malformed {} line '{}' (--strict)",
+                    path.display(),
+                    i + first_line,
+                    if filter.bedpe { "BEDPE" } else { "BED" },
+                    line
+                )));
+            }
+            DataLine::Malformed => {
+                count.malformed += 1;
+                continue;
+            }
+        };
+        if filter.weighted && fragment_score(&line).is_none() {
+            return Err(Error::Parse(format!(
+                "{}: line {}: no numeric score in the fifth column of '{}' (--weighted)",
+                path.display(),
+                i + first_line,
+                line
+            )));
+        }
+        if filter.has_bad_coords(&line) {
+            if filter.on_bad_coord == BadCoords::Error {
+                return Err(Error::Parse(format!(
+                    "{}: line {}: fragment '{}' is empty or runs past the chromosome end \
+                     (--on-bad-coord error)",
+                    path.display(),
+                    i + first_line,
+                    line
+                )));
+            }
+            count.bad_coords += 1;
+            if !filter.keeps_bad_coords(&line) {
+                continue;
+            }
+        }
+        if filter.dedup && seen.is_duplicate(&line) {
+            count.duplicates += 1;
+            continue;
+        }
+        let chrom = line.split('\t').next().unwrap_or("");
+        if per_chrom {
+            tally_chrom(&mut count.per_chrom, chrom, 1);
+        }
+        if regions.is_mito(chrom) {
+            count.mito += 1;
+        }
+        if regions.is_spikein(chrom) {
+            count.spikein += 1;
+        }
+        if regions.blacklist.is_some_and(|blacklist| blacklist.overlaps(&line)) {
+            count.blacklisted += 1;
+        }
+        if !filter.chroms.keeps_bed_line(&line) {
+            count.chrom_filtered += 1;
+        } else if filter.lengths.keeps_bed_line(&line) {
+            count.kept += 1;
+            if let (Some(width), Some(length)) = (histogram_bin, fragment_length(&line)) {
+                tally_length(&mut count.lengths, length, width);
+            }
+        } else {
+            count.length_filtered += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Derive a per-file seed from the global seed and the file path (FNV-1a), so each
+/// sample gets an independent but reproducible random stream.
+fn file_seed(seed: u64, path: &Path) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64 ^ seed;
+    for b in path.to_string_lossy().bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// samtools takes the seed as the integer part of `-s`, so keep it within i32 range.
+fn samtools_seed(seed: u64) -> u64 {
+    seed % (i32::MAX as u64)
+}
+
+/// Build the `samtools view -s SEED.FRACTION` argument, with the seed reduced by
+/// [`samtools_seed`]. Returns `None` when the fraction keeps every read, since `-s` can't
+/// express 1.0 (`42.1000` would mean 10%).
+/// The fraction is written with nine decimals, clamped so it never rounds to 0 or 1.
+fn samtools_subsample_arg(seed: u64, fraction: f64) -> Option<String> {
+    if fraction >= 1.0 {
+        return None;
+    }
+    let digits = format!("{:.9}", fraction.clamp(1e-9, 0.999_999_999));
+    let frac = digits["0.".len()..].trim_end_matches('0');
+    Some(format!("{}.{}", samtools_seed(seed), frac))
+}
+
+/// Strip the line terminator `read_line` keeps, as `BufRead::lines` does. The first line
+/// of a file is trimmed of all trailing whitespace, as `read_header` does.
+fn strip_newline(line: &str, offset: u64) -> &str {
+    if offset == 0 {
+        return line.trim_end();
+    }
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Uniform reservoir sample (Algorithm R) of `min_count` lines over the union of `paths`,
+/// after each file's header line if there is one; the first file's header is kept. Lines
+/// on filtered chromosomes, outside the length window or, with `--dedup`, repeating an
+/// earlier fragment of their file are skipped before sampling. Without `min_count` every
+/// line is kept. `slot` turns a picked line, given its file's index in `paths` and its
+/// byte offset there, into what the reservoir holds.
+fn reservoir_sample<T, R: Rng>(
+    paths: &[PathBuf],
+    min_count: Option<usize>,
+    filter: &BedLineFilter,
+    rng: &mut R,
+    mut slot: impl FnMut(usize, u64, &str) -> T,
+) -> Result<(Option<String>, Vec<T>), Error> {
+    let mut header = None;
+    let mut sample: Vec<T> = Vec::with_capacity(min_count.unwrap_or(0));
+    let min_count = min_count.unwrap_or(usize::MAX);
+    let mut seen = 0usize;
+    let mut buf = String::new();
+    for (n, path) in paths.iter().enumerate() {
+        let mut reader = open_bed(path).map_err(Error::io(path))?;
+        let mut fragments = SeenFragments::default();
+        let mut pos = 0u64;
+        loop {
+            buf.clear();
+            let read = reader.read_line(&mut buf).map_err(Error::io(path))?;
+            if read == 0 {
+                break;
+            }
+            let offset = pos;
+            pos += read as u64;
+            let line = strip_newline(&buf, offset);
+            if offset == 0 && !filter.no_header && is_header_line(line) {
+                // A BEDPE header doesn't fit the fragments
+                if n == 0 && !filter.bedpe {
+                    header = Some(line.to_string());
+                }
+                continue;
+            }
+            if line.trim().is_empty() || is_comment_line(line) {
+                continue;
+            }
+            // Counting already reported the malformed lines, or failed on them with --strict
+            let DataLine::Fragment(fragment) = filter.read(line) else {
+                continue;
+            };
+            let line: &str = &fragment;
+            if !filter.chroms.keeps_bed_line(line) || !filter.lengths.keeps_bed_line(line) {
+                continue;
+            }
+            if filter.has_bad_coords(line) && !filter.keeps_bad_coords(line) {
+                continue;
+            }
+            if filter.dedup && fragments.is_duplicate(line) {
+                continue;
+            }
+            if seen < min_count {
+                sample.push(slot(n, offset, line));
+            } else {
+                let j = rng.gen_range(0..=seen);
+                if j < min_count {
+                    sample[j] = slot(n, offset, line);
+                }
+            }
+            seen += 1;
+        }
+    }
+    Ok((header, sample))
+}
+
+/// Second pass of `--low-memory` sampling: read back the lines picked as `(file, offset)`
+/// slots into one buffer, returning it with each slot's range in it. Every file is read in
+/// offset order; plain files are seeked, gzip streams are decompressed up to each line.
+fn read_sampled_lines(
+    paths: &[PathBuf],
+    slots: &[(usize, u64)],
+) -> Result<(String, Vec<Range<usize>>), Error> {
+    let mut order: Vec<usize> = (0..slots.len()).collect();
+    order.sort_unstable_by_key(|&i| slots[i]);
+    let mut packed = String::new();
+    let mut ranges = vec![0..0; slots.len()];
+    let mut line = String::new();
+    for group in order.chunk_by(|&a, &b| slots[a].0 == slots[b].0) {
+        let path = &paths[slots[group[0]].0];
+        let mut read_group = || -> std::io::Result<()> {
+            let is_gzip = BufReader::new(File::open(path)?).fill_buf()?.starts_with(&[0x1f, 0x8b]);
+            let mut plain = BufReader::new(File::open(path)?);
+            let mut gzip = if is_gzip { Some(open_bed(path)?) } else { None };
+            let mut pos = 0u64;
+            for &i in group {
+                let offset = slots[i].1;
+                let reader: &mut dyn BufRead = match &mut gzip {
+                    Some(gzip) => {
+                        std::io::copy(&mut gzip.take(offset - pos), &mut std::io::sink())?;
+                        gzip
+                    }
+                    None => {
+                        plain.seek_relative((offset - pos) as i64)?;
+                        &mut plain
+                    }
+                };
+                line.clear();
+                pos = offset + reader.read_line(&mut line)? as u64;
+                let start = packed.len();
+                packed.push_str(strip_newline(&line, offset));
+                ranges[i] = start..packed.len();
+            }
+            Ok(())
+        };
+        read_group().map_err(Error::io(path))?;
+    }
+    Ok((packed, ranges))
+}
+
+/// Where to get each external tool, for the preflight error message.
+fn install_hint(tool: &str) -> &'static str {
+    match tool {
+        "samtools" => "http://www.htslib.org/ (conda install -c bioconda samtools)",
+        "bamCoverage" => "deepTools (conda install -c bioconda deeptools)",
+        "bedGraphToBigWig" => "UCSC tools (conda install -c bioconda ucsc-bedgraphtobigwig)",
+        _ => "your system package manager",
+    }
+}
+
+/// SAM flag of a read marked as a PCR or optical duplicate.
+const DUPLICATE_FLAG: u16 = 0x400;
+
+/// Which BAM records count as fragments. The same filter drives the QC count and the
+/// downsampling `samtools view`, so the downsampling fraction is computed over exactly the
+/// reads that end up in the track.
+struct BamFilter {
+    /// SAM flags a read must have (default: properly paired)
+    include_flags: u16,
+    /// SAM flags a read must not have (default: unmapped, secondary)
+    exclude_flags: u16,
+    /// Minimum mapping quality
+    min_mapq: u8,
+    /// Fragment length window on |TLEN|
+    lengths: LengthFilter,
+    /// Chromosomes a read must be mapped to
+    chroms: ChromFilter,
+}
+
+impl BamFilter {
+    /// Build the filter from the pipeline settings. Paired-end data defaults to `-f 2 -F 260`;
+    /// single-end data drops the proper-pair requirement, which would otherwise discard every
+    /// read. Explicit include/exclude flags win over either default; `dedup` adds the
+    /// duplicate flag to the excluded ones.
+    fn new(
+        include_flags: Option<u16>,
+        exclude_flags: Option<u16>,
+        single_end: bool,
+        min_mapq: u8,
+        lengths: LengthFilter,
+        chroms: ChromFilter,
+        dedup: bool,
+    ) -> Self {
+        let default_include = if single_end { 0 } else { 2 };
+        let dedup_flag = if dedup { DUPLICATE_FLAG } else { 0 };
+        BamFilter {
+            include_flags: include_flags.unwrap_or(default_include),
+            exclude_flags: exclude_flags.unwrap_or(260) | dedup_flag,
+            min_mapq,
+            lengths,
+            chroms,
+        }
+    }
+
+    #[cfg(feature = "htslib")]
+    fn keeps(&self, flags: u16, mapq: u8) -> bool {
+        flags & self.include_flags == self.include_flags
+            && flags & self.exclude_flags == 0
+            && mapq >= self.min_mapq
+    }
+
+    /// Equivalent `samtools view` filter arguments, including the chromosome and length
+    /// expressions.
+    fn samtools_args(&self) -> Vec<String> {
+        self.args_with(true, true)
+    }
+
+    /// `samtools view` flag and MAPQ arguments plus, when asked for, the chromosome and
+    /// length filters as one `-e` expression.
+    fn args_with(&self, chroms: bool, lengths: bool) -> Vec<String> {
+        let mut args = self.flag_args();
+        let exprs: Vec<String> = [
+            chroms.then(|| self.chroms.samtools_expr()).flatten(),
+            lengths.then(|| self.lengths.samtools_expr()).flatten(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let expr = match exprs.as_slice() {
+            [] => None,
+            [expr] => Some(expr.clone()),
+            _ => Some(format!("({})", exprs.join(") && ("))),
+        };
+        if let Some(expr) = expr {
+            args.push("-e".to_string());
+            args.push(expr);
+        }
+        args
+    }
+
+    /// `samtools view` flag and MAPQ arguments, without the length filter.
+    fn flag_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-f".to_string(),
+            self.include_flags.to_string(),
+            "-F".to_string(),
+            self.exclude_flags.to_string(),
+        ];
+        if self.min_mapq > 0 {
+            args.push("-q".to_string());
+            args.push(self.min_mapq.to_string());
+        }
+        args
+    }
+
+    /// `samtools view` flag and MAPQ arguments selecting the marked duplicates that the
+    /// filter drops but that pass every other flag and the MAPQ.
+    #[cfg_attr(feature = "htslib", allow(dead_code))]
+    fn duplicate_args(&self) -> Vec<String> {
+        let mut args = self.flag_args();
+        args[1] = (self.include_flags | DUPLICATE_FLAG).to_string();
+        args[3] = (self.exclude_flags & !DUPLICATE_FLAG).to_string();
+        args
+    }
+}
+
+/// Count BAM records that pass `filter`'s flags and MAPQ, split by the chromosome and
+/// length filters.
+#[cfg(feature = "htslib")]
+fn count_bam_fragments(
+    tools: &Tools,
+    path: &Path,
+    filter: &BamFilter,
+    regions: &QcRegions,
+    histogram_bin: Option<u64>,
+) -> Result<FragmentCount, Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let htslib_err = |source| Error::Htslib {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut reader = bam::Reader::from_path(path).map_err(htslib_err)?;
+    if let Some(reference) = &tools.reference {
+        reader.set_reference(reference).map_err(htslib_err)?;
+    }
+    let header = reader.header();
+    let chrom_names: Vec<String> = (0..header.target_count())
+        .map(|tid| String::from_utf8_lossy(header.tid2name(tid)).into_owned())
+        .collect();
+    let mut record = bam::Record::new();
+    let mut count = FragmentCount::default();
+    while let Some(result) = reader.read(&mut record) {
+        result.map_err(htslib_err)?;
+        if !filter.keeps(record.flags(), record.mapq()) {
+            let flags = record.flags();
+            if flags & DUPLICATE_FLAG != 0 && filter.keeps(flags & !DUPLICATE_FLAG, record.mapq()) {
+                count.duplicates += 1;
+            }
+            continue;
+        }
+        // Unmapped reads have no reference
+        let chrom = usize::try_from(record.tid()).map_or("", |tid| chrom_names[tid].as_str());
+        if regions.is_mito(chrom) {
+            count.mito += 1;
+        }
+        if regions.is_spikein(chrom) {
+            count.spikein += 1;
+        }
+        if let Some(blacklist) = regions.blacklist {
+            let start = record.pos().max(0) as u64;
+            let end = record.cigar().end_pos().max(0) as u64;
+            if blacklist.overlaps_interval(chrom, start, end) {
+                count.blacklisted += 1;
+            }
+        }
+        if filter.chroms.is_active() && !filter.chroms.keeps(chrom) {
+            count.chrom_filtered += 1;
+            continue;
+        }
+        if filter.lengths.keeps(record.insert_size().unsigned_abs()) {
+            count.kept += 1;
+            // One mate of each pair, the leftmost, has a positive TLEN
+            let length = u64::try_from(record.insert_size());
+            if let (Some(width), Ok(length @ 1..)) = (histogram_bin, length) {
+                tally_length(&mut count.lengths, length, width);
+            }
+        } else {
+            count.length_filtered += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Count BAM records that pass `filter`'s flags and MAPQ, split by the chromosome and
+/// length filters. Each active filter and QC region takes another `samtools view -c` pass.
+/// Fragment lengths need rust-htslib, so no histogram is kept.
+#[cfg(not(feature = "htslib"))]
+fn count_bam_fragments(
+    tools: &Tools,
+    path: &Path,
+    filter: &BamFilter,
+    regions: &QcRegions,
+    _histogram_bin: Option<u64>,
+) -> Result<FragmentCount, Error> {
+    let samtools_count = |args: Vec<String>| -> Result<usize, Error> {
+        let output = tools.run(
+            tools.samtools_reading("view").arg("-c").args(args).arg(path),
+            "samtools view -c",
+        )?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.trim().parse().map_err(|_| {
+            Error::Parse(format!("unexpected samtools view -c output '{}'", stdout.trim()))
+        })
+    };
+    let total = samtools_count(filter.flag_args())?;
+    let on_chroms = if filter.chroms.is_active() {
+        samtools_count(filter.args_with(true, false))?
+    } else {
+        total
+    };
+    let kept = if filter.lengths.is_active() {
+        samtools_count(filter.samtools_args())?
+    } else {
+        on_chroms
+    };
+    let with_flags = |extra: &[&str]| {
+        let mut args = filter.flag_args();
+        args.extend(extra.iter().map(|a| a.to_string()));
+        samtools_count(args)
+    };
+    let mito = match regions.mito_expr() {
+        Some(expr) => with_flags(&["-e", &expr])?,
+        None => 0,
+    };
+    let spikein = match regions.spikein_expr() {
+        Some(expr) => with_flags(&["-e", &expr])?,
+        None => 0,
+    };
+    let blacklisted = match regions.blacklist_path {
+        Some(bed) => with_flags(&["-L", &bed.to_string_lossy()])?,
+        None => 0,
+    };
+    let duplicates = if filter.exclude_flags & DUPLICATE_FLAG != 0 {
+        samtools_count(filter.duplicate_args())?
+    } else {
+        0
+    };
+    Ok(FragmentCount {
+        kept,
+        length_filtered: on_chroms.saturating_sub(kept),
+        chrom_filtered: total.saturating_sub(on_chroms),
+        per_chrom: Vec::new(),
+        mito,
+        blacklisted,
+        malformed: 0,
+        duplicates,
+        spikein,
+        bad_coords: 0,
+        interchromosomal: 0,
+        lengths: LengthHistogram::new(),
+    })
+}
+
+/// Mapped reads per chromosome from the BAM index, in header order. Needs a `.bai`.
+#[cfg(feature = "htslib")]
+fn bam_chrom_counts(_tools: &Tools, path: &Path) -> Result<Vec<(String, usize)>, Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let htslib_err = |source| Error::Htslib {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut reader = bam::IndexedReader::from_path(path).map_err(htslib_err)?;
+    let header = reader.header().clone();
+    let stats = reader.index_stats().map_err(htslib_err)?;
+    Ok(stats
+        .into_iter()
+        .filter_map(|(tid, _, mapped, _)| {
+            let tid = u32::try_from(tid).ok()?;
+            let chrom = String::from_utf8_lossy(header.tid2name(tid)).to_string();
+            Some((chrom, mapped as usize))
+        })
+        .collect())
+}
+
+/// Mapped reads per chromosome from `samtools idxstats`, in header order. Needs a `.bai`.
+#[cfg(not(feature = "htslib"))]
+fn bam_chrom_counts(tools: &Tools, path: &Path) -> Result<Vec<(String, usize)>, Error> {
+    let output = tools.run(
+        Command::new(&tools.samtools).arg("idxstats").arg(path),
+        "samtools idxstats",
+    )?;
+    let mut per_chrom = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        // The last line, `*`, holds the unplaced unmapped reads
+        if fields.len() < 3 || fields[0] == "*" {
+            continue;
+        }
+        let mapped = fields[2].parse().map_err(|_| {
+            Error::Parse(format!("unexpected samtools idxstats line '{}'", line))
+        })?;
+        per_chrom.push((fields[0].to_string(), mapped));
+    }
+    Ok(per_chrom)
+}
+
+/// `(chrom, length)` pairs from the `@SQ` lines of a BAM header, in header order.
+#[cfg(feature = "htslib")]
+fn read_bam_chroms(_tools: &Tools, path: &Path) -> Result<Vec<(String, u32)>, Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let reader = bam::Reader::from_path(path).map_err(|source| Error::Htslib {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let header = reader.header();
+    Ok((0..header.target_count())
+        .map(|tid| {
+            let name = String::from_utf8_lossy(header.tid2name(tid)).to_string();
+            let len = header.target_len(tid).unwrap_or(0) as u32;
+            (name, len)
+        })
+        .collect())
+}
+
+/// `(chrom, length)` pairs from the `@SQ` lines of a BAM header, in header order.
+#[cfg(not(feature = "htslib"))]
+fn read_bam_chroms(tools: &Tools, path: &Path) -> Result<Vec<(String, u32)>, Error> {
+    let output = tools.run(
+        Command::new(&tools.samtools).args(["view", "-H"]).arg(path),
+        "samtools view -H",
+    )?;
+    let mut chroms = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if !line.starts_with("@SQ") {
+            continue;
+        }
+        let tag = |key: &str| line.split('\t').find_map(|field| field.strip_prefix(key));
+        let (Some(name), Some(len)) = (tag("SN:"), tag("LN:")) else {
+            continue;
+        };
+        let len = len.parse().map_err(|_| {
+            Error::Parse(format!("{}: invalid @SQ length '{}' for {}", path.display(), len, name))
+        })?;
+        chroms.push((name.to_string(), len));
+    }
+    Ok(chroms)
+}
+
+/// The `SO:` sort order of a SAM header's `@HD` line, if it has one.
+fn header_sort_order(header: &str) -> Option<&str> {
+    let hd = header.lines().find(|line| line.starts_with("@HD"))?;
+    hd.split('\t').find_map(|field| field.strip_prefix("SO:"))
+}
+
+/// Sort order from a BAM header's `@HD` line.
+#[cfg(feature = "htslib")]
+fn read_bam_sort_order(_tools: &Tools, path: &Path) -> Result<Option<String>, Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let reader = bam::Reader::from_path(path).map_err(|source| Error::Htslib {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let header = String::from_utf8_lossy(reader.header().as_bytes()).to_string();
+    Ok(header_sort_order(&header).map(str::to_string))
+}
+
+/// Sort order from a BAM header's `@HD` line.
+#[cfg(not(feature = "htslib"))]
+fn read_bam_sort_order(tools: &Tools, path: &Path) -> Result<Option<String>, Error> {
+    let output = tools.run(
+        Command::new(&tools.samtools).args(["view", "-H"]).arg(path),
+        "samtools view -H",
+    )?;
+    let header = String::from_utf8_lossy(&output.stdout);
+    Ok(header_sort_order(&header).map(str::to_string))
+}
+
+/// The index samtools would pick up for a BAM or CRAM, if there is one.
+fn find_bam_index(path: &Path) -> Option<PathBuf> {
+    let name = path.as_os_str().to_string_lossy();
+    let candidates = if is_cram(path) {
+        vec![format!("{}.crai", name)]
+    } else {
+        vec![
+            format!("{}.bai", name),
+            format!("{}.csi", name),
+            path.with_extension("bai").to_string_lossy().to_string(),
+        ]
+    };
+    candidates.into_iter().map(PathBuf::from).find(|index| index.is_file())
+}
+
+/// Whether `path` is a CRAM, by its extension.
+fn is_cram(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cram"))
+}
+
+/// Up to five names, then how many more there are.
+fn name_preview(names: &[&str]) -> String {
+    let mut preview = names.iter().take(5).copied().collect::<Vec<_>>().join(", ");
+    if names.len() > 5 {
+        preview.push_str(&format!(" and {} more", names.len() - 5));
+    }
+    preview
+}
+
+/// Warn where a BAM header and a chrom sizes file disagree, which usually means they come
+/// from different reference builds.
+fn warn_chrom_mismatches(
+    bam: &Path,
+    header: &[(String, u32)],
+    chrom_sizes: &Path,
+    sizes: &[(String, u32)],
+) {
+    let header_map: HashMap<&str, u32> = header.iter().map(|(c, l)| (c.as_str(), *l)).collect();
+    let sizes_map: HashMap<&str, u32> = sizes.iter().map(|(c, l)| (c.as_str(), *l)).collect();
+    let bam = bam.display();
+    let chrom_sizes = chrom_sizes.display();
+
+    let not_in_sizes: Vec<&str> = header
+        .iter()
+        .map(|(c, _)| c.as_str())
+        .filter(|c| !sizes_map.contains_key(c))
+        .collect();
+    if !not_in_sizes.is_empty() {
+        warn!(
+            "{} chromosome(s) in the header of {} are not in {}: {}",
+            not_in_sizes.len(),
+            bam,
+            chrom_sizes,
+            name_preview(&not_in_sizes)
+        );
+    }
+    let not_in_header: Vec<&str> = sizes
+        .iter()
+        .map(|(c, _)| c.as_str())
+        .filter(|c| !header_map.contains_key(c))
+        .collect();
+    if !not_in_header.is_empty() {
+        warn!(
+            "{} chromosome(s) in {} are not in the header of {}: {}",
+            not_in_header.len(),
+            chrom_sizes,
+            bam,
+            name_preview(&not_in_header)
+        );
+    }
+    for (chrom, len) in sizes {
+        if let Some(&header_len) = header_map.get(chrom.as_str()) {
+            if header_len != *len {
+                warn!(
+                    "{}: length {} in {} but {} in the header of {}",
+                    chrom, len, chrom_sizes, header_len, bam
+                );
+            }
+        }
+    }
+}
+
+/// Return the tools that cannot be started. Only a failed spawn counts as missing, since
+/// some tools (e.g. bedGraphToBigWig) exit nonzero for `--version`.
+fn missing_tools<'a>(tools: &[(&'static str, &'a Path)]) -> Vec<(&'static str, &'a Path)> {
+    tools
+        .iter()
+        .filter(|(_, path)| {
+            Command::new(path)
+                .arg("--version")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_err()
+        })
+        .copied()
+        .collect()
+}
+
+/// Per-sample result, `Err` holding a description of the failed step.
+pub type SampleOutcome = (PathBuf, Result<(), Error>);
+
+/// How a processed sample was downsampled, so the methods can be reported exactly.
+#[derive(Clone)]
+pub struct SampleDraw {
+    /// Seed of the sample's random draw, derived from the run seed and its file path; for
+    /// BAMs the integer part of `samtools view -s`
+    pub seed: u64,
+    /// The fraction passed to `samtools view -s`, rounded to nine decimals; `None` for BED
+    /// input and for BAMs kept whole
+    pub samtools_fraction: Option<f64>,
+    /// Fragments the tracks were built from: exact for BED input, the expected read count
+    /// for BAMs, since samtools keeps each read independently
+    pub retained: usize,
+}
+
+/// A file that could not be counted, with the reason.
+type CountError = (PathBuf, Error);
+
+/// Everything a BED worker needs besides the file it processes.
+struct BedContext<'a> {
+    tools: &'a Tools,
+    layout: &'a OutputLayout,
+    members: &'a HashMap<PathBuf, Vec<PathBuf>>,
+    chrom_sizes: &'a Path,
+    chrom_order: &'a HashMap<String, usize>,
+    chrom_list: &'a [(String, u32)],
+    chrom_lengths: &'a HashMap<String, u32>,
+    atac_shift: Option<&'a AtacShift>,
+    /// Length stranded reads are extended to, for `--extend`
+    extend: Option<u64>,
+    mode: CoverageMode,
+    bin_size: usize,
+    /// Bin start spacing; equal to `bin_size` unless `--step` slides the bins
+    step: usize,
+    /// `--smooth-length` window the bin counts are averaged over
+    smooth_length: Option<usize>,
+    /// `--pseudocount` added to the scaled bin counts before taking their `--log2`
+    log2: Option<f64>,
+    format: TrackFormat,
+    /// `--regions` intervals in chrom sizes order, counted instead of bins
+    regions: Option<&'a [Region]>,
+    seed: u64,
+    target: usize,
+    /// False with `--no-downsample`, when every fragment is kept
+    downsample: bool,
+    no_header: bool,
+    /// Skip exact-duplicate fragments, for `--dedup`
+    dedup: bool,
+    on_bad_coord: BadCoords,
+    /// Sum the score column over fragments instead of counting them, for `--weighted`
+    weighted: bool,
+    /// Read BEDPE mate pairs as fragments, for `--bedpe`
+    bedpe: bool,
+    /// Sample byte offsets and read the picked lines back, for `--low-memory`
+    low_memory: bool,
+    chroms: &'a ChromFilter,
+    blacklist: Option<&'a Blacklist>,
+    lengths: LengthFilter,
+    size_classes: &'a [SizeClass],
+    /// Split each track by strand, for `--stranded`
+    stranded: bool,
+    scale: Scale,
+    /// `--spikein-normalize` factor of each sample; empty without it
+    spikein_factors: &'a HashMap<PathBuf, f64>,
+    native_bigwig: bool,
+    keep_intermediates: bool,
+    /// Write the sorted fragments of each track to the outputs, for `--keep-downsampled-bed`
+    keep_downsampled_bed: bool,
+    /// Hand the track values back for `--matrix` or `--correlation`
+    matrix: bool,
+    timings: &'a Timings<'a>,
+    /// Where the sampled fragments' lengths go, for `--length-histogram` without
+    /// `--length-histogram-full`
+    length_histograms: Option<&'a LengthHistograms>,
+}
+
+/// Everything a BAM worker needs besides the file it processes.
+struct BamContext<'a> {
+    tools: &'a Tools,
+    filter: &'a BamFilter,
+    layout: &'a OutputLayout,
+    members: &'a HashMap<PathBuf, Vec<PathBuf>>,
+    blacklist: Option<&'a Path>,
+    bin_size: usize,
+    output_format: OutputFormat,
+    seed: u64,
+    downsample: bool,
+    /// Reads each sample is downsampled to
+    target: usize,
+    /// Reservoir sample exactly `target` reads, as whole templates, instead of using
+    /// `samtools view -s`
+    exact: bool,
+    size_classes: &'a [SizeClass],
+    /// Split each track by strand, for `--stranded`
+    stranded: bool,
+    mode: CoverageMode,
+    normalize: Normalization,
+    effective_genome_size: Option<u64>,
+    smooth_length: Option<usize>,
+    extend: Option<u64>,
+    /// `--spikein-normalize` factor of each sample, passed as `--scaleFactor`
+    spikein_factors: &'a HashMap<PathBuf, f64>,
+    /// Run bamCoverage per chromosome and join the parts, for `--split-by-chrom`
+    split_by_chrom: bool,
+    keep_intermediates: bool,
+    /// Threads each samtools and bamCoverage call may use
+    threads: usize,
+    timings: &'a Timings<'a>,
+    /// Where the sampled fragments' lengths go, for `--length-histogram` without
+    /// `--length-histogram-full`
+    length_histograms: Option<&'a LengthHistograms>,
+}
+
+impl BamContext<'_> {
+    /// `-@` arguments handing samtools the threads beyond its main one.
+    fn samtools_threads(&self) -> Vec<String> {
+        if self.threads > 1 {
+            vec!["-@".to_string(), (self.threads - 1).to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Progress bar length for `sample`: the merge when pooled, downsampling and indexing,
+    /// then each bamCoverage output of every track, plus the per-chromosome runs with
+    /// `--split-by-chrom`.
+    fn stages(&self, sample: &Path) -> u64 {
+        let merge = (self.members[sample].len() > 1) as u64;
+        let format = TrackFormat::Bins {
+            bin_size: self.bin_size,
+            step: None,
+            output: self.output_format,
+            log2: false,
+        };
+        let outputs = format.suffixes(None, !self.downsample).len() as u64;
+        let tracks = Track::all(self.size_classes, self.stranded).len() as u64;
+        merge + 2 + tracks * (outputs + self.split_by_chrom as u64)
+    }
+}
+
+/// What each track is written as, which decides its file name.
+#[derive(Clone, Copy)]
+enum TrackFormat {
+    /// `bin_size` bins, slid by `step` when set, as a bigWig and/or bedGraph
+    Bins { bin_size: usize, step: Option<usize>, output: OutputFormat, log2: bool },
+    /// A TSV of fragment counts over the `--regions` intervals
+    RegionCounts,
+}
+
+impl TrackFormat {
+    /// Suffixes of the outputs for one track, prefixed with the size class name if any and
+    /// marked `full` when the track wasn't downsampled. Binned tracks get one per output
+    /// format, named with the bin size, the step of sliding bins and `log2` when transformed.
+    fn suffixes(self, class: Option<&str>, full: bool) -> Vec<String> {
+        let full = if full { "full_" } else { "" };
+        let names = match self {
+            TrackFormat::Bins { bin_size, step, output, log2 } => {
+                let step = step.map(|step| format!("_step{}", step)).unwrap_or_default();
+                let log2 = if log2 { "_log2" } else { "" };
+                let name = |ext| format!("{}{}bp{}{}.{}", full, bin_size, step, log2, ext);
+                output.extensions().iter().map(name).collect()
+            }
+            TrackFormat::RegionCounts => vec![format!("{}regions.tsv", full)],
+        };
+        names
+            .into_iter()
+            .map(|name| match class {
+                Some(class) => format!("{}_{}", class, name),
+                None => name,
+            })
+            .collect()
+    }
+}
+
+/// Suffix of a track's fragment BED written with `--keep-downsampled-bed`, prefixed with the
+/// size class name if any; `full_fragments.bed` when the track wasn't downsampled.
+fn fragments_bed_suffix(class: Option<&str>, full: bool) -> String {
+    let name = if full { "full_fragments.bed" } else { "downsampled.bed" };
+    match class {
+        Some(class) => format!("{}_{}", class, name),
+        None => name.to_string(),
+    }
+}
+
+/// How the downsampling target is chosen from the samples that pass QC (`--target`,
+/// `--reference`).
+#[derive(Clone, PartialEq)]
+pub enum Target {
+    /// The smallest library, so every sample ends up at the same depth
+    Min,
+    /// The median library size
+    Median,
+    /// This percentile (0-100) of the library sizes
+    Percentile(f64),
+    /// An absolute fragment count
+    Count(usize),
+    /// The library of one sample, given by input file name, path or sample name
+    Reference(String),
+}
+
+/// Parse `min`, `median`, `percentile:<p>` or `count:<n>`.
+pub fn parse_target(s: &str) -> Result<Target, String> {
+    match s.trim().split_once(':') {
+        None if s.trim() == "min" => Ok(Target::Min),
+        None if s.trim() == "median" => Ok(Target::Median),
+        Some(("percentile", p)) => match p.trim().parse::<f64>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => Ok(Target::Percentile(p)),
+            _ => Err(format!("percentile must be between 0 and 100, got '{}'", p)),
+        },
+        Some(("count", n)) => match n.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Target::Count(n)),
+            _ => Err(format!("count must be a positive integer, got '{}'", n)),
+        },
+        _ => Err(format!(
+            "expected min, median, percentile:<p> or count:<n>, got '{}'",
+            s
+        )),
+    }
+}
+
+/// What a run does with the samples that pass QC: the common depth they are downsampled to,
+/// and for each sample the fraction of its fragments that is kept and the bigWigs written.
+pub struct DownsamplePlan {
+    /// Fragment count every sample is downsampled to, by default that of the smallest passing
+    /// sample; samples below it keep all their fragments
+    pub target: usize,
+    /// False with `--no-downsample`: every sample keeps all its fragments and the target is
+    /// only reported
+    pub downsample: bool,
+    pub samples: Vec<PlannedSample>,
+}
+
+#[derive(Clone)]
+pub struct PlannedSample {
+    pub file: PathBuf,
+    pub sample: String,
+    pub fragments: usize,
+    /// Share of the fragments kept; 1.0 for the sample that sets the target
+    pub fraction: f64,
+    /// Final bigWigs or region counts, one per size class or a single track
+    pub outputs: Vec<PathBuf>,
+}
+
+impl PlannedSample {
+    /// Whether every final output of the sample already exists and is non-empty, so a
+    /// re-run can skip it. Intermediates don't count.
+    pub fn outputs_exist(&self) -> bool {
+        self.outputs
+            .iter()
+            .all(|path| std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() > 0))
+    }
+}
+
+impl DownsamplePlan {
+    fn new(
+        qc: &QcResult,
+        target: &Target,
+        downsample: bool,
+        layout: &OutputLayout,
+        tracks: &[Track],
+        format: TrackFormat,
+        keep_bed: bool,
+    ) -> Result<Self, Error> {
+        let passed: Vec<&SampleQc> = qc.samples.iter().filter(|s| s.pass).collect();
+        if passed.is_empty() {
+            return Err(Error::NoSamplesPassQc);
+        }
+        let counts: Vec<usize> = passed.iter().map(|s| s.fragments).collect();
+        let at_quantile = |q: f64| quantile(&sorted_f64(&counts), q).unwrap_or(0.0).round();
+        let target = match target {
+            Target::Min => counts.iter().copied().min().unwrap_or(0),
+            Target::Median => at_quantile(0.5) as usize,
+            Target::Percentile(p) => at_quantile(p / 100.0) as usize,
+            Target::Count(n) => *n,
+            Target::Reference(name) => {
+                let reference = qc
+                    .samples
+                    .iter()
+                    .find(|s| {
+                        s.sample == *name
+                            || s.file.as_os_str() == name.as_str()
+                            || s.file.file_name().is_some_and(|f| f == name.as_str())
+                    })
+                    .ok_or_else(|| {
+                        Error::Config(format!("Reference sample '{}' is not an input", name))
+                    })?;
+                if !reference.pass {
+                    return Err(Error::Config(format!(
+                        "Reference sample '{}' did not pass QC",
+                        name
+                    )));
+                }
+                info!("Downsampling to reference {}", reference.file.display());
+                reference.fragments
+            }
+        };
+        // An empty library that passed QC would make every track empty
+        if downsample && target == 0 {
+            let empty: Vec<String> = (passed.iter())
+                .filter(|s| s.fragments == 0)
+                .map(|s| s.file.display().to_string())
+                .collect();
+            return Err(Error::Config(format!(
+                "The downsampling target is 0 fragments, which would leave every track empty; \
+                 samples without fragments: {}. Exclude them with --min-fragments 1",
+                empty.join(", ")
+            )));
+        }
+        if !downsample {
+            info!("Not downsampling; tracks use every fragment");
+        } else {
+            info!("Downsampling target: {} fragments", target);
+        }
+        // Samples below the target keep everything rather than being upsampled
+        for s in passed.iter().filter(|s| downsample && s.fragments < target) {
+            warn!(
+                "{}: only {} fragments, below the target of {}; keeping all of them",
+                s.file.display(),
+                s.fragments,
+                target
+            );
+        }
+        let names: Vec<Option<String>> = tracks.iter().map(Track::name).collect();
+        let samples = passed
+            .into_iter()
+            .map(|s| PlannedSample {
+                file: s.file.clone(),
+                sample: s.sample.clone(),
+                fragments: s.fragments,
+                fraction: if downsample {
+                    (target as f64 / s.fragments as f64).min(1.0)
+                } else {
+                    1.0
+                },
+                outputs: names
+                    .iter()
+                    .flat_map(|name| {
+                        let mut suffixes = format.suffixes(name.as_deref(), !downsample);
+                        if keep_bed {
+                            suffixes.push(fragments_bed_suffix(name.as_deref(), !downsample));
+                        }
+                        suffixes
+                    })
+                    .map(|suffix| layout.path(&s.file, &suffix))
+                    .collect(),
+            })
+            .collect();
+        Ok(DownsamplePlan {
+            target,
+            downsample,
+            samples,
+        })
+    }
+
+    fn log(&self) {
+        if self.downsample {
+            info!(
+                "Plan: downsample {} sample(s) to {} fragments",
+                self.samples.len(),
+                self.target
+            );
+        } else {
+            info!("Plan: {} sample(s) at full depth", self.samples.len());
+        }
+        for s in &self.samples {
+            info!(
+                "  {} ({}): {} fragments, keeping {:.4}",
+                s.file.display(),
+                s.sample,
+                s.fragments,
+                s.fraction
+            );
+            for output in &s.outputs {
+                info!("    -> {}", output.display());
+            }
+        }
+    }
+}
+
+/// Lines of a failed tool's stderr kept for its error message.
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Run `cmd` once, capturing its stdout. Its stderr is read line by line as it comes, each
+/// line logged at debug level so `-v` shows it live, and the last [`STDERR_TAIL_LINES`] are
+/// returned for the error message.
+fn run_capturing_stderr(cmd: &mut Command, step: &str) -> Result<(Output, String), Error> {
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| Error::ToolNotFound {
+            tool: step.to_string(),
+            source,
+        })?;
+    let stderr = child.stderr.take().expect("stderr is piped");
+    std::thread::scope(|scope| {
+        let tail = scope.spawn(|| {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                debug!("{}: {}", step, line);
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            Vec::from(tail).join("\n")
+        });
+        let output = child.wait_with_output().map_err(|source| Error::ToolNotFound {
+            tool: step.to_string(),
+            source,
+        })?;
+        Ok((output, tail.join().unwrap_or_default()))
+    })
+}
+
+/// `text` on indented lines after a colon, for appending to a message; empty when `text`
+/// is blank.
+fn indented_lines(text: &str) -> String {
+    if text.trim().is_empty() {
+        return String::new();
+    }
+    let lines: Vec<String> = text.lines().map(|line| format!("\n    {}", line)).collect();
+    format!(":{}", lines.concat())
+}
+
+/// File name of `path` for log and progress messages, or the whole path if it has none.
+fn file_label(path: &Path) -> String {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => path.display().to_string(),
+    }
+}
+
+fn create_file(path: &Path) -> Result<File, Error> {
+    File::create(path).map_err(Error::io(path))
+}
+
+/// Where a final output is written until it is complete: `path` with the process id and
+/// `.tmp` appended, in the same directory so renaming it into place stays on one filesystem.
+/// Runs sharing an output directory each write their own partial file, so neither can rename
+/// the other's half-written one into place.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}.tmp", std::process::id()));
+    PathBuf::from(name)
+}
+
+/// Produce `path` atomically: `write` fills in its partial path, which is renamed over `path`
+/// only once it succeeded, so an existing output is always a complete one. On failure the
+/// partial file is removed.
+fn write_atomic<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
+    let partial = partial_path(path);
+    let result = write(&partial)
+        .and_then(|()| std::fs::rename(&partial, path).map_err(Error::io(path)));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+/// The count QC and the downsampling target use: chromosome and length filtered with
+/// `--filter-qc`.
+fn qc_count(count: &FragmentCount, filter_qc: bool) -> usize {
+    if filter_qc {
+        count.kept
+    } else {
+        count.total()
+    }
+}
+
+fn log_filtered(file: &Path, chroms: &ChromFilter, lengths: &LengthFilter, count: &FragmentCount) {
+    if chroms.is_active() {
+        info!(
+            "{}: {} of {} fragments removed by the chromosome filter",
+            file.display(),
+            count.chrom_filtered,
+            count.total()
+        );
+    }
+    if lengths.is_active() {
+        info!(
+            "{}: {} of {} fragments removed by the length filter",
+            file.display(),
+            count.length_filtered,
+            count.total()
+        );
+    }
+}
+
+/// What a processed BED sample hands back to the run.
+struct BedOutcome {
+    scale: f64,
+    draw: SampleDraw,
+    /// With `--matrix`, each track's values in row order, keyed by size class
+    columns: Vec<(Option<String>, Vec<f32>)>,
+}
+
+/// Downsample one BED sample and write its tracks, returning the factor its bins were scaled by.
+fn process_bed_sample(
+    ctx: &BedContext,
+    planned: &PlannedSample,
+    pb: &ProgressBar,
+) -> Result<BedOutcome, Error> {
+    let file_path = planned.file.as_path();
+    let filename = file_label(file_path);
+    let mut clock = Instant::now();
+
+    let seed = file_seed(ctx.seed, file_path);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let members = &ctx.members[file_path];
+    let min_count = ctx.downsample.then_some(ctx.target);
+    let filter = BedLineFilter {
+        no_header: ctx.no_header,
+        chroms: ctx.chroms,
+        lengths: &ctx.lengths,
+        dedup: ctx.dedup,
+        chrom_lengths: ctx.chrom_lengths,
+        on_bad_coord: ctx.on_bad_coord,
+        weighted: ctx.weighted,
+        bedpe: ctx.bedpe,
+    };
+    // With --low-memory the reservoir holds 16-byte offsets instead of lines, and the
+    // picked lines are read back into one buffer rather than one allocation each
+    let packed: String;
+    let (header, mut sample): (Option<String>, Vec<Cow<str>>) = if ctx.low_memory {
+        let (header, slots) =
+            reservoir_sample(members, min_count, &filter, &mut rng, |n, o, _| (n, o))?;
+        let ranges;
+        (packed, ranges) = read_sampled_lines(members, &slots)?;
+        // The picked BEDPE pairs are read back as pairs and turned into fragments again
+        let lines = ranges.into_iter().filter_map(|r| match filter.read(&packed[r]) {
+            DataLine::Fragment(fragment) => Some(fragment),
+            _ => None,
+        });
+        (header, lines.collect())
+    } else {
+        reservoir_sample(members, min_count, &filter, &mut rng, |_, _, l| {
+            Cow::Owned(l.to_string())
+        })?
+    };
+    let draw = SampleDraw {
+        seed,
+        samtools_fraction: None,
+        retained: sample.len(),
+    };
+    info!(
+        "{}: kept {} of {} fragments (fraction {}, seed {})",
+        filename, draw.retained, planned.fragments, planned.fraction, seed
+    );
+    if let Some(histograms) = ctx.length_histograms {
+        let mut histogram = LengthHistogram::new();
+        for length in sample.iter().filter_map(|line| fragment_length(line)) {
+            tally_length(&mut histogram, length, histograms.width);
+        }
+        histograms.insert(file_path, histogram);
+    }
+    ctx.timings.lap(file_path, Stage::Sampling, &mut clock);
+    pb.inc(1);
+
+    // Fragments on chromosomes missing from the sizes file can't be binned; a reference
+    // build mismatch usually shows up here first
+    let order_map = ctx.chrom_order;
+    let mut unknown: BTreeMap<String, usize> = BTreeMap::new();
+    sample.retain(|line| {
+        let chrom = line.split('\t').next().unwrap_or("");
+        let known = order_map.contains_key(chrom);
+        if !known {
+            *unknown.entry(chrom.to_string()).or_default() += 1;
+        }
+        known
+    });
+    if !unknown.is_empty() {
+        let list: Vec<String> = unknown.iter().map(|(c, n)| format!("{} ({})", c, n)).collect();
+        warn!(
+            "{}: dropped fragments on chromosomes not in the chrom sizes file: {}",
+            filename,
+            list.join(", ")
+        );
+    }
+
+    // Sampling kept the fragments running past their chromosome's end that clamping leaves
+    // something of
+    if ctx.on_bad_coord == BadCoords::Clamp {
+        for line in sample.iter_mut() {
+            if let Some(Cow::Owned(clamped)) = clamp_to_chrom(line, ctx.chrom_lengths) {
+                *line = Cow::Owned(clamped);
+            }
+        }
+    }
+
+    if let Some(shift) = ctx.atac_shift {
+        let before = sample.len();
+        sample = sample
+            .iter()
+            .filter_map(|line| shift.apply(line, ctx.chrom_lengths).map(Cow::Owned))
+            .collect();
+        debug!(
+            "{}: ATAC shift dropped {} fragments at chromosome edges",
+            filename,
+            before - sample.len()
+        );
+    }
+
+    if let Some(length) = ctx.extend {
+        for line in sample.iter_mut() {
+            if let Cow::Owned(extended) = extend_read(line, length, ctx.chrom_lengths) {
+                *line = Cow::Owned(extended);
+            }
+        }
+    }
+
+    // Like bamCoverage, drop whole fragments that touch a blacklisted region
+    if let Some(blacklist) = ctx.blacklist {
+        let before = sample.len();
+        sample.retain(|line| !blacklist.overlaps(line));
+        debug!(
+            "{}: {} fragments overlap the blacklist",
+            filename,
+            before - sample.len()
+        );
+    }
+
+    sort_bed_lines(&mut sample, order_map);
+    ctx.timings.lap(file_path, Stage::Sort, &mut clock);
+    pb.inc(1);
+
+    // Size classes share the sample's factor, so their tracks stay comparable
+    let sampled = sample.len().max(1) as f64;
+    let spikein = ctx.spikein_factors.get(file_path);
+    let scale = spikein.copied().unwrap_or(1.0)
+        * match ctx.scale {
+            Scale::None => 1.0,
+            Scale::Cpm => 1e6 / sampled,
+            Scale::Target => ctx.target as f64 / sampled,
+        };
+    if ctx.scale != Scale::None || spikein.is_some() {
+        info!("{}: scaling bin counts by {}", filename, scale);
+    }
+
+    if ctx.stranded {
+        let unstranded = sample
+            .iter()
+            .filter(|line| !matches!(line.split('\t').nth(5).map(str::trim), Some("+" | "-")))
+            .count();
+        if unstranded > 0 {
+            warn!(
+                "{}: {} fragments without a + or - strand are in neither stranded track",
+                filename, unstranded
+            );
+        }
+    }
+    let tracks: Vec<(Option<String>, Vec<&str>)> = Track::all(ctx.size_classes, ctx.stranded)
+        .into_iter()
+        .map(|track| {
+            let lines: Vec<&str> = sample
+                .iter()
+                .map(|line| line.as_ref())
+                .filter(|line| track.keeps_bed_line(line))
+                .collect();
+            let name = track.name();
+            if let Some(name) = &name {
+                info!("{}: {} fragments in track {}", filename, lines.len(), name);
+            }
+            (name, lines)
+        })
+        .collect();
+    let mut columns = Vec::new();
+    for (name, lines) in &tracks {
+        let class = name.as_deref();
+        let values = write_bed_track(ctx, file_path, class, header.as_deref(), lines, scale, pb)?;
+        if let Some(values) = values {
+            columns.push((name.clone(), values));
+        }
+    }
+    Ok(BedOutcome {
+        scale,
+        draw,
+        columns,
+    })
+}
+
+/// Write a track's bin counts, times `scale`, to its bedGraph and bigWig `outputs`.
+/// `bedgraph` is where a temporary bedGraph goes when bedGraphToBigWig needs one.
+fn write_bin_outputs(
+    ctx: &BedContext,
+    filename: &str,
+    counts: &HashMap<String, Vec<f64>>,
+    scale: f64,
+    outputs: &[PathBuf],
+    bedgraph: &Path,
+) -> Result<(), Error> {
+    let output = |ext: &str| outputs.iter().find(|p| p.extension().is_some_and(|e| e == ext));
+    let write_bedgraph = |path: &Path| -> Result<(), Error> {
+        let write_err = Error::io(path);
+        let mut writer = BufWriter::new(create_file(path)?);
+        for (chrom, start, end, count) in bin_records(counts, ctx.chrom_list, ctx.step) {
+            let value = scaled(count, scale);
+            writeln!(writer, "{}\t{}\t{}\t{}", chrom, start, end, value).map_err(&write_err)?;
+        }
+        writer.flush().map_err(&write_err)
+    };
+    // Bins come out grouped by chromosome with increasing starts, which is all bedGraphToBigWig
+    // needs, so no external sort is required. It converts the final bedGraph when one is
+    // written, a temporary one otherwise.
+    let final_bedgraph = output("bedGraph");
+    if let Some(path) = final_bedgraph {
+        write_atomic(path, write_bedgraph)?;
+        info!("{}: wrote {}", filename, path.display());
+    }
+    if let Some(bigwig) = output("bw") {
+        if ctx.native_bigwig {
+            write_atomic(bigwig, |partial| {
+                write_native_bigwig(counts, ctx.chrom_list, ctx.step, scale, partial)
+                    .map_err(Error::io(bigwig))
+            })?;
+        } else {
+            let source = match final_bedgraph {
+                Some(path) => path,
+                None => {
+                    write_bedgraph(bedgraph)?;
+                    bedgraph
+                }
+            };
+            write_atomic(bigwig, |partial| {
+                ctx.tools.run_step(
+                    Command::new(&ctx.tools.bedgraph_to_bigwig)
+                        .arg(source)
+                        .arg(ctx.chrom_sizes)
+                        .arg(partial),
+                    "bedGraphToBigWig",
+                )
+            })?;
+        }
+        info!("{}: wrote {}", filename, bigwig.display());
+    }
+    Ok(())
+}
+
+/// Prefix of a track's quantile-normalized outputs, e.g. `mono_qnorm`.
+fn qnorm_track_name(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{}_qnorm", name),
+        None => "qnorm".to_string(),
+    }
+}
+
+/// Quantile-normalize `columns` in place so they all share one distribution: the mean of
+/// their sorted values. Each value becomes the reference value at its rank, and tied values
+/// the mean of the reference values their ranks span.
+fn quantile_normalize(columns: &mut [Vec<f32>]) {
+    let Some(rows) = columns.first().map(Vec::len) else {
+        return;
+    };
+    let orders: Vec<Vec<usize>> = columns
+        .iter()
+        .map(|column| {
+            let mut order: Vec<usize> = (0..rows).collect();
+            order.sort_by(|&a, &b| column[a].total_cmp(&column[b]));
+            order
+        })
+        .collect();
+    let mut reference = vec![0.0; rows];
+    for (column, order) in columns.iter().zip(&orders) {
+        for (rank, &row) in order.iter().enumerate() {
+            reference[rank] += column[row] as f64 / columns.len() as f64;
+        }
+    }
+    for (column, order) in columns.iter_mut().zip(&orders) {
+        let mut start = 0;
+        while start < rows {
+            let value = column[order[start]];
+            let end = (start..rows)
+                .find(|&rank| column[order[rank]] != value)
+                .unwrap_or(rows);
+            let mean = reference[start..end].iter().sum::<f64>() / (end - start) as f64;
+            for &row in &order[start..end] {
+                column[row] = mean as f32;
+            }
+            start = end;
+        }
+    }
+}
+
+/// Quantile-normalize each track across the samples and write the results next to the
+/// samples' other outputs, under the [`qnorm_track_name`] of the track. `tracks` holds each
+/// sample file and track name with the track's scaled values.
+fn write_qnorm_tracks(
+    ctx: &BedContext,
+    mut tracks: Vec<(PathBuf, Option<String>, Vec<f32>)>,
+) -> Result<(), Error> {
+    let mut names: Vec<Option<String>> = Vec::new();
+    for (_, name, _) in &tracks {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    for name in names {
+        let (track, rest): (Vec<_>, Vec<_>) =
+            tracks.into_iter().partition(|(_, track, _)| *track == name);
+        tracks = rest;
+        let (files, mut columns): (Vec<PathBuf>, Vec<Vec<f32>>) =
+            track.into_iter().map(|(file, _, values)| (file, values)).unzip();
+        quantile_normalize(&mut columns);
+        let name = qnorm_track_name(name.as_deref());
+        for (file, values) in files.iter().zip(columns) {
+            let filename = file_label(file);
+            let outputs: Vec<PathBuf> = (ctx.format.suffixes(Some(&name), !ctx.downsample))
+                .iter()
+                .map(|suffix| ctx.layout.path(file, suffix))
+                .collect();
+            let values = values.into_iter().map(f64::from);
+            if let Some(regions) = ctx.regions {
+                let counts: Vec<f64> = values.collect();
+                let tsv = &outputs[0];
+                write_atomic(tsv, |partial| {
+                    write_region_counts(regions, &counts, 1.0, partial).map_err(Error::io(tsv))
+                })?;
+                info!("{}: wrote {}", filename, tsv.display());
+                continue;
+            }
+            let mut counts: HashMap<String, Vec<f64>> = HashMap::new();
+            let chroms = bin_intervals(ctx.chrom_list, ctx.step).map(|(chrom, _, _)| chrom);
+            for (chrom, value) in chroms.zip(values) {
+                counts.entry(chrom.to_string()).or_default().push(value);
+            }
+            let bedgraph = format!("{}_{}bp.bedGraph", name, ctx.bin_size);
+            let bedgraph = ctx.layout.tmp_path(file, &bedgraph);
+            let result = write_bin_outputs(ctx, &filename, &counts, 1.0, &outputs, &bedgraph);
+            if !ctx.keep_intermediates {
+                let _ = std::fs::remove_file(&bedgraph);
+            }
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Key ordering chromosome names naturally, so `chr2` sorts before `chr10`: the name split
+/// into runs of non-digits, each paired with the number following it.
+fn natural_key(name: &str) -> Vec<(String, Option<u64>)> {
+    let mut key = Vec::new();
+    let mut rest = name;
+    while !rest.is_empty() {
+        let text_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let (text, tail) = rest.split_at(text_len);
+        let digits_len = tail.find(|c: char| !c.is_ascii_digit()).unwrap_or(tail.len());
+        let (digits, tail) = tail.split_at(digits_len);
+        let number = (!digits.is_empty()).then(|| digits.parse().unwrap_or(u64::MAX));
+        key.push((text.to_string(), number));
+        rest = tail;
+    }
+    key
+}
+
+/// Sort BED lines by chrom sizes order, then start, then end, like `bedtools sort -faidx`,
+/// and then strand, `+` before `-` before lines without one, so the order doesn't depend on
+/// the input order. Chromosomes missing from the order come after the known ones, in
+/// natural name order. The sort is stable, so fully tied lines keep their input order.
+fn sort_bed_lines<S: AsRef<str>>(lines: &mut [S], chrom_order: &HashMap<String, usize>) {
+    // Rank the unknown names once, so the per-line sort key stays four integers
+    let unknown: HashSet<&str> = lines
+        .iter()
+        .map(|line| line.as_ref().split('\t').next().unwrap_or(""))
+        .filter(|chrom| !chrom_order.contains_key(*chrom))
+        .collect();
+    let mut unknown: Vec<String> = unknown.into_iter().map(str::to_string).collect();
+    unknown.sort_by_cached_key(|name| natural_key(name));
+    let unknown_rank: HashMap<&str, usize> = unknown
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.as_str(), chrom_order.len() + i))
+        .collect();
+    lines.sort_by_cached_key(|line| {
+        let mut fields = line.as_ref().split('\t');
+        let chrom = fields.next().unwrap_or("");
+        let rank = chrom_order.get(chrom).or_else(|| unknown_rank.get(chrom)).copied();
+        let rank = rank.unwrap_or(usize::MAX);
+        let start = fields.next().and_then(|f| f.trim().parse::<u64>().ok());
+        let end = fields.next().and_then(|f| f.trim().parse::<u64>().ok());
+        let strand = match fields.nth(2).map(str::trim) {
+            Some("+") => 0u8,
+            Some("-") => 1,
+            _ => 2,
+        };
+        (rank, start.unwrap_or(0), end.unwrap_or(0), strand)
+    });
+}
+
+/// Bin one set of sorted fragments into a bigWig, named after `class` when given. With
+/// `--matrix` the written values are returned as well, in row order.
+fn write_bed_track(
+    ctx: &BedContext,
+    file_path: &Path,
+    class: Option<&str>,
+    header: Option<&str>,
+    lines: &[&str],
+    scale: f64,
+    pb: &ProgressBar,
+) -> Result<Option<Vec<f32>>, Error> {
+    let filename = file_label(file_path);
+    let mut clock = Instant::now();
+    let bin_size = ctx.bin_size;
+    let track = |suffix: String| match class {
+        Some(class) => format!("{}_{}", class, suffix),
+        None => suffix,
+    };
+
+    let layout = ctx.layout;
+    let out_bed = layout.tmp_path(file_path, &track("downsampled.bed".to_string()));
+    let bedgraph = layout.tmp_path(file_path, &track(format!("{}bp.bedGraph", bin_size)));
+    let outputs: Vec<PathBuf> = (ctx.format.suffixes(class, !ctx.downsample).iter())
+        .map(|suffix| layout.path(file_path, suffix))
+        .collect();
+
+    // Midpoint/ends points no longer follow the fragment order, so they are sorted again
+    let points: Vec<String>;
+    let track_lines: Vec<&str> = if ctx.mode == CoverageMode::Fragment {
+        lines.to_vec()
+    } else {
+        let mut converted: Vec<String> = lines
+            .iter()
+            .flat_map(|line| coverage_intervals(line, ctx.mode))
+            .collect();
+        sort_bed_lines(&mut converted, ctx.chrom_order);
+        points = converted;
+        points.iter().map(String::as_str).collect()
+    };
+
+    let result = (|| {
+        if ctx.keep_intermediates {
+            let write_err = Error::io(&out_bed);
+            let mut writer = BufWriter::new(create_file(&out_bed)?);
+            if let Some(header) = header {
+                writeln!(writer, "{}", header).map_err(&write_err)?;
+            }
+            for line in &track_lines {
+                writeln!(writer, "{}", line).map_err(&write_err)?;
+            }
+            writer.flush().map_err(&write_err)?;
+        }
+        // The fragments themselves, even when midpoints or ends are binned
+        if ctx.keep_downsampled_bed {
+            let path = layout.path(file_path, &fragments_bed_suffix(class, !ctx.downsample));
+            write_atomic(&path, |partial| {
+                let write = || -> std::io::Result<()> {
+                    let mut writer = BufWriter::new(File::create(partial)?);
+                    if let Some(header) = header {
+                        writeln!(writer, "{}", header)?;
+                    }
+                    for line in lines {
+                        writeln!(writer, "{}", line)?;
+                    }
+                    writer.flush()
+                };
+                write().map_err(Error::io(&path))
+            })?;
+            info!("{}: wrote {}", filename, path.display());
+        }
+
+        // Scores were checked while counting; a line without one can't have been sampled
+        let fragments = track_lines.iter().filter_map(|line| {
+            let (chrom, start, end) = parse_interval(line)?;
+            let weight = if ctx.weighted { fragment_score(line).unwrap_or(0.0) } else { 1.0 };
+            Some((chrom, start, end, weight))
+        });
+        if let Some(regions) = ctx.regions {
+            let counts = count_regions(fragments, regions);
+            ctx.timings.lap(file_path, Stage::Coverage, &mut clock);
+            pb.inc(1);
+            let tsv = &outputs[0];
+            write_atomic(tsv, |partial| {
+                write_region_counts(regions, &counts, scale, partial).map_err(Error::io(tsv))
+            })?;
+            ctx.timings.lap(file_path, Stage::Writing, &mut clock);
+            pb.inc(1);
+            info!("{}: wrote {}", filename, tsv.display());
+            let values = counts.iter().map(|&count| scaled(count, scale));
+            return Ok(ctx.matrix.then(|| values.collect()));
+        }
+        let mut counts = compute_bin_counts(fragments, ctx.chrom_list, bin_size, ctx.step);
+        if let Some(length) = ctx.smooth_length {
+            smooth_bin_counts(&mut counts, length / ctx.step / 2);
+        }
+        // The transform is of the scaled counts, which then need no further scaling
+        let scale = match ctx.log2 {
+            Some(pseudocount) => {
+                for count in counts.values_mut().flatten() {
+                    *count = (*count * scale + pseudocount).log2();
+                }
+                1.0
+            }
+            None => scale,
+        };
+        ctx.timings.lap(file_path, Stage::Coverage, &mut clock);
+        pb.inc(1);
+
+        write_bin_outputs(ctx, &filename, &counts, scale, &outputs, &bedgraph)?;
+        ctx.timings.lap(file_path, Stage::Writing, &mut clock);
+        pb.inc(1);
+        let values = bin_records(&counts, ctx.chrom_list, ctx.step);
+        Ok(ctx.matrix.then(|| values.map(|(_, _, _, count)| scaled(count, scale)).collect()))
+    })();
+
+    if !ctx.keep_intermediates {
+        let _ = std::fs::remove_file(&bedgraph);
+    }
+    result
+}
+
+/// `(chrom, start, end)` of a BED line, or `None` for header, track or malformed lines.
+fn parse_interval(line: &str) -> Option<(&str, u64, u64)> {
+    let mut fields = line.split('\t');
+    let chrom = fields.next()?;
+    let start = fields.next()?.trim().parse().ok()?;
+    let end = fields.next()?.trim().parse().ok()?;
+    Some((chrom, start, end))
+}
+
+/// `end - start` of a BED line, `None` when either doesn't parse.
+fn fragment_length(line: &str) -> Option<u64> {
+    let mut fields = line.split('\t').skip(1);
+    let start: u64 = fields.next()?.trim().parse().ok()?;
+    let end: u64 = fields.next()?.trim().parse().ok()?;
+    Some(end.saturating_sub(start))
+}
+
+/// The score (fifth) column of a BED line, `None` when missing or not a finite number.
+fn fragment_score(line: &str) -> Option<f64> {
+    let score: f64 = line.split('\t').nth(4)?.trim().parse().ok()?;
+    score.is_finite().then_some(score)
+}
+
+/// Count, for every `bin_size` bin of every chromosome, the fragments overlapping it (by at
+/// least 1 bp, as `bedtools coverage -counts` does). Bins start every `step` bp; a step
+/// below the bin size makes them overlap, each centred on its own `step`-wide interval.
+/// Each fragment adds +1/-1 at its first and one-past-last bin and a prefix sum turns that
+/// into counts, so the cost is linear in fragments plus bins. Fragments on unknown
+/// chromosomes or of zero length are ignored, and ends past the chromosome are clipped.
+/// Each fragment adds its weight, 1 unless `--weighted`; bins no fragment overlaps are
+/// exactly 0 despite the rounding of the weighted sums.
+fn compute_bin_counts<S: AsRef<str>>(
+    fragments: impl IntoIterator<Item = (S, u64, u64, f64)>,
+    chrom_sizes: &[(String, u32)],
+    bin_size: usize,
+    step: usize,
+) -> HashMap<String, Vec<f64>> {
+    let (bin_size, step) = (bin_size as i64, step as i64);
+    // Bin i spans [i * step - pad, i * step - pad + bin_size)
+    let pad = (bin_size - step) / 2;
+    let mut deltas: HashMap<String, Vec<(i64, f64)>> = chrom_sizes
+        .iter()
+        .map(|(chrom, size)| {
+            let bins = (*size as u64).div_ceil(step as u64) as usize;
+            (chrom.clone(), vec![(0, 0.0); bins + 1])
+        })
+        .collect();
+    for (chrom, start, end, weight) in fragments {
+        let Some(delta) = deltas.get_mut(chrom.as_ref()) else {
+            continue;
+        };
+        let bins = delta.len() as i64 - 1;
+        let (start, end) = (start as i64, end as i64);
+        let first = ((start + pad - bin_size).div_euclid(step) + 1).max(0);
+        if end <= start || first >= bins {
+            continue;
+        }
+        let last = (end - 1 + pad).div_euclid(step).min(bins - 1);
+        let (first, next) = (first as usize, last as usize + 1);
+        delta[first] = (delta[first].0 + 1, delta[first].1 + weight);
+        delta[next] = (delta[next].0 - 1, delta[next].1 - weight);
+    }
+    deltas
+        .into_iter()
+        .map(|(chrom, delta)| {
+            let (mut fragments, mut sum) = (0i64, 0.0);
+            let counts = delta[..delta.len() - 1]
+                .iter()
+                .map(|&(n, weight)| {
+                    fragments += n;
+                    sum += weight;
+                    if fragments == 0 {
+                        sum = 0.0;
+                    }
+                    sum
+                })
+                .collect();
+            (chrom, counts)
+        })
+        .collect()
+}
+
+/// Replace each bin count by the mean of the bins up to `half` bins either side of it on the
+/// same chromosome, like bamCoverage's `--smoothLength`. Windows at the chromosome ends are
+/// cut short and averaged over the bins they hold.
+fn smooth_bin_counts(counts: &mut HashMap<String, Vec<f64>>, half: usize) {
+    if half == 0 {
+        return;
+    }
+    for bins in counts.values_mut() {
+        let raw = bins.clone();
+        for (i, bin) in bins.iter_mut().enumerate() {
+            let window = &raw[i.saturating_sub(half)..(i + half + 1).min(raw.len())];
+            *bin = window.iter().sum::<f64>() / window.len() as f64;
+        }
+    }
+}
+
+/// `(chrom, start, end)` for every bin, in chrom sizes order, as `step`-wide intervals that
+/// don't overlap even when the bins do; the last interval of a chromosome ends at the
+/// chromosome end.
+fn bin_intervals(
+    chrom_sizes: &[(String, u32)],
+    step: usize,
+) -> impl Iterator<Item = (&str, u32, u32)> + '_ {
+    let step = step as u32;
+    chrom_sizes.iter().flat_map(move |(chrom, size)| {
+        (0..size.div_ceil(step)).map(move |i| {
+            let start = i * step;
+            (chrom.as_str(), start, start.saturating_add(step).min(*size))
+        })
+    })
+}
+
+/// Write the `bin_size` bins of every chromosome in `chrom_sizes` as a three-column BED in
+/// file order, the last bin of each chromosome ending at its end; these are the bins BED
+/// mode counts into. Without an `output` path the BED goes to stdout.
+pub fn write_bins(chrom_sizes: &Path, bin_size: usize, output: Option<&Path>) -> Result<(), Error> {
+    if bin_size == 0 {
+        return Err(Error::Config("--bin-size must be greater than zero".into()));
+    }
+    let chrom_list = parse_chrom_sizes(chrom_sizes)?;
+    let write = |writer: &mut dyn Write| -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        for (chrom, start, end) in bin_intervals(&chrom_list, bin_size) {
+            writeln!(writer, "{}\t{}\t{}", chrom, start, end)?;
+        }
+        writer.flush()
+    };
+    match output {
+        Some(path) => write_atomic(path, |partial| {
+            write(&mut create_file(partial)?).map_err(Error::io(path))
+        }),
+        None => write(&mut std::io::stdout().lock()).map_err(Error::io(Path::new("<stdout>"))),
+    }
+}
+
+/// `(chrom, start, end, count)` for every bin, as laid out by [`bin_intervals`].
+fn bin_records<'a>(
+    counts: &'a HashMap<String, Vec<f64>>,
+    chrom_sizes: &'a [(String, u32)],
+    step: usize,
+) -> impl Iterator<Item = (&'a str, u32, u32, f64)> + 'a {
+    let counts = chrom_sizes.iter().flat_map(|(chrom, _)| counts[chrom].iter().copied());
+    bin_intervals(chrom_sizes, step)
+        .zip(counts)
+        .map(|((chrom, start, end), count)| (chrom, start, end, count))
+}
+
+/// A bin count times the sample's scale factor, at the single precision bigWigs store.
+/// Unscaled counts print as the same integers.
+fn scaled(count: f64, scale: f64) -> f32 {
+    (count * scale) as f32
+}
+
+/// Write one line of `chrom start end name count` per region, under a header line.
+fn write_region_counts(
+    regions: &[Region],
+    counts: &[f64],
+    scale: f64,
+    path: &Path,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "chrom\tstart\tend\tname\tcount")?;
+    for (region, &count) in regions.iter().zip(counts) {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            region.chrom,
+            region.start,
+            region.end,
+            region.name,
+            scaled(count, scale)
+        )?;
+    }
+    writer.flush()
+}
+
+/// Write the tracks of every sample side by side, one row per bin or region and one column
+/// per track, under a header of coordinates and column names.
+fn write_matrix(
+    path: &Path,
+    regions: Option<&[Region]>,
+    chrom_list: &[(String, u32)],
+    step: usize,
+    columns: &[(String, Vec<f32>)],
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let names: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+    let coords = if regions.is_some() { "chrom\tstart\tend\tname" } else { "chrom\tstart\tend" };
+    writeln!(writer, "{}\t{}", coords, names.join("\t"))?;
+    let write_values = |writer: &mut BufWriter<File>, row: usize| -> std::io::Result<()> {
+        for (_, values) in columns {
+            write!(writer, "\t{}", values[row])?;
+        }
+        writeln!(writer)
+    };
+    match regions {
+        Some(regions) => {
+            for (row, region) in regions.iter().enumerate() {
+                let (chrom, start, end) = (&region.chrom, region.start, region.end);
+                write!(writer, "{}\t{}\t{}\t{}", chrom, start, end, region.name)?;
+                write_values(&mut writer, row)?;
+            }
+        }
+        None => {
+            for (row, (chrom, start, end)) in bin_intervals(chrom_list, step).enumerate() {
+                write!(writer, "{}\t{}\t{}", chrom, start, end)?;
+                write_values(&mut writer, row)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Write the pairwise correlations of the tracks as a symmetric matrix, with the track
+/// names along the top and down the first column.
+fn write_correlation(
+    path: &Path,
+    columns: &[(String, Vec<f32>)],
+    method: CorrelationMethod,
+) -> std::io::Result<()> {
+    let vectors: Vec<Vec<f64>> = columns
+        .iter()
+        .map(|(_, values)| {
+            let values: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+            match method {
+                CorrelationMethod::Pearson => values,
+                CorrelationMethod::Spearman => ranks(&values),
+            }
+        })
+        .collect();
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (name, _) in columns {
+        write!(writer, "\t{}", name)?;
+    }
+    writeln!(writer)?;
+    for (i, (name, _)) in columns.iter().enumerate() {
+        write!(writer, "{}", name)?;
+        for j in 0..columns.len() {
+            write!(writer, "\t{:.4}", pearson(&vectors[i], &vectors[j]))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Write bin counts straight to a bigWig, skipping the bedGraph intermediate and
+/// bedGraphToBigWig.
+fn write_native_bigwig(
+    counts: &HashMap<String, Vec<f64>>,
+    chroms: &[(String, u32)],
+    step: usize,
+    scale: f64,
+    bigwig: &Path,
+) -> std::io::Result<()> {
+    let mut writer = BigWigWriter::create(bigwig, chroms, (step * 10) as u32)?;
+    for (chrom, start, end, count) in bin_records(counts, chroms, step) {
+        writer.add(chrom, start, end, scaled(count, scale))?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Write about `target` of the reads in `input` that pass `filter` to `output`, drawn as whole
+/// templates by reservoir sampling seeded with `seed`. Unlike `samtools view -s`, which keeps
+/// each template with a probability, this keeps exactly `target / 2` pairs (or `target`
+/// single-end reads), at the cost of holding their names in memory. The first pass offers
+/// each template once, through its leftmost mate (the one with a positive TLEN, or read 1
+/// when TLEN is 0); the second writes both mates of each picked template in input order, so
+/// the BAM stays sorted. Whether the data is paired is taken from the first passing read.
+#[cfg(feature = "htslib")]
+fn exact_downsample_bam(
+    tools: &Tools,
+    input: &Path,
+    output: &Path,
+    filter: &BamFilter,
+    target: usize,
+    seed: u64,
+) -> Result<(), Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let htslib_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| Error::Htslib { path, source }
+    };
+    let open = || -> Result<bam::Reader, Error> {
+        let mut reader = bam::Reader::from_path(input).map_err(htslib_err(input))?;
+        if let Some(reference) = &tools.reference {
+            reader.set_reference(reference).map_err(htslib_err(input))?;
+        }
+        Ok(reader)
+    };
+    let mut reader = open()?;
+    let header = reader.header().clone();
+    // Whether reads on each reference, or unmapped ones, pass the chromosome filter
+    let chrom_kept: Vec<bool> = (0..header.target_count())
+        .map(|tid| filter.chroms.keeps(&String::from_utf8_lossy(header.tid2name(tid))))
+        .collect();
+    let unplaced_kept = filter.chroms.keeps("");
+    let keeps = |record: &bam::Record| {
+        let on_chrom = match usize::try_from(record.tid()) {
+            Ok(tid) => chrom_kept[tid],
+            Err(_) => unplaced_kept,
+        };
+        on_chrom
+            && filter.keeps(record.flags(), record.mapq())
+            && filter.lengths.keeps(record.insert_size().unsigned_abs())
+    };
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut templates = None;
+    let mut sample: Vec<Vec<u8>> = Vec::new();
+    let mut seen = 0usize;
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.map_err(htslib_err(input))?;
+        if !keeps(&record) {
+            continue;
+        }
+        let templates = *templates.get_or_insert(if record.is_paired() {
+            target / 2
+        } else {
+            target
+        });
+        let leftmost = !record.is_paired()
+            || record.insert_size() > 0
+            || (record.insert_size() == 0 && record.is_first_in_template());
+        if !leftmost {
+            continue;
+        }
+        if seen < templates {
+            sample.push(record.qname().to_vec());
+        } else {
+            let j = rng.gen_range(0..=seen);
+            if j < templates {
+                sample[j] = record.qname().to_vec();
+            }
+        }
+        seen += 1;
+    }
+    let picked: HashSet<Vec<u8>> = sample.into_iter().collect();
+
+    let header = bam::Header::from_template(&header);
+    let mut writer =
+        bam::Writer::from_path(output, &header, bam::Format::Bam).map_err(htslib_err(output))?;
+    let mut reader = open()?;
+    while let Some(result) = reader.read(&mut record) {
+        result.map_err(htslib_err(input))?;
+        if keeps(&record) && picked.contains(record.qname()) {
+            writer.write(&record).map_err(htslib_err(output))?;
+        }
+    }
+    Ok(())
+}
+
+/// `--exact` reads BAM records itself, which needs rust-htslib.
+#[cfg(not(feature = "htslib"))]
+fn exact_downsample_bam(
+    _tools: &Tools,
+    _input: &Path,
+    _output: &Path,
+    _filter: &BamFilter,
+    _target: usize,
+    _seed: u64,
+) -> Result<(), Error> {
+    Err(needs_htslib("--exact"))
+}
+
+/// Fragment-length histogram of the reads in a downsampled BAM, from TLEN. Only the leftmost
+/// mate of a pair has a positive TLEN, so each pair counts once; single-end reads, whose
+/// TLEN is 0, aren't counted.
+#[cfg(feature = "htslib")]
+fn bam_length_histogram(tools: &Tools, path: &Path, width: u64) -> Result<LengthHistogram, Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let htslib_err = |source| Error::Htslib {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut reader = bam::Reader::from_path(path).map_err(htslib_err)?;
+    if let Some(reference) = &tools.reference {
+        reader.set_reference(reference).map_err(htslib_err)?;
+    }
+    let mut histogram = LengthHistogram::new();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.map_err(htslib_err)?;
+        if let Ok(length @ 1..) = u64::try_from(record.insert_size()) {
+            tally_length(&mut histogram, length, width);
+        }
+    }
+    Ok(histogram)
+}
+
+/// BAM fragment lengths are read with rust-htslib.
+#[cfg(not(feature = "htslib"))]
+fn bam_length_histogram(
+    _tools: &Tools,
+    _path: &Path,
+    _width: u64,
+) -> Result<LengthHistogram, Error> {
+    Err(needs_htslib("--length-histogram"))
+}
+
+/// Why `option` is refused by a build without the `htslib` feature.
+fn needs_htslib(option: &str) -> Error {
+    Error::Config(format!(
+        "{} needs bedfragment_ds built with the htslib feature (cargo build --features htslib)",
+        option
+    ))
+}
+
+/// Downsample one BAM sample and run bamCoverage on it, returning how it was downsampled.
+fn process_bam_sample(
+    ctx: &BamContext,
+    planned: &PlannedSample,
+    pb: &ProgressBar,
+) -> Result<SampleDraw, Error> {
+    let tools = ctx.tools;
+
+    let file_path = planned.file.as_path();
+    let filename = file_label(file_path);
+
+    let bam_seed = samtools_seed(file_seed(ctx.seed, file_path));
+    let subsample = samtools_subsample_arg(bam_seed, planned.fraction);
+    // With --exact the reads are drawn here rather than by samtools
+    let exact_target = (ctx.exact && subsample.is_some()).then_some(ctx.target);
+    let subsample = subsample.filter(|_| exact_target.is_none());
+    // The fraction samtools gets is rounded, so the retained count is reported from it
+    let samtools_fraction = subsample.as_ref().map(|arg| {
+        let (_, digits) = arg.split_once('.').unwrap_or_default();
+        format!("0.{}", digits).parse::<f64>().unwrap_or_default()
+    });
+    let draw = SampleDraw {
+        seed: bam_seed,
+        samtools_fraction,
+        retained: exact_target.unwrap_or_else(|| {
+            (planned.fragments as f64 * samtools_fraction.unwrap_or(1.0)).round() as usize
+        }),
+    };
+
+    match &subsample {
+        _ if exact_target.is_some() => info!(
+            "{}: downsampling to exactly {} of {} reads (seed {})",
+            filename, draw.retained, planned.fragments, bam_seed
+        ),
+        Some(arg) => info!(
+            "{}: downsampling with samtools -s {} (fraction {}, seed {}), keeping about {} of \
+             {} reads",
+            filename,
+            arg,
+            planned.fraction,
+            bam_seed,
+            draw.retained,
+            planned.fragments
+        ),
+        None => info!("{}: at the target depth, keeping all reads", filename),
+    }
+    let tmp_bam = ctx.layout.tmp_path(file_path, "downsampled.bam");
+    ctx.layout.cleanup.register(tmp_bam.with_extension("bam.bai"));
+    ctx.layout.cleanup.register(tmp_bam.with_extension("bai"));
+    let members = &ctx.members[file_path];
+    let merged_bam = ctx.layout.tmp_path(file_path, "merged.bam");
+
+    let mut clock = Instant::now();
+    let result = (|| {
+        // Pooled samples are merged first so the subsample is drawn from the union
+        let input = if members.len() > 1 {
+            debug!("{}: merging {} files", filename, members.len());
+            tools.run_step(
+                tools
+                    .samtools_reading("merge")
+                    .arg("-f")
+                    .args(ctx.samtools_threads())
+                    .arg(&merged_bam)
+                    .args(members),
+                "samtools merge",
+            )?;
+            ctx.timings.lap(file_path, Stage::Merge, &mut clock);
+            pb.inc(1);
+            merged_bam.as_path()
+        } else {
+            file_path
+        };
+
+        // Write downsampled BAM to disk
+        if let Some(target) = exact_target {
+            exact_downsample_bam(tools, input, &tmp_bam, ctx.filter, target, bam_seed)?;
+        } else {
+            let mut view_cmd = tools.samtools_reading("view");
+            view_cmd.arg("-b").args(ctx.samtools_threads());
+            if let Some(arg) = &subsample {
+                view_cmd.args(["-s", arg]);
+            }
+            // Written with -o rather than to stdout, so a retry starts on an empty file
+            view_cmd
+                .args(ctx.filter.samtools_args())
+                .arg("-o")
+                .arg(&tmp_bam)
+                .arg(input);
+            tools.run_step(&mut view_cmd, "samtools downsampling")?;
+        }
+        ctx.timings.lap(file_path, Stage::Downsampling, &mut clock);
+        pb.inc(1);
+
+        // Index the downsampled BAM file
+        tools.run_step(
+            Command::new(&tools.samtools)
+                .arg("index")
+                .args(ctx.samtools_threads())
+                .arg(&tmp_bam),
+            "samtools index",
+        )?;
+        ctx.timings.lap(file_path, Stage::Index, &mut clock);
+        pb.inc(1);
+
+        if let Some(histograms) = ctx.length_histograms {
+            histograms.insert(file_path, bam_length_histogram(tools, &tmp_bam, histograms.width)?);
+        }
+
+        for track in Track::all(ctx.size_classes, ctx.stranded) {
+            // Count each size class once, before its first strand
+            if let (Some(class), None | Some(Strand::Forward)) = (track.class, track.strand) {
+                // The downsampled BAM is already flag/MAPQ/chromosome filtered; only split by
+                // length
+                let class_filter = BamFilter {
+                    include_flags: 0,
+                    exclude_flags: 0,
+                    min_mapq: 0,
+                    lengths: class.lengths,
+                    chroms: ChromFilter::default(),
+                };
+                let regions = QcRegions::default();
+                let count = count_bam_fragments(tools, &tmp_bam, &class_filter, &regions, None)?;
+                info!("{}: {} reads in size class {}", filename, count.kept, class.name);
+                ctx.timings.lap(file_path, Stage::Counting, &mut clock);
+            }
+            run_bam_coverage(ctx, file_path, &tmp_bam, &track, pb, &mut clock)?;
+        }
+        Ok(())
+    })();
+
+    let _ = std::fs::remove_file(&merged_bam);
+    if !ctx.keep_intermediates {
+        let _ = std::fs::remove_file(&tmp_bam);
+        let bai_path = tmp_bam.with_extension("bam.bai");
+        let _ = std::fs::remove_file(&bai_path);
+        let bai_path2 = tmp_bam.with_extension("bai");
+        let _ = std::fs::remove_file(&bai_path2);
+    }
+    result.map(|()| draw)
+}
+
+/// Run bamCoverage on a downsampled BAM for one track, restricted to its size class's
+/// fragment lengths and its strand when it has them.
+fn run_bam_coverage(
+    ctx: &BamContext,
+    file_path: &Path,
+    bam: &Path,
+    track: &Track,
+    pb: &ProgressBar,
+    clock: &mut Instant,
+) -> Result<(), Error> {
+    let filename = file_label(file_path);
+    let name = track.name();
+    let format = TrackFormat::Bins {
+        bin_size: ctx.bin_size,
+        step: None,
+        output: ctx.output_format,
+        log2: false,
+    };
+
+    let suffixes = format.suffixes(name.as_deref(), !ctx.downsample);
+    if ctx.split_by_chrom {
+        return run_split_bam_coverage(ctx, file_path, bam, track, &suffixes, pb, clock);
+    }
+    let bin_size_arg = ctx.bin_size.to_string();
+    // bamCoverage writes one format per run, so both formats take two runs
+    let bamcov_cmd = |file_format: &str| {
+        let mut bamcov_cmd = Command::new(&ctx.tools.bam_coverage);
+        bamcov_cmd.args(["--outFileFormat", file_format]);
+        bamcov_args(ctx, &mut bamcov_cmd, file_path, bam, &bin_size_arg, track, ctx.threads);
+        bamcov_cmd
+    };
+    for suffix in suffixes {
+        let bamcov_out = ctx.layout.path(file_path, &suffix);
+        let file_format = match suffix.ends_with(".bedGraph") {
+            true => "bedgraph",
+            false => "bigwig",
+        };
+        write_atomic(&bamcov_out, |partial| {
+            ctx.tools.run_step(bamcov_cmd(file_format).arg("-o").arg(partial), "bamCoverage")
+        })?;
+        info!("{}: wrote {}", filename, bamcov_out.display());
+        ctx.timings.lap(file_path, Stage::Coverage, clock);
+        pb.inc(1);
+    }
+    Ok(())
+}
+
+/// `--split-by-chrom`: run single-threaded bamCoverage on each chromosome of the BAM header
+/// in parallel, each writing a bedGraph part, then join the parts in header order into the
+/// track's outputs. BigWigs are written natively from the parts, so the result doesn't
+/// depend on which part finished first.
+fn run_split_bam_coverage(
+    ctx: &BamContext,
+    file_path: &Path,
+    bam: &Path,
+    track: &Track,
+    suffixes: &[String],
+    pb: &ProgressBar,
+    clock: &mut Instant,
+) -> Result<(), Error> {
+    let filename = file_label(file_path);
+    let chroms: Vec<(String, u32)> = read_bam_chroms(ctx.tools, bam)?
+        .into_iter()
+        .filter(|(chrom, len)| *len > 0 && ctx.filter.chroms.keeps(chrom))
+        .collect();
+    let bin_size_arg = ctx.bin_size.to_string();
+    let class_prefix = track.name().map_or(String::new(), |name| format!("{}.", name));
+    let parts: Vec<PathBuf> = (0..chroms.len())
+        .map(|i| ctx.layout.tmp_path(file_path, &format!("{}part{}.bedGraph", class_prefix, i)))
+        .collect();
+    debug!("{}: running bamCoverage on {} chromosomes", filename, chroms.len());
+
+    let result = (|| {
+        chroms.par_iter().zip(&parts).try_for_each(|((chrom, _), part)| {
+            let mut bamcov_cmd = Command::new(&ctx.tools.bam_coverage);
+            bamcov_cmd.args(["--outFileFormat", "bedgraph", "--region", chrom]);
+            bamcov_args(ctx, &mut bamcov_cmd, file_path, bam, &bin_size_arg, track, 1);
+            ctx.tools.run_step(bamcov_cmd.arg("-o").arg(part), "bamCoverage")
+        })?;
+        ctx.timings.lap(file_path, Stage::Coverage, clock);
+        pb.inc(1);
+        for suffix in suffixes {
+            let out = ctx.layout.path(file_path, suffix);
+            write_atomic(&out, |partial| {
+                if suffix.ends_with(".bedGraph") {
+                    concat_files(&parts, partial)
+                } else {
+                    bigwig_from_bedgraphs(&parts, &chroms, ctx.bin_size, partial)
+                }
+            })?;
+            info!("{}: wrote {}", filename, out.display());
+            ctx.timings.lap(file_path, Stage::Writing, clock);
+            pb.inc(1);
+        }
+        Ok(())
+    })();
+
+    if !ctx.keep_intermediates {
+        for part in &parts {
+            let _ = std::fs::remove_file(part);
+        }
+    }
+    result
+}
+
+/// Write `parts` one after the other into `out`.
+fn concat_files(parts: &[PathBuf], out: &Path) -> Result<(), Error> {
+    let mut writer = BufWriter::new(create_file(out)?);
+    for part in parts {
+        let mut reader = File::open(part).map_err(Error::io(part))?;
+        std::io::copy(&mut reader, &mut writer).map_err(Error::io(out))?;
+    }
+    writer.flush().map_err(Error::io(out))
+}
+
+/// Write the bedGraph `parts`, each sorted and in `chroms` order, as one bigWig.
+fn bigwig_from_bedgraphs(
+    parts: &[PathBuf],
+    chroms: &[(String, u32)],
+    bin_size: usize,
+    bigwig: &Path,
+) -> Result<(), Error> {
+    let mut writer =
+        BigWigWriter::create(bigwig, chroms, (bin_size * 10) as u32).map_err(Error::io(bigwig))?;
+    for part in parts {
+        let file = File::open(part).map_err(Error::io(part))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(Error::io(part))?;
+            let value = line.split('\t').nth(3).and_then(|v| v.trim().parse::<f32>().ok());
+            let (Some((chrom, start, end)), Some(value)) = (parse_interval(&line), value) else {
+                return Err(Error::Parse(format!(
+                    "{}: unexpected bamCoverage bedGraph line '{}'",
+                    part.display(),
+                    line
+                )));
+            };
+            writer.add(chrom, start as u32, end as u32, value).map_err(Error::io(bigwig))?;
+        }
+    }
+    writer.finish().map_err(Error::io(bigwig))
+}
+
+/// Add the bamCoverage options shared by every output format of a track.
+fn bamcov_args(
+    ctx: &BamContext,
+    bamcov_cmd: &mut Command,
+    sample: &Path,
+    bam: &Path,
+    bin_size_arg: &str,
+    track: &Track,
+    threads: usize,
+) {
+    bamcov_cmd
+        .arg("-p")
+        .arg(threads.to_string())
+        .arg("-b")
+        .arg(bam)
+        .args(["--binSize", bin_size_arg, "--normalizeUsing", ctx.normalize.as_arg()]);
+    if let Some(size) = ctx.effective_genome_size {
+        bamcov_cmd.arg("--effectiveGenomeSize").arg(size.to_string());
+    }
+    if let Some(length) = ctx.smooth_length {
+        bamcov_cmd.arg("--smoothLength").arg(length.to_string());
+    }
+    if let Some(length) = ctx.extend {
+        bamcov_cmd.arg("--extendReads").arg(length.to_string());
+    }
+    if let Some(factor) = ctx.spikein_factors.get(sample) {
+        bamcov_cmd.arg("--scaleFactor").arg(factor.to_string());
+    }
+    if let Some(blacklist_path) = ctx.blacklist {
+        bamcov_cmd.arg("--blackListFileName").arg(blacklist_path);
+    }
+    if ctx.mode == CoverageMode::Ends {
+        // Count only the 5'-most base of each read, i.e. the cut site of each mate
+        bamcov_cmd.args(["--Offset", "1"]);
+    }
+    if let Some(class) = track.class {
+        let min = class.lengths.min.unwrap_or(0).to_string();
+        let max = class.lengths.max.unwrap_or(0).to_string();
+        bamcov_cmd.args(["--minFragmentLength", &min, "--maxFragmentLength", &max]);
+    }
+    if let Some(strand) = track.strand {
+        bamcov_cmd.args(["--filterRNAstrand", strand.as_arg()]);
+    }
+}
+
+/// Process every planned sample in parallel, each with its own progress bar of `stages`
+/// steps, and collect the outcomes. With `fail_fast`, samples not yet started after a
+/// failure are not run. A failed sample's bar stays at the stage it stopped in. When the
+/// bars are hidden, each finished sample is logged instead.
+fn run_samples<T, S, F>(
+    samples: &[PlannedSample],
+    m: &MultiProgress,
+    events: &EventStream,
+    fail_fast: bool,
+    stages: S,
+    work: F,
+) -> Vec<(PathBuf, Result<T, Error>)>
+where
+    T: Send,
+    S: Fn(&PlannedSample) -> u64 + Sync,
+    F: Fn(&PlannedSample, &ProgressBar) -> Result<T, Error> + Sync,
+{
+    let aborted = AtomicBool::new(false);
+    let finished = AtomicUsize::new(0);
+    samples
+        .par_iter()
+        .map(|planned| {
+            let file_path = &planned.file;
+            if aborted.load(Ordering::Relaxed) {
+                return (file_path.clone(), Err(Error::Aborted));
+            }
+            let pb = m.add(ProgressBar::new(stages(planned)));
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{msg} {bar:40.cyan/blue} {pos}/{len} ({eta})")
+                    .expect("Progress bar template error")
+                    .progress_chars("#>-"),
+            );
+            let filename = file_label(file_path);
+            pb.set_message(format!("Processing {}", filename));
+
+            let sample = planned.sample.clone();
+            let file = file_path.clone();
+            events.send(Event::SampleStarted {
+                sample: sample.clone(),
+                file: file.clone(),
+            });
+            let started = Instant::now();
+            let result = work(planned, &pb);
+            let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+            events.send(match &result {
+                Ok(_) => Event::SampleCompleted {
+                    sample,
+                    file,
+                    seconds: started.elapsed().as_secs_f64(),
+                },
+                Err(e) => Event::SampleFailed {
+                    sample,
+                    file,
+                    error: e.to_string(),
+                },
+            });
+            match &result {
+                Ok(_) => {
+                    if m.is_hidden() {
+                        info!("Completed {} ({}/{} samples)", filename, done, samples.len());
+                    }
+                    pb.finish_with_message(format!("Completed {}", filename))
+                }
+                Err(e) => {
+                    error!("{}: {}", filename, e);
+                    pb.abandon_with_message(format!("Failed {}", filename));
+                    if fail_fast {
+                        aborted.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            (file_path.clone(), result)
+        })
+        .collect()
+}
+
+/// Count the fragments of every sample in parallel, summing over the files pooled into it,
+/// behind a single progress bar, or a log line every tenth of the samples when the bar is
+/// hidden. Every file is attempted; failures are collected and returned together so they
+/// can all be reported at once.
+fn count_samples<F>(
+    samples: &[PathBuf],
+    members: &HashMap<PathBuf, Vec<PathBuf>>,
+    m: &MultiProgress,
+    timings: &Timings,
+    count: F,
+) -> Result<Vec<(PathBuf, FragmentCount)>, Vec<CountError>>
+where
+    F: Fn(&Path) -> Result<FragmentCount, Error> + Sync,
+{
+    let pb = m.add(ProgressBar::new(samples.len() as u64));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} {bar:40.cyan/blue} {pos}/{len} ({eta})")
+            .expect("Progress bar template error")
+            .progress_chars("#>-"),
+    );
+    pb.set_message("Counting fragments");
+    let counted = AtomicUsize::new(0);
+    let tick = || {
+        pb.inc(1);
+        let done = counted.fetch_add(1, Ordering::Relaxed) + 1;
+        if m.is_hidden() && done * 10 / samples.len() > (done - 1) * 10 / samples.len() {
+            info!("Counted fragments in {}/{} samples", done, samples.len());
+        }
+    };
+    let results: Vec<_> = samples
+        .par_iter()
+        .map(|file_path| {
+            let mut clock = Instant::now();
+            let mut total = FragmentCount::default();
+            for member in &members[file_path] {
+                match count(member) {
+                    Ok(c) => total += c,
+                    Err(e) => {
+                        tick();
+                        return Err((member.clone(), e));
+                    }
+                }
+            }
+            timings.lap(file_path, Stage::Counting, &mut clock);
+            tick();
+            Ok((file_path.clone(), total))
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let mut counts = Vec::with_capacity(results.len());
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok(count) => counts.push(count),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(counts)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Log every counting failure and turn them into one error.
+fn count_errors(errors: Vec<CountError>) -> Error {
+    for (file, e) in &errors {
+        error!("Counting fragments failed for {}: {}", file.display(), e);
+    }
+    Error::Counting(errors)
+}
+
+/// A step of a sample's processing timed for `--timing`.
+#[derive(Clone, Copy)]
+enum Stage {
+    /// Fragment counting, plus the size class counts of BAM samples
+    Counting,
+    /// `samtools merge` of pooled BAMs
+    Merge,
+    /// Reservoir sampling of BED fragments
+    Sampling,
+    /// `samtools view -s`
+    Downsampling,
+    /// `samtools index`
+    Index,
+    /// Chromosome, ATAC shift and blacklist filters and the sort of BED fragments
+    Sort,
+    /// Binning or region counting; all of bamCoverage in BAM mode
+    Coverage,
+    /// bedGraph, bigWig and region count outputs
+    Writing,
+}
+
+impl Stage {
+    const ALL: [Stage; 8] = [
+        Stage::Counting,
+        Stage::Merge,
+        Stage::Sampling,
+        Stage::Downsampling,
+        Stage::Index,
+        Stage::Sort,
+        Stage::Coverage,
+        Stage::Writing,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Counting => "counting",
+            Stage::Merge => "merge",
+            Stage::Sampling => "sampling",
+            Stage::Downsampling => "downsampling",
+            Stage::Index => "index",
+            Stage::Sort => "sort",
+            Stage::Coverage => "coverage",
+            Stage::Writing => "writing",
+        }
+    }
+}
+
+/// Wall-clock time each sample spent in each stage, summed over its size classes and
+/// pooled files.
+struct Timings<'a> {
+    times: Mutex<HashMap<PathBuf, [Option<Duration>; Stage::ALL.len()]>>,
+    /// Stream every lap is also sent to, as a completed stage
+    events: &'a EventStream,
+}
+
+impl<'a> Timings<'a> {
+    fn new(events: &'a EventStream) -> Self {
+        Timings {
+            times: Mutex::default(),
+            events,
+        }
+    }
+
+    /// Add the time since `clock` to `sample`'s `stage`, then restart `clock`.
+    fn lap(&self, sample: &Path, stage: Stage, clock: &mut Instant) {
+        let elapsed = clock.elapsed();
+        let mut samples = self.times.lock().unwrap();
+        let time = &mut samples.entry(sample.to_path_buf()).or_default()[stage as usize];
+        *time = Some(time.unwrap_or_default() + elapsed);
+        drop(samples);
+        self.events.send(Event::StageCompleted {
+            sample: self.events.sample_name(sample),
+            file: sample.to_path_buf(),
+            stage: stage.as_str(),
+            seconds: elapsed.as_secs_f64(),
+        });
+        *clock = Instant::now();
+    }
+
+    /// The recorded stages of every sample, in pipeline order.
+    fn take(&self) -> HashMap<PathBuf, Vec<(&'static str, Duration)>> {
+        let samples = std::mem::take(&mut *self.times.lock().unwrap());
+        samples
+            .into_iter()
+            .map(|(file, times)| {
+                let stages = Stage::ALL.iter().zip(times);
+                let times = stages.filter_map(|(stage, time)| Some((stage.as_str(), time?)));
+                (file, times.collect())
+            })
+            .collect()
+    }
+}
+
+/// One line of the `--events` stream, tagged with its `event` name. Samples are named as in
+/// their outputs, alongside the file identifying them.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    RunStarted {
+        input_type: InputType,
+        inputs: usize,
+    },
+    SampleStarted {
+        sample: String,
+        file: PathBuf,
+    },
+    /// Sent for every pass through a stage, so once per track for the per-track stages
+    StageCompleted {
+        sample: String,
+        file: PathBuf,
+        stage: &'static str,
+        seconds: f64,
+    },
+    SampleCompleted {
+        sample: String,
+        file: PathBuf,
+        seconds: f64,
+    },
+    SampleFailed {
+        sample: String,
+        file: PathBuf,
+        error: String,
+    },
+    RunFinished {
+        succeeded: usize,
+        failed: usize,
+        skipped: usize,
+        seconds: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct EventLine {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// Newline-delimited JSON events for `--events`, so orchestrators can follow a run without
+/// scraping the progress bars. Workers send events down a channel to a thread of its own,
+/// which writes and flushes each line as it arrives. Without a target every event is dropped.
+#[derive(Default)]
+struct EventStream {
+    sender: Option<mpsc::Sender<EventLine>>,
+    writer: Option<thread::JoinHandle<std::io::Result<()>>>,
+    /// The events file, or `<stdout>`, for errors
+    target: PathBuf,
+    /// Sample name of each sample's first file, set once the inputs are pooled
+    names: OnceLock<HashMap<PathBuf, String>>,
+}
+
+impl EventStream {
+    /// Start writing events to `path`, or to stdout with `stdout`; a stream that drops
+    /// everything with neither.
+    fn open(path: Option<&Path>, stdout: bool) -> Result<Self, Error> {
+        let (out, target): (Box<dyn Write + Send>, PathBuf) = match path {
+            Some(path) => (Box::new(create_file(path)?), path.to_path_buf()),
+            None if stdout => (Box::new(std::io::stdout()), PathBuf::from("<stdout>")),
+            None => return Ok(EventStream::default()),
+        };
+        let (sender, receiver) = mpsc::channel::<EventLine>();
+        let writer = thread::spawn(move || {
+            let mut out = BufWriter::new(out);
+            for line in receiver {
+                serde_json::to_writer(&mut out, &line)?;
+                writeln!(out)?;
+                out.flush()?;
+            }
+            Ok(())
+        });
+        Ok(EventStream {
+            sender: Some(sender),
+            writer: Some(writer),
+            target,
+            names: OnceLock::new(),
+        })
+    }
+
+    fn send(&self, event: Event) {
+        if let Some(sender) = &self.sender {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            // A writer that stopped on an error reports it from `finish`
+            let _ = sender.send(EventLine {
+                timestamp: now.as_secs_f64(),
+                event,
+            });
+        }
+    }
+
+    /// Name of the sample identified by `file`, its stem until the names are set.
+    fn sample_name(&self, file: &Path) -> String {
+        (self.names.get().and_then(|names| names.get(file)).cloned())
+            .unwrap_or_else(|| sample_stem(file))
+    }
+
+    /// Close the channel and wait for the writer to drain it.
+    fn finish(mut self) -> Result<(), Error> {
+        self.sender = None;
+        match self.writer.take() {
+            Some(writer) => {
+                let written = writer.join().expect("events writer panicked");
+                written.map_err(Error::io(&self.target))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Outcome of a pipeline run.
+pub struct RunReport {
+    /// Seed the run used, either the configured one or a randomly chosen one
+    pub seed: u64,
+    /// Library-size QC over all samples
+    pub qc: QcResult,
+    /// Downsampling target and per-sample plan for the samples that passed QC
+    pub plan: DownsamplePlan,
+    /// Result of every sample that passed QC and was processed, `Err` holding the failed
+    /// step; empty for a dry run
+    pub outcomes: Vec<SampleOutcome>,
+    /// Samples not processed because their outputs already existed
+    pub skipped: Vec<PathBuf>,
+    /// Factor each processed sample's coverage was multiplied by with `--scale` or
+    /// `--spikein-normalize`; empty without scaling
+    pub scale_factors: HashMap<PathBuf, f64>,
+    /// Malformed BED lines skipped in each sample that had any
+    pub malformed_lines: HashMap<PathBuf, usize>,
+    /// Seed, samtools fraction and retained count of each sample processed successfully
+    pub draws: HashMap<PathBuf, SampleDraw>,
+    /// Wall-clock time of each stage every sample went through, in pipeline order
+    pub timings: HashMap<PathBuf, Vec<(&'static str, Duration)>>,
+}
+
+impl RunReport {
+    /// Number of samples that failed.
+    pub fn failures(&self) -> usize {
+        self.outcomes.iter().filter(|(_, r)| r.is_err()).count()
+    }
+
+    /// Log a per-sample summary table.
+    pub fn log_summary(&self) {
+        let failures = self.failures();
+        if self.skipped.is_empty() {
+            info!(
+                "Summary: {} succeeded, {} failed",
+                self.outcomes.len() - failures,
+                failures
+            );
+        } else {
+            info!(
+                "Summary: {} succeeded, {} skipped, {} failed",
+                self.outcomes.len() - failures,
+                self.skipped.len(),
+                failures
+            );
+        }
+        let total_malformed: usize = self.malformed_lines.values().sum();
+        if total_malformed > 0 {
+            warn!("Skipped {} malformed BED line(s)", total_malformed);
+        }
+        for (file, result) in &self.outcomes {
+            let malformed = match self.malformed_lines.get(file) {
+                Some(n) => format!(" ({} malformed lines skipped)", n),
+                None => String::new(),
+            };
+            match result {
+                Ok(()) => info!("  OK      {}{}", file.display(), malformed),
+                Err(e) => error!("  FAILED  {}: {}", file.display(), e),
+            }
+        }
+        for file in &self.skipped {
+            info!("  SKIPPED {}", file.display());
+        }
+    }
+
+    /// Stages timed in any sample, in pipeline order.
+    fn timed_stages(&self) -> Vec<&'static str> {
+        let timed: HashSet<&str> =
+            self.timings.values().flat_map(|times| times.iter().map(|(s, _)| *s)).collect();
+        Stage::ALL.iter().map(|s| s.as_str()).filter(|s| timed.contains(s)).collect()
+    }
+
+    /// Log the time spent in each stage summed over the samples, and its share of the total.
+    fn log_timing(&self) {
+        let mut totals: Vec<(&str, Duration)> =
+            self.timed_stages().into_iter().map(|s| (s, Duration::ZERO)).collect();
+        for (stage, time) in self.timings.values().flatten() {
+            if let Some(total) = totals.iter_mut().find(|(s, _)| s == stage) {
+                total.1 += *time;
+            }
+        }
+        let all: Duration = totals.iter().map(|(_, time)| *time).sum();
+        info!("Time per stage, summed over samples:");
+        for (stage, time) in &totals {
+            let share = time.as_secs_f64() / all.as_secs_f64().max(f64::MIN_POSITIVE);
+            info!("  {:<13}{:>10.3}s {:>5.1}%", stage, time.as_secs_f64(), share * 100.0);
+        }
+        info!("  {:<13}{:>10.3}s", "total", all.as_secs_f64());
+    }
+
+    /// Write a TSV with a row per sample and its seconds in each stage (`--timing`).
+    fn write_timing(&self, path: &Path) -> Result<(), Error> {
+        let stages = self.timed_stages();
+        write_atomic(path, |partial| {
+            let write_err = Error::io(path);
+            let mut writer = BufWriter::new(create_file(partial)?);
+            writeln!(writer, "file\tsample\t{}\ttotal", stages.join("\t")).map_err(&write_err)?;
+            for s in &self.qc.samples {
+                let Some(times) = self.timings.get(&s.file) else {
+                    continue;
+                };
+                let mut row = vec![s.file.display().to_string(), s.sample.clone()];
+                for stage in &stages {
+                    let time = times.iter().find(|(t, _)| t == stage).map(|(_, time)| *time);
+                    row.push(time.map(|t| format!("{:.3}", t.as_secs_f64())).unwrap_or_default());
+                }
+                let total: Duration = times.iter().map(|(_, time)| *time).sum();
+                row.push(format!("{:.3}", total.as_secs_f64()));
+                writeln!(writer, "{}", row.join("\t")).map_err(&write_err)?;
+            }
+            writer.flush().map_err(&write_err)
+        })
+    }
+}
+
+/// Index of what a run produced (`--manifest`): the run parameters, then every sample with
+/// its QC result, fraction, status and bigWigs.
+#[derive(Serialize)]
+struct Manifest<'a> {
+    seed: u64,
+    bin_size: usize,
+    qc_method: QcMethod,
+    qc_cutoff: f64,
+    qc_upper_cutoff: Option<f64>,
+    target: usize,
+    /// The options the run was started with
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    settings: BTreeMap<&'a str, &'a str>,
+    samples: Vec<ManifestSample<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestSample<'a> {
+    file: &'a Path,
+    /// Every input file of the sample; more than one when files were pooled
+    inputs: &'a [PathBuf],
+    sample: &'a str,
+    fragments: usize,
+    /// `pass`, or why the sample was excluded
+    qc: &'static str,
+    fraction: Option<f64>,
+    /// Fraction passed to `samtools view -s` (BAM input)
+    samtools_fraction: Option<f64>,
+    /// Seed of the sample's random draw
+    seed: Option<u64>,
+    /// Fragments the tracks were built from; expected reads for BAMs
+    retained: Option<usize>,
+    /// Factor the coverage was multiplied by with `--scale` or `--spikein-normalize`
+    scale_factor: Option<f64>,
+    /// `ok`, `skipped`, `failed` or `excluded`
+    status: &'static str,
+    /// bigWigs the sample has on disk after the run
+    outputs: &'a [PathBuf],
+}
+
+impl<'a> Manifest<'a> {
+    fn new(
+        report: &'a RunReport,
+        members: &'a HashMap<PathBuf, Vec<PathBuf>>,
+        bin_size: usize,
+        settings: &'a [(String, String)],
+    ) -> Self {
+        let samples = report
+            .qc
+            .samples
+            .iter()
+            .map(|s| {
+                let planned = report.plan.samples.iter().find(|p| p.file == s.file);
+                let outcome = report.outcomes.iter().find(|(f, _)| *f == s.file);
+                let draw = report.draws.get(&s.file);
+                let status = match (planned, outcome) {
+                    (None, _) => "excluded",
+                    (Some(_), Some((_, Err(_)))) => "failed",
+                    (Some(_), _) if report.skipped.contains(&s.file) => "skipped",
+                    (Some(_), _) => "ok",
+                };
+                let outputs = match planned {
+                    Some(planned) if status != "failed" => planned.outputs.as_slice(),
+                    _ => &[],
+                };
+                ManifestSample {
+                    file: &s.file,
+                    inputs: members.get(&s.file).map_or(&[], Vec::as_slice),
+                    sample: &s.sample,
+                    fragments: s.fragments,
+                    qc: s.excluded.map_or("pass", |e| e.as_str()),
+                    fraction: planned.map(|p| p.fraction),
+                    samtools_fraction: draw.and_then(|d| d.samtools_fraction),
+                    seed: draw.map(|d| d.seed),
+                    retained: draw.map(|d| d.retained),
+                    scale_factor: report.scale_factors.get(&s.file).copied(),
+                    status,
+                    outputs,
+                }
+            })
+            .collect();
+        Manifest {
+            seed: report.seed,
+            bin_size,
+            qc_method: report.qc.method,
+            qc_cutoff: report.qc.cutoff,
+            qc_upper_cutoff: report.qc.upper_cutoff,
+            target: report.plan.target,
+            settings: settings.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+            samples,
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Error> {
+        let format = ReportFormat::from_path(path)?;
+        write_atomic(path, |partial| self.write_as(partial, format).map_err(Error::io(path)))
+    }
+
+    fn write_as(&self, path: &Path, format: ReportFormat) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Tsv => {
+                // Run parameters as `#key<TAB>value` lines above the sample table
+                writeln!(writer, "#seed\t{}", self.seed)?;
+                writeln!(writer, "#bin_size\t{}", self.bin_size)?;
+                writeln!(writer, "#qc_method\t{}", self.qc_method.as_str())?;
+                writeln!(writer, "#qc_cutoff\t{}", self.qc_cutoff)?;
+                if let Some(upper) = self.qc_upper_cutoff {
+                    writeln!(writer, "#qc_upper_cutoff\t{}", upper)?;
+                }
+                writeln!(writer, "#target\t{}", self.target)?;
+                for (key, value) in &self.settings {
+                    writeln!(writer, "#setting.{}\t{}", key, value)?;
+                }
+                writeln!(
+                    writer,
+                    "file\tinputs\tsample\tfragments\tqc\tfraction\tsamtools_fraction\tseed\t\
+                     retained\tscale_factor\tstatus\toutputs"
+                )?;
+                let join = |paths: &[PathBuf]| {
+                    let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+                    paths.join(",")
+                };
+                for s in &self.samples {
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        s.file.display(),
+                        join(s.inputs),
+                        s.sample,
+                        s.fragments,
+                        s.qc,
+                        s.fraction.map(|f| f.to_string()).unwrap_or_default(),
+                        s.samtools_fraction.map(|f| f.to_string()).unwrap_or_default(),
+                        s.seed.map(|n| n.to_string()).unwrap_or_default(),
+                        s.retained.map(|n| n.to_string()).unwrap_or_default(),
+                        s.scale_factor.map(|f| f.to_string()).unwrap_or_default(),
+                        s.status,
+                        join(s.outputs)
+                    )?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The downsampling and coverage pipeline, configured through [`PipelineBuilder`].
+///
+/// ```no_run
+/// use bedfragment_ds::Pipeline;
+///
+/// let pipeline = Pipeline::builder()
+///     .chrom_sizes("hg38.chrom.sizes")
+///     .bin_size(100)
+///     .seed(42)
+///     .native_bigwig(true)
+///     .build()?;
+/// let report = pipeline.run_bed(&["a.bed".into(), "b.bed".into()])?;
+/// assert_eq!(report.failures(), 0);
+/// # Ok::<(), bedfragment_ds::Error>(())
+/// ```
+pub struct Pipeline {
+    tools: Tools,
+    chrom_sizes: Option<PathBuf>,
+    blacklist: Option<PathBuf>,
+    outdir: Option<PathBuf>,
+    tmp_dir: Option<PathBuf>,
+    bin_size: usize,
+    step: Option<usize>,
+    smooth_length: Option<usize>,
+    log2: Option<f64>,
+    regions: Option<PathBuf>,
+    seed: Option<u64>,
+    threads: usize,
+    max_concurrent: Option<usize>,
+    per_file_threads: Option<usize>,
+    qc: QcParams,
+    target: Target,
+    no_downsample: bool,
+    qc_report: Option<PathBuf>,
+    per_chrom_report: Option<PathBuf>,
+    length_histogram: Option<PathBuf>,
+    length_histogram_bin: u64,
+    length_histogram_full: bool,
+    manifest: Option<PathBuf>,
+    timing: Option<PathBuf>,
+    events: Option<PathBuf>,
+    events_stdout: bool,
+    settings: Vec<(String, String)>,
+    matrix: Option<PathBuf>,
+    correlation: Option<PathBuf>,
+    correlation_method: CorrelationMethod,
+    quantile_normalize: bool,
+    filter_qc: bool,
+    no_header: bool,
+    strict: bool,
+    on_bad_coord: BadCoords,
+    dedup: bool,
+    weighted: bool,
+    bedpe: bool,
+    low_memory: bool,
+    chroms: ChromFilter,
+    mito_chroms: Vec<String>,
+    spikein_chroms: Vec<String>,
+    spikein_normalize: bool,
+    lengths: LengthFilter,
+    bam_filter: BamFilter,
+    no_auto_index: bool,
+    mode: CoverageMode,
+    normalize: Normalization,
+    effective_genome_size: Option<u64>,
+    split_by_chrom: bool,
+    scale: Scale,
+    atac_shift: Option<AtacShift>,
+    extend: Option<u64>,
+    name_pattern: Option<Regex>,
+    group_pattern: Option<Regex>,
+    pool: PoolMode,
+    /// Inputs read from `--files-from`, run after the ones passed in
+    listed_files: Vec<PathBuf>,
+    /// Sample name or group of `--files-from` inputs that have one
+    file_groups: HashMap<PathBuf, String>,
+    recursive: bool,
+    size_classes: Vec<SizeClass>,
+    stranded: bool,
+    native_bigwig: bool,
+    output_format: OutputFormat,
+    keep_bedgraph: bool,
+    keep_downsampled_bed: bool,
+    keep_tmp_bam: bool,
+    exact: bool,
+    fail_fast: bool,
+    dry_run: bool,
+    count_only: bool,
+    force: bool,
+    progress: MultiProgress,
+    cleanup: CleanupRegistry,
+}
+
+/// Builder for [`Pipeline`]. Every setting has the same default as the command line.
+pub struct PipelineBuilder {
+    tools: Tools,
+    chrom_sizes: Option<PathBuf>,
+    blacklist: Option<PathBuf>,
+    outdir: Option<PathBuf>,
+    tmp_dir: Option<PathBuf>,
+    bin_size: usize,
+    step: Option<usize>,
+    smooth_length: Option<usize>,
+    log2: bool,
+    pseudocount: Option<f64>,
+    regions: Option<PathBuf>,
+    seed: Option<u64>,
+    threads: usize,
+    max_concurrent: Option<usize>,
+    per_file_threads: Option<usize>,
+    qc: QcParams,
+    target: Target,
+    no_downsample: bool,
+    qc_report: Option<PathBuf>,
+    per_chrom_report: Option<PathBuf>,
+    length_histogram: Option<PathBuf>,
+    length_histogram_bin: u64,
+    length_histogram_full: bool,
+    manifest: Option<PathBuf>,
+    timing: Option<PathBuf>,
+    events: Option<PathBuf>,
+    events_stdout: bool,
+    settings: Vec<(String, String)>,
+    matrix: Option<PathBuf>,
+    correlation: Option<PathBuf>,
+    correlation_method: CorrelationMethod,
+    quantile_normalize: bool,
+    filter_qc: bool,
+    no_header: bool,
+    strict: bool,
+    on_bad_coord: BadCoords,
+    dedup: bool,
+    weighted: bool,
+    bedpe: bool,
+    low_memory: bool,
+    chroms: ChromFilter,
+    mito_chroms: Vec<String>,
+    exclude_mito: bool,
+    spikein_chroms: Vec<String>,
+    spikein_normalize: bool,
+    lengths: LengthFilter,
+    min_mapq: u8,
+    include_flags: Option<u16>,
+    exclude_flags: Option<u16>,
+    single_end: bool,
+    no_auto_index: bool,
+    mode: CoverageMode,
+    normalize: Normalization,
+    effective_genome_size: Option<u64>,
+    genome: Option<Genome>,
+    split_by_chrom: bool,
+    scale: Scale,
+    atac_shift: Option<AtacShift>,
+    extend: Option<u64>,
+    name_pattern: Option<Regex>,
+    group_pattern: Option<Regex>,
+    pool: PoolMode,
+    files_from: Option<PathBuf>,
+    recursive: bool,
+    size_classes: Vec<SizeClass>,
+    stranded: bool,
+    native_bigwig: bool,
+    output_format: OutputFormat,
+    keep_bedgraph: bool,
+    keep_downsampled_bed: bool,
+    keep_tmp_bam: bool,
+    exact: bool,
+    fail_fast: bool,
+    dry_run: bool,
+    count_only: bool,
+    force: bool,
+    progress: Option<MultiProgress>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        PipelineBuilder {
+            tools: Tools::default(),
+            chrom_sizes: None,
+            blacklist: None,
+            outdir: None,
+            tmp_dir: None,
+            bin_size: 50,
+            step: None,
+            smooth_length: None,
+            log2: false,
+            pseudocount: None,
+            regions: None,
+            seed: None,
+            threads: 0,
+            max_concurrent: None,
+            per_file_threads: None,
+            qc: QcParams::default(),
+            target: Target::Min,
+            no_downsample: false,
+            qc_report: None,
+            per_chrom_report: None,
+            length_histogram: None,
+            length_histogram_bin: 10,
+            length_histogram_full: false,
+            manifest: None,
+            timing: None,
+            events: None,
+            events_stdout: false,
+            settings: Vec::new(),
+            matrix: None,
+            correlation: None,
+            correlation_method: CorrelationMethod::Pearson,
+            quantile_normalize: false,
+            filter_qc: false,
+            no_header: false,
+            strict: false,
+            on_bad_coord: BadCoords::Clamp,
+            dedup: false,
+            weighted: false,
+            bedpe: false,
+            low_memory: false,
+            chroms: ChromFilter::default(),
+            mito_chroms: Vec::new(),
+            exclude_mito: false,
+            spikein_chroms: Vec::new(),
+            spikein_normalize: false,
+            lengths: LengthFilter::default(),
+            min_mapq: 0,
+            include_flags: None,
+            exclude_flags: None,
+            single_end: false,
+            no_auto_index: false,
+            split_by_chrom: false,
+            mode: CoverageMode::Fragment,
+            normalize: Normalization::None,
+            effective_genome_size: None,
+            genome: None,
+            scale: Scale::None,
+            atac_shift: None,
+            extend: None,
+            name_pattern: None,
+            group_pattern: None,
+            pool: PoolMode::None,
+            files_from: None,
+            recursive: false,
+            size_classes: Vec::new(),
+            stranded: false,
+            native_bigwig: false,
+            output_format: OutputFormat::Bigwig,
+            keep_bedgraph: false,
+            keep_downsampled_bed: false,
+            keep_tmp_bam: false,
+            exact: false,
+            fail_fast: false,
+            dry_run: false,
+            count_only: false,
+            force: false,
+            progress: None,
+        }
+    }
+}
+
+impl PipelineBuilder {
+    /// samtools executable (default: `samtools` on `PATH`).
+    pub fn samtools(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tools.samtools = path.into();
+        self
+    }
+
+    /// bamCoverage executable (default: `bamCoverage` on `PATH`).
+    pub fn bam_coverage(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tools.bam_coverage = path.into();
+        self
+    }
+
+    /// bedGraphToBigWig executable (default: `bedGraphToBigWig` on `PATH`).
+    pub fn bedgraph_to_bigwig(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tools.bedgraph_to_bigwig = path.into();
+        self
+    }
+
+    /// Retry an external tool that exits nonzero up to `retries` times, with a short pause
+    /// in between, before failing the sample (default 0).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.tools.retries = retries;
+        self
+    }
+
+    /// Reference FASTA to decode CRAM input with, passed to samtools as `--reference`;
+    /// required when any input is a `.cram`.
+    pub fn reference_fasta(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.tools.reference = path.into();
+        self
+    }
+
+    /// Chromosome sizes file or `.fai` index; required for BED input. With BAM input it is
+    /// only checked against the first BAM's header.
+    pub fn chrom_sizes(mut self, path: impl Into<PathBuf>) -> Self {
+        self.chrom_sizes = Some(path.into());
+        self
+    }
+
+    /// Blacklist BED file. Fragments overlapping it are dropped in BED mode; in BAM mode it is
+    /// passed to bamCoverage.
+    pub fn blacklist(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.blacklist = path.into();
+        self
+    }
+
+    /// Directory for all outputs (default: next to each input file).
+    pub fn outdir(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.outdir = path.into();
+        self
+    }
+
+    /// Parent directory for the per-run temp directory (default: the system temp dir).
+    pub fn tmp_dir(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.tmp_dir = path.into();
+        self
+    }
+
+    /// Bin size in bp of the coverage tracks (default 50).
+    pub fn bin_size(mut self, bin_size: usize) -> Self {
+        self.bin_size = bin_size;
+        self
+    }
+
+    /// Slide the bins by this many bp instead of tiling them (BED input only).
+    pub fn step(mut self, step: impl Into<Option<usize>>) -> Self {
+        self.step = step.into();
+        self
+    }
+
+    /// Average each bin with its neighbours over a window of this many bp, at least the bin
+    /// size: passed to bamCoverage as `--smoothLength`, done on the bin counts for BED input.
+    pub fn smooth_length(mut self, length: impl Into<Option<usize>>) -> Self {
+        self.smooth_length = length.into();
+        self
+    }
+
+    /// Write `log2(count + pseudocount)` of the scaled bin counts instead of the counts, in
+    /// tracks named with `_log2` (BED input only).
+    pub fn log2(mut self, log2: bool) -> Self {
+        self.log2 = log2;
+        self
+    }
+
+    /// Pseudocount added before `log2`, which must be positive (default 1).
+    pub fn pseudocount(mut self, pseudocount: impl Into<Option<f64>>) -> Self {
+        self.pseudocount = pseudocount.into();
+        self
+    }
+
+    /// Count fragments over the intervals of this BED file, written as a TSV per track,
+    /// instead of binning the genome into bigWigs (BED input only).
+    pub fn regions(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.regions = path.into();
+        self
+    }
+
+    /// Random seed for downsampling (default: a random seed, reported in the run report).
+    pub fn seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.seed = seed.into();
+        self
+    }
+
+    /// Number of worker threads (default 0, meaning all available cores).
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Process at most this many samples at a time (default: one per thread). Counting
+    /// still uses every thread.
+    pub fn max_concurrent(mut self, max_concurrent: impl Into<Option<usize>>) -> Self {
+        self.max_concurrent = max_concurrent.into();
+        self
+    }
+
+    /// Threads for each samtools and bamCoverage call (BAM input only). By default the
+    /// threads not needed to process every sample at once are shared out among them.
+    pub fn per_file_threads(mut self, threads: impl Into<Option<usize>>) -> Self {
+        self.per_file_threads = threads.into();
+        self
+    }
+
+    /// Library-size QC settings.
+    pub fn qc(mut self, params: QcParams) -> Self {
+        self.qc = params;
+        self
+    }
+
+    /// How the downsampling target is chosen (default: the smallest passing sample).
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Write tracks from every fragment instead of downsampling; QC still decides which
+    /// samples are processed. Output names get a `full` suffix.
+    pub fn no_downsample(mut self, no_downsample: bool) -> Self {
+        self.no_downsample = no_downsample;
+        self
+    }
+
+    /// Write the QC report to this `.json` or `.tsv` file.
+    pub fn qc_report(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.qc_report = path.into();
+        self
+    }
+
+    /// Write each sample's fragments per chromosome to this `.json` or `.tsv` file. BAM input
+    /// is counted with `samtools idxstats`, so the BAMs must be indexed.
+    pub fn per_chrom_report(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.per_chrom_report = path.into();
+        self
+    }
+
+    /// Write each sample's fragment-length histogram to this TSV: `end - start` of BED
+    /// fragments, TLEN of BAM read pairs (which needs the `htslib` feature).
+    pub fn length_histogram(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.length_histogram = path.into();
+        self
+    }
+
+    /// Width of the `--length-histogram` bins in bp. Defaults to 10.
+    pub fn length_histogram_bin(mut self, width: u64) -> Self {
+        self.length_histogram_bin = width;
+        self
+    }
+
+    /// Build `--length-histogram` from every kept fragment rather than the downsampled set.
+    /// Count-only runs have no downsampled set and always use the full library.
+    pub fn length_histogram_full(mut self, full: bool) -> Self {
+        self.length_histogram_full = full;
+        self
+    }
+
+    /// Write a manifest of every sample and the bigWigs it produced to this `.json` or
+    /// `.tsv` file.
+    pub fn manifest(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.manifest = path.into();
+        self
+    }
+
+    /// Write each sample's wall-clock time per stage to this TSV file, and log the time
+    /// per stage summed over the samples.
+    pub fn timing(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.timing = path.into();
+        self
+    }
+
+    /// Write newline-delimited JSON events to this file as the run goes: `run_started`,
+    /// `sample_started`, `stage_completed`, `sample_completed` or `sample_failed`, and
+    /// `run_finished`, each with a Unix `timestamp`.
+    pub fn events(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.events = path.into();
+        self
+    }
+
+    /// Write the `events` stream to stdout instead of a file.
+    pub fn events_stdout(mut self, events_stdout: bool) -> Self {
+        self.events_stdout = events_stdout;
+        self
+    }
+
+    /// The settings the run was started with, as `(option, value)` pairs, recorded in the
+    /// manifest so it documents how its outputs were made.
+    pub fn settings(mut self, settings: Vec<(String, String)>) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Also write every sample's bin (or region) values into one TSV matrix with a column per
+    /// track (BED input only).
+    pub fn matrix(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.matrix = path.into();
+        self
+    }
+
+    /// Write the pairwise correlations of the samples' tracks to this TSV (BED input only).
+    pub fn correlation(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.correlation = path.into();
+        self
+    }
+
+    /// How `correlation` compares the tracks (default Pearson).
+    pub fn correlation_method(mut self, method: CorrelationMethod) -> Self {
+        self.correlation_method = method;
+        self
+    }
+
+    /// Also write quantile-normalized copies of the tracks, suffixed `_qnorm`, in which every
+    /// sample's bin (or region) values follow the same distribution (BED input only). All the
+    /// samples' values are held in memory until the last sample is done.
+    pub fn quantile_normalize(mut self, quantile_normalize: bool) -> Self {
+        self.quantile_normalize = quantile_normalize;
+        self
+    }
+
+    /// Run QC and pick the downsampling target on chromosome and length filtered counts.
+    pub fn filter_qc(mut self, filter_qc: bool) -> Self {
+        self.filter_qc = filter_qc;
+        self
+    }
+
+    /// Treat the first line of BED files as data instead of auto-detecting a header.
+    pub fn no_header(mut self, no_header: bool) -> Self {
+        self.no_header = no_header;
+        self
+    }
+
+    /// Fail on the first malformed BED line instead of skipping and counting them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// How BED fragments that are empty or run past their chromosome's end are handled
+    /// (default: clamped to the chromosome).
+    pub fn on_bad_coord(mut self, on_bad_coord: BadCoords) -> Self {
+        self.on_bad_coord = on_bad_coord;
+        self
+    }
+
+    /// Drop exact-duplicate fragments (same chrom, start, end and strand) before counting
+    /// and sampling BED files, and reads flagged as duplicates (`-F 1024`) in BAM files.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Weight each BED fragment's coverage by its score column instead of counting it once,
+    /// for pre-weighted fragment files. Every score must parse as a number.
+    pub fn weighted(mut self, weighted: bool) -> Self {
+        self.weighted = weighted;
+        self
+    }
+
+    /// Read BED input as BEDPE mate pairs, each standing for the fragment from its leftmost
+    /// mate start to its rightmost mate end. Pairs with mates on different chromosomes are
+    /// dropped and counted.
+    pub fn bedpe(mut self, bedpe: bool) -> Self {
+        self.bedpe = bedpe;
+        self
+    }
+
+    /// Sample BED lines by byte offset and read the picked ones back in a second pass,
+    /// trading a second read of every input for less memory per worker.
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    /// Keep only fragments on these chromosomes (all when `None`) and not on `exclude`.
+    pub fn chroms(mut self, include: Option<Vec<String>>, exclude: Vec<String>) -> Self {
+        self.chroms = ChromFilter::new(include, exclude);
+        self
+    }
+
+    /// Names of the mitochondrial chromosome whose share of fragments the QC report gives.
+    /// When empty, the default, whichever of `chrM`, `MT`, `chrMT` and `M` the chromosome
+    /// sizes or BAM header list is used.
+    pub fn mito_chroms(mut self, chroms: Vec<String>) -> Self {
+        self.mito_chroms = chroms;
+        self
+    }
+
+    /// Leave the mitochondrial chromosome out of the tracks, like `--exclude-chroms`. Without
+    /// `mito_chroms`, every usual name is excluded.
+    pub fn exclude_mito(mut self, exclude: bool) -> Self {
+        self.exclude_mito = exclude;
+        self
+    }
+
+    /// Spike-in chromosome names (e.g. the E. coli or yeast contigs of a CUT&RUN spike-in).
+    /// Their fragments are counted for the QC report and left out of the tracks.
+    pub fn spikein_chroms(mut self, chroms: Vec<String>) -> Self {
+        self.spikein_chroms = chroms;
+        self
+    }
+
+    /// Scale each sample's tracks by its spike-in fragments rather than by library size;
+    /// needs `spikein_chroms`.
+    pub fn spikein_normalize(mut self, normalize: bool) -> Self {
+        self.spikein_normalize = normalize;
+        self
+    }
+
+    /// Keep only fragments of `min..=max` bp; either bound may be open.
+    pub fn fragment_lengths(mut self, min: Option<u64>, max: Option<u64>) -> Self {
+        self.lengths = LengthFilter { min, max };
+        self
+    }
+
+    /// Minimum mapping quality of BAM reads.
+    pub fn min_mapq(mut self, min_mapq: u8) -> Self {
+        self.min_mapq = min_mapq;
+        self
+    }
+
+    /// SAM flags a BAM read must have (default 2, or 0 for single-end data).
+    pub fn include_flags(mut self, flags: impl Into<Option<u16>>) -> Self {
+        self.include_flags = flags.into();
+        self
+    }
+
+    /// SAM flags a BAM read must not have (default 260).
+    pub fn exclude_flags(mut self, flags: impl Into<Option<u16>>) -> Self {
+        self.exclude_flags = flags.into();
+        self
+    }
+
+    /// BAM input is single-end, so reads aren't required to be properly paired.
+    pub fn single_end(mut self, single_end: bool) -> Self {
+        self.single_end = single_end;
+        self
+    }
+
+    /// Fail on an input BAM without an index instead of running `samtools index` on it.
+    pub fn no_auto_index(mut self, no_auto_index: bool) -> Self {
+        self.no_auto_index = no_auto_index;
+        self
+    }
+
+    /// Run bamCoverage once per chromosome, in parallel, and join the parts into each
+    /// track, so a few large BAMs still use every thread.
+    pub fn split_by_chrom(mut self, split: bool) -> Self {
+        self.split_by_chrom = split;
+        self
+    }
+
+    /// What each fragment contributes to the coverage track.
+    pub fn mode(mut self, mode: CoverageMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// bamCoverage normalization of the BAM tracks (default none, i.e. raw counts).
+    pub fn normalize(mut self, normalize: Normalization) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Effective genome size in bp passed to bamCoverage; required for RPGC normalization.
+    pub fn effective_genome_size(mut self, size: impl Into<Option<u64>>) -> Self {
+        self.effective_genome_size = size.into();
+        self
+    }
+
+    /// Take the effective genome size from a build preset, unless
+    /// [`PipelineBuilder::effective_genome_size`] gives one.
+    pub fn genome(mut self, genome: impl Into<Option<Genome>>) -> Self {
+        self.genome = genome.into();
+        self
+    }
+
+    /// Library-size scaling of the BED bin counts (default none, i.e. raw counts).
+    pub fn scale(mut self, scale: Scale) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Apply the Tn5 shift to BED fragments, moving plus-strand ends by `plus` and
+    /// minus-strand ends by `minus` bp (the usual values are +4 and -5).
+    pub fn atac_shift(mut self, plus: i64, minus: i64) -> Self {
+        self.atac_shift = Some(AtacShift { plus, minus });
+        self
+    }
+
+    /// Extend single-end reads to this many bp in their 3' direction before coverage:
+    /// `--extendReads` for bamCoverage, done on stranded BED lines for BED input.
+    pub fn extend(mut self, length: impl Into<Option<u64>>) -> Self {
+        self.extend = length.into();
+        self
+    }
+
+    /// Take sample names from the first capture group of this regex.
+    pub fn name_pattern(mut self, pattern: impl Into<Option<Regex>>) -> Self {
+        self.name_pattern = pattern.into();
+        self
+    }
+
+    /// Group files by the first capture group of this regex; used with [`PoolMode::Sum`].
+    pub fn group_pattern(mut self, pattern: impl Into<Option<Regex>>) -> Self {
+        self.group_pattern = pattern.into();
+        self
+    }
+
+    /// How to combine files of the same group.
+    pub fn pool(mut self, pool: PoolMode) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Also run the inputs listed in this file, one path per line, after the ones passed
+    /// to `run_bed`/`run_bam`. A second, tab-separated column gives the file's sample name,
+    /// which with [`PoolMode::Sum`] is also its group. Blank lines and `#` comments are
+    /// skipped.
+    pub fn files_from(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.files_from = path.into();
+        self
+    }
+
+    /// Search directory inputs for input files at any depth rather than only directly
+    /// inside them. Glob patterns and directories among the inputs are always expanded.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Write one bigWig per fragment length class instead of a single track.
+    pub fn size_classes(mut self, classes: Vec<SizeClass>) -> Self {
+        self.size_classes = classes;
+        self
+    }
+
+    /// Write separate forward and reverse strand tracks, named `_fwd` and `_rev`: BED
+    /// fragments split by their strand column, BAM reads by bamCoverage `--filterRNAstrand`.
+    pub fn stranded(mut self, stranded: bool) -> Self {
+        self.stranded = stranded;
+        self
+    }
+
+    /// Write bigWigs with the built-in writer instead of bedGraphToBigWig.
+    pub fn native_bigwig(mut self, native_bigwig: bool) -> Self {
+        self.native_bigwig = native_bigwig;
+        self
+    }
+
+    /// Write binned tracks as bigWigs (the default), bedGraphs or both.
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// Keep the intermediate downsampled BEDs and bedGraphs (BED input).
+    pub fn keep_bedgraph(mut self, keep: bool) -> Self {
+        self.keep_bedgraph = keep;
+        self
+    }
+
+    /// Write each sample's sampled, sorted fragments to the output directory as
+    /// `<sample>_downsampled.bed` (BED input), whether or not intermediates are kept.
+    pub fn keep_downsampled_bed(mut self, keep: bool) -> Self {
+        self.keep_downsampled_bed = keep;
+        self
+    }
+
+    /// Keep the intermediate downsampled BAMs (BAM input).
+    pub fn keep_tmp_bam(mut self, keep: bool) -> Self {
+        self.keep_tmp_bam = keep;
+        self
+    }
+
+    /// Downsample BAMs to exactly the target by reservoir sampling the filtered read pairs
+    /// with rust-htslib, instead of `samtools view -s` (BAM input; needs the `htslib`
+    /// feature).
+    pub fn exact(mut self, exact: bool) -> Self {
+        self.exact = exact;
+        self
+    }
+
+    /// Stop starting new samples after the first failure.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Only count, run QC and plan the downsampling: no samples are processed and nothing is
+    /// written. BAM input is still counted with `samtools view -c` unless built with htslib.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Stop after counting and QC, writing the QC report but no tracks. The returned report
+    /// holds the QC result and the plan a full run would follow.
+    pub fn count_only(mut self, count_only: bool) -> Self {
+        self.count_only = count_only;
+        self
+    }
+
+    /// Regenerate every sample, even those whose bigWigs already exist.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Draw progress bars here (default: no progress bars). With a hidden target, progress is
+    /// logged as plain lines instead.
+    pub fn progress(mut self, progress: MultiProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Check the settings and build the pipeline.
+    pub fn build(self) -> Result<Pipeline, Error> {
+        if self.bin_size == 0 {
+            return Err(Error::Config("--bin-size must be greater than zero".into()));
+        }
+        if let Some(step) = self.step {
+            if step == 0 || step > self.bin_size {
+                return Err(Error::Config(format!(
+                    "--step must be between 1 and --bin-size ({}), got {}",
+                    self.bin_size, step
+                )));
+            }
+            if self.regions.is_some() {
+                return Err(Error::Config("--step does not apply to --regions".into()));
+            }
+        }
+        if self.extend == Some(0) {
+            return Err(Error::Config("--extend must be greater than zero".into()));
+        }
+        if let Some(length) = self.smooth_length {
+            if length < self.bin_size {
+                return Err(Error::Config(format!(
+                    "--smooth-length must be at least --bin-size ({}), got {}",
+                    self.bin_size, length
+                )));
+            }
+            if self.regions.is_some() {
+                return Err(Error::Config("--smooth-length does not apply to --regions".into()));
+            }
+        }
+        let log2 = match (self.log2, self.pseudocount) {
+            (false, Some(_)) => {
+                return Err(Error::Config("--pseudocount only applies with --log2".into()));
+            }
+            (false, None) => None,
+            // log2(0) is undefined, so empty bins need something added
+            (true, Some(pseudocount)) if !(pseudocount > 0.0 && pseudocount.is_finite()) => {
+                return Err(Error::Config(format!(
+                    "--pseudocount must be a positive number with --log2, got {}",
+                    pseudocount
+                )));
+            }
+            (true, pseudocount) => Some(pseudocount.unwrap_or(1.0)),
+        };
+        if log2.is_some() && self.regions.is_some() {
+            return Err(Error::Config("--log2 does not apply to --regions".into()));
+        }
+        if self.regions.is_some() && self.output_format != OutputFormat::Bigwig {
+            return Err(Error::Config("--output-format does not apply to --regions".into()));
+        }
+        if self.events.is_some() && self.events_stdout {
+            return Err(Error::Config("--events and --events-stdout can't be combined".into()));
+        }
+        if self.max_concurrent == Some(0) {
+            return Err(Error::Config("--max-concurrent must be greater than zero".into()));
+        }
+        let effective_genome_size = self
+            .effective_genome_size
+            .or(self.genome.map(Genome::effective_genome_size));
+        if self.normalize == Normalization::Rpgc && effective_genome_size.is_none() {
+            return Err(Error::Config(
+                "--normalize rpgc requires --effective-genome-size or --genome".into(),
+            ));
+        }
+        if self.per_file_threads == Some(0) {
+            return Err(Error::Config("--per-file-threads must be greater than zero".into()));
+        }
+        if let (Some(min), Some(max)) = (self.lengths.min, self.lengths.max) {
+            if min > max {
+                return Err(Error::Config(format!(
+                    "--min-length ({}) must not exceed --max-length ({})",
+                    min, max
+                )));
+            }
+        }
+        if let Some(pattern) = &self.name_pattern {
+            if pattern.captures_len() < 2 {
+                return Err(Error::Config(
+                    "--name-pattern needs a capture group for the sample name".into(),
+                ));
+            }
+        }
+        let (listed_files, file_groups) = match &self.files_from {
+            Some(path) => read_file_list(path)?,
+            None => (Vec::new(), HashMap::new()),
+        };
+        let group_pattern = match (self.group_pattern, self.pool) {
+            (None, PoolMode::Sum) if !file_groups.is_empty() => None,
+            (None, PoolMode::Sum) => {
+                return Err(Error::Config(
+                    "--pool sum needs a --group-pattern or a --files-from group column to \
+                     group files by"
+                        .into(),
+                ));
+            }
+            (Some(pattern), PoolMode::Sum) if pattern.captures_len() < 2 => {
+                return Err(Error::Config(
+                    "--group-pattern needs a capture group for the group key".into(),
+                ));
+            }
+            (Some(_), PoolMode::None) => {
+                warn!("--group-pattern has no effect without --pool sum");
+                None
+            }
+            (pattern, _) => pattern,
+        };
+        let mut class_names = HashSet::new();
+        for class in &self.size_classes {
+            if !class_names.insert(class.name.as_str()) {
+                return Err(Error::Config(format!(
+                    "Size class name '{}' is used more than once",
+                    class.name
+                )));
+            }
+        }
+        let reports = [&self.qc_report, &self.per_chrom_report, &self.manifest];
+        for report in reports.into_iter().flatten() {
+            ReportFormat::from_path(report)?;
+        }
+        if self.length_histogram_bin == 0 {
+            return Err(Error::Config("--length-histogram-bin must be at least 1".into()));
+        }
+
+        if self.spikein_normalize {
+            if self.spikein_chroms.is_empty() {
+                return Err(Error::Config("--spikein-normalize needs --spikein-chroms".into()));
+            }
+            if self.scale != Scale::None || self.normalize != Normalization::None {
+                return Err(Error::Config(
+                    "--spikein-normalize replaces --scale and --normalize; use only one".into(),
+                ));
+            }
+        }
+        // Spike-in reads only feed the QC report and the scale factors, never the tracks
+        let mut chroms = self.chroms;
+        chroms.exclude.extend(self.spikein_chroms.iter().cloned());
+        if self.exclude_mito {
+            match self.mito_chroms.as_slice() {
+                [] => chroms.exclude.extend(MITO_NAMES.map(String::from)),
+                names => chroms.exclude.extend(names.iter().cloned()),
+            }
+        }
+
+        let bam_filter = BamFilter::new(
+            self.include_flags,
+            self.exclude_flags,
+            self.single_end,
+            self.min_mapq,
+            self.lengths,
+            chroms.clone(),
+            self.dedup,
+        );
+        let progress = self
+            .progress
+            .unwrap_or_else(|| MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
+        Ok(Pipeline {
+            tools: self.tools,
+            chrom_sizes: self.chrom_sizes,
+            blacklist: self.blacklist,
+            outdir: self.outdir,
+            tmp_dir: self.tmp_dir,
+            bin_size: self.bin_size,
+            step: self.step.filter(|&step| step != self.bin_size),
+            smooth_length: self.smooth_length,
+            log2,
+            regions: self.regions,
+            seed: self.seed,
+            threads: self.threads,
+            max_concurrent: self.max_concurrent,
+            per_file_threads: self.per_file_threads,
+            qc: self.qc,
+            target: self.target,
+            no_downsample: self.no_downsample,
+            qc_report: self.qc_report,
+            per_chrom_report: self.per_chrom_report,
+            length_histogram: self.length_histogram,
+            length_histogram_bin: self.length_histogram_bin,
+            length_histogram_full: self.length_histogram_full,
+            manifest: self.manifest,
+            timing: self.timing,
+            events: self.events,
+            events_stdout: self.events_stdout,
+            settings: self.settings,
+            matrix: self.matrix,
+            correlation: self.correlation,
+            correlation_method: self.correlation_method,
+            quantile_normalize: self.quantile_normalize,
+            filter_qc: self.filter_qc,
+            no_header: self.no_header,
+            strict: self.strict,
+            on_bad_coord: self.on_bad_coord,
+            dedup: self.dedup,
+            weighted: self.weighted,
+            bedpe: self.bedpe,
+            low_memory: self.low_memory,
+            chroms,
+            mito_chroms: self.mito_chroms,
+            spikein_chroms: self.spikein_chroms,
+            spikein_normalize: self.spikein_normalize,
+            lengths: self.lengths,
+            bam_filter,
+            no_auto_index: self.no_auto_index,
+            mode: self.mode,
+            normalize: self.normalize,
+            effective_genome_size,
+            split_by_chrom: self.split_by_chrom,
+            scale: self.scale,
+            atac_shift: self.atac_shift,
+            extend: self.extend,
+            name_pattern: self.name_pattern,
+            group_pattern,
+            pool: self.pool,
+            listed_files,
+            file_groups,
+            recursive: self.recursive,
+            size_classes: self.size_classes,
+            stranded: self.stranded,
+            native_bigwig: self.native_bigwig,
+            output_format: self.output_format,
+            keep_bedgraph: self.keep_bedgraph,
+            keep_downsampled_bed: self.keep_downsampled_bed,
+            keep_tmp_bam: self.keep_tmp_bam,
+            exact: self.exact,
+            fail_fast: self.fail_fast,
+            dry_run: self.dry_run,
+            count_only: self.count_only,
+            force: self.force,
+            progress,
+            cleanup: CleanupRegistry::default(),
+        })
+    }
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// Intermediate files of the runs so far, so a Ctrl-C handler can remove them.
+    pub fn cleanup(&self) -> CleanupRegistry {
+        self.cleanup.clone()
+    }
+
+    /// Downsample fragment BED files and write their coverage bigWigs.
+    pub fn run_bed(&self, files: &[PathBuf]) -> Result<RunReport, Error> {
+        self.run(InputType::Bed, files)
+    }
+
+    /// Downsample BAM files and write their coverage bigWigs with bamCoverage.
+    pub fn run_bam(&self, files: &[PathBuf]) -> Result<RunReport, Error> {
+        self.run(InputType::Bam, files)
+    }
+
+    /// Run on a thread pool of the configured size.
+    fn run(&self, input_type: InputType, files: &[PathBuf]) -> Result<RunReport, Error> {
+        let nthreads = if self.threads > 0 {
+            self.threads
+        } else {
+            num_cpus::get()
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(nthreads)
+            .build()?;
+        let inputs: Vec<PathBuf> = files.iter().chain(&self.listed_files).cloned().collect();
+        let files = expand_inputs(&inputs, input_type, self.recursive)?;
+        let events = EventStream::open(self.events.as_deref(), self.events_stdout)?;
+        events.send(Event::RunStarted {
+            input_type,
+            inputs: files.len(),
+        });
+        let started = Instant::now();
+        let result = pool.install(|| self.run_in_pool(input_type, &files, &events));
+        let (failed, processed, skipped) = match &result {
+            Ok(report) => (report.failures(), report.outcomes.len(), report.skipped.len()),
+            Err(_) => (0, 0, 0),
+        };
+        events.send(Event::RunFinished {
+            succeeded: processed - failed,
+            failed,
+            skipped,
+            seconds: started.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(Error::to_string),
+        });
+        let finished = events.finish();
+        let report = result?;
+        finished?;
+        Ok(report)
+    }
+
+    /// Whether BED-mode tracks are converted with bedGraphToBigWig.
+    fn needs_bedgraph_to_bigwig(&self) -> bool {
+        let bigwig = self.output_format != OutputFormat::Bedgraph;
+        bigwig && !self.native_bigwig && self.regions.is_none()
+    }
+
+    /// Whether the samples' track values are kept for `--matrix`, `--correlation` or
+    /// `--quantile-normalize`.
+    fn collects_tracks(&self) -> bool {
+        self.matrix.is_some() || self.correlation.is_some() || self.quantile_normalize
+    }
+
+    /// How the tracks are written: region counts with `--regions`, binned tracks otherwise.
+    fn track_format(&self) -> TrackFormat {
+        match self.regions {
+            Some(_) => TrackFormat::RegionCounts,
+            None => TrackFormat::Bins {
+                bin_size: self.bin_size,
+                step: self.step,
+                output: self.output_format,
+                log2: self.log2.is_some(),
+            },
+        }
+    }
+
+    /// Rough bytes each directory will receive: downsampled BAMs (and BEDs kept with
+    /// --keep-bedgraph or --keep-downsampled-bed) at the input size times the kept
+    /// fraction, and BED tracks at a few bytes per bin for a bigWig or a bedGraph line per
+    /// bin. Compression, empty bins and bamCoverage's own output aren't modelled, so this
+    /// errs low.
+    fn space_needed(
+        &self,
+        input_type: InputType,
+        to_run: &[PlannedSample],
+        members: &HashMap<PathBuf, Vec<PathBuf>>,
+        layout: &OutputLayout,
+        bins: u64,
+    ) -> HashMap<PathBuf, u64> {
+        const BIGWIG_BYTES_PER_BIN: u64 = 4;
+        const BEDGRAPH_BYTES_PER_BIN: u64 = 20;
+        let mut needed: HashMap<PathBuf, u64> = HashMap::new();
+        let mut intermediates = Vec::new();
+        for s in to_run {
+            let files = &members[&s.file];
+            let input: u64 = files
+                .iter()
+                .filter_map(|f| std::fs::metadata(f).ok())
+                .map(|m| m.len())
+                .sum();
+            // A CRAM decodes into a BAM of roughly twice its size
+            let decoded = if files.iter().any(|f| is_cram(f)) { 2 * input } else { input };
+            let mut tmp = (decoded as f64 * s.fraction) as u64;
+            if input_type == InputType::Bam && files.len() > 1 {
+                tmp += decoded; // the merged BAM
+            }
+            intermediates.push((layout.tmp_dir_for(&s.file), tmp));
+            for output in &s.outputs {
+                let bytes = match output.extension().and_then(|e| e.to_str()) {
+                    Some("bw") => bins * BIGWIG_BYTES_PER_BIN,
+                    Some("bedGraph") => bins * BEDGRAPH_BYTES_PER_BIN,
+                    Some("bed") => (input as f64 * s.fraction) as u64,
+                    _ => 0,
+                };
+                let dir = output.parent().unwrap_or(Path::new("")).to_path_buf();
+                *needed.entry(dir).or_default() += bytes;
+            }
+        }
+        // Intermediates are removed as each sample finishes unless kept, so only the largest
+        // of the samples running at once count then
+        match input_type {
+            InputType::Bed if !self.keep_bedgraph => intermediates.clear(),
+            InputType::Bam if !self.keep_tmp_bam => {
+                let concurrency = self
+                    .max_concurrent
+                    .unwrap_or(usize::MAX)
+                    .min(rayon::current_num_threads());
+                intermediates.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+                intermediates.truncate(concurrency);
+            }
+            _ => {}
+        }
+        for (dir, bytes) in intermediates {
+            *needed.entry(dir).or_default() += bytes;
+        }
+        needed
+    }
+
+    /// Fail when the rough estimate of [`Pipeline::space_needed`] is more than the free space
+    /// of a filesystem. Filesystems df can't report on are not checked.
+    fn check_space(
+        &self,
+        input_type: InputType,
+        to_run: &[PlannedSample],
+        members: &HashMap<PathBuf, Vec<PathBuf>>,
+        layout: &OutputLayout,
+        bins: u64,
+    ) -> Result<(), Error> {
+        let mut filesystems: BTreeMap<PathBuf, (u64, u64)> = BTreeMap::new();
+        for (dir, bytes) in self.space_needed(input_type, to_run, members, layout, bins) {
+            if let Some((mount, free)) = free_space(&dir) {
+                let entry = filesystems.entry(mount).or_insert((0, free));
+                entry.0 += bytes;
+            }
+        }
+        for (mount, (needed, free)) in filesystems {
+            debug!(
+                "{}: about {} needed, {} free",
+                mount.display(),
+                format_bytes(needed),
+                format_bytes(free)
+            );
+            if needed > free {
+                return Err(Error::Config(format!(
+                    "Not enough disk space on {}: the run needs about {} but only {} is free \
+                     (free some space, or point --outdir or --tmp-dir elsewhere)",
+                    mount.display(),
+                    format_bytes(needed),
+                    format_bytes(free)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn run_in_pool(
+        &self,
+        input_type: InputType,
+        files: &[PathBuf],
+        events: &EventStream,
+    ) -> Result<RunReport, Error> {
+        if files.is_empty() {
+            return Err(Error::Config("No fragment files provided".into()));
+        }
+        let seed = match self.seed {
+            Some(s) => s,
+            None => {
+                let s = random::<u64>();
+                info!(
+                    "No --seed given, using random seed {} (pass --seed {} to reproduce)",
+                    s, s
+                );
+                s
+            }
+        };
+
+        let required = self
+            .tools
+            .required(input_type, !self.needs_bedgraph_to_bigwig(), !self.count_only);
+        let missing = missing_tools(&required);
+        if !missing.is_empty() {
+            let list: String = missing
+                .iter()
+                .map(|(name, path)| {
+                    format!("\n  {} - install from {}", path.display(), install_hint(name))
+                })
+                .collect();
+            // A dry run still reports what's missing but goes on to show the plan
+            if !self.dry_run {
+                return Err(Error::MissingTools(list));
+            }
+            warn!("{}", Error::MissingTools(list));
+        }
+
+        // Read up front so a bad file fails early, even in BAM mode where bamCoverage uses it
+        let blacklist = self.blacklist.as_deref().map(Blacklist::read).transpose()?;
+
+        // Every chromosome of the chrom sizes or the first BAM's header, before filtering
+        let mut reference_chroms: Option<Vec<String>> = None;
+        // Chromosome order and sizes for BED input, read up front so a bad file fails early
+        let chroms = match input_type {
+            InputType::Bed => {
+                if self.normalize != Normalization::None {
+                    return Err(Error::Config(
+                        "--normalize is only supported for BAM input".into(),
+                    ));
+                }
+                if self.split_by_chrom {
+                    return Err(Error::Config(
+                        "--split-by-chrom is only supported for BAM input".into(),
+                    ));
+                }
+                if self.tools.reference.is_some() {
+                    return Err(Error::Config(
+                        "--reference-fasta is only supported for BAM and CRAM input".into(),
+                    ));
+                }
+                if self.exact {
+                    warn!("--exact only applies to BAM input; BED sampling is always exact");
+                }
+                let path = self.chrom_sizes.as_deref().ok_or_else(|| {
+                    Error::Config("A chromosome sizes file is required for BED input".into())
+                })?;
+                // Filtered chromosomes get no bins, so they are left out of the tracks
+                let mut chrom_list = parse_chrom_sizes(path)?;
+                reference_chroms = Some(chrom_list.iter().map(|(name, _)| name.clone()).collect());
+                if self.chroms.is_active() {
+                    if let Some(include) = &self.chroms.include {
+                        for chrom in include {
+                            if !chrom_list.iter().any(|(name, _)| name == chrom) {
+                                warn!("--chroms: {} is not in {}", chrom, path.display());
+                            }
+                        }
+                    }
+                    chrom_list.retain(|(chrom, _)| self.chroms.keeps(chrom));
+                }
+                let chrom_order = chrom_order(&chrom_list);
+                Some((path, chrom_order, chrom_list))
+            }
+            InputType::Bam => {
+                if self.mode == CoverageMode::Midpoint {
+                    return Err(Error::Config(
+                        "--mode midpoint is only supported for BED input".into(),
+                    ));
+                }
+                if self.scale != Scale::None {
+                    return Err(Error::Config(
+                        "--scale is only supported for BED input; use --normalize for BAM".into(),
+                    ));
+                }
+                if self.step.is_some() {
+                    return Err(Error::Config("--step is only supported for BED input".into()));
+                }
+                if self.weighted {
+                    return Err(Error::Config("--weighted is only supported for BED input".into()));
+                }
+                if self.bedpe {
+                    return Err(Error::Config("--bedpe is only supported for BED input".into()));
+                }
+                if self.exact && !cfg!(feature = "htslib") {
+                    return Err(needs_htslib("--exact"));
+                }
+                if self.length_histogram.is_some() && !cfg!(feature = "htslib") {
+                    return Err(needs_htslib("--length-histogram for BAM input"));
+                }
+                if self.keep_downsampled_bed {
+                    return Err(Error::Config(
+                        "--keep-downsampled-bed is only supported for BED input; \
+                         use --keep-tmp-bam for the downsampled BAMs"
+                            .into(),
+                    ));
+                }
+                // Each part would be normalized on its own chromosome's reads
+                if self.split_by_chrom && self.normalize != Normalization::None {
+                    return Err(Error::Config(
+                        "--split-by-chrom can't be combined with --normalize".into(),
+                    ));
+                }
+                if self.regions.is_some() {
+                    return Err(Error::Config("--regions is only supported for BED input".into()));
+                }
+                if self.log2.is_some() {
+                    return Err(Error::Config("--log2 is only supported for BED input".into()));
+                }
+                if self.collects_tracks() {
+                    return Err(Error::Config(
+                        "--matrix, --correlation and --quantile-normalize are only supported \
+                         for BED input"
+                            .into(),
+                    ));
+                }
+                if self.atac_shift.is_some() {
+                    warn!(
+                        "--atac-shift only applies to BED input; \
+                         shift BAMs with deepTools alignmentSieve --ATACshift"
+                    );
+                }
+                match &self.tools.reference {
+                    Some(reference) if !reference.is_file() => {
+                        return Err(Error::Config(format!(
+                            "--reference-fasta {} is not a file",
+                            reference.display()
+                        )));
+                    }
+                    Some(_) => {}
+                    None => {
+                        if let Some(cram) = files.iter().find(|f| is_cram(f)) {
+                            return Err(Error::Config(format!(
+                                "{} is a CRAM; pass the FASTA it was written against with \
+                                 --reference-fasta",
+                                cram.display()
+                            )));
+                        }
+                    }
+                }
+                debug!("BAM filter: {}", self.bam_filter.samtools_args().join(" "));
+
+                // The first BAM's header gives the chromosome sizes, which catches a --chroms
+                // typo or a --chrom-sizes file from another reference build
+                if missing.is_empty() {
+                    let header = read_bam_chroms(&self.tools, &files[0])?;
+                    reference_chroms = Some(header.iter().map(|(name, _)| name.clone()).collect());
+                    if header.is_empty() {
+                        warn!("{}: no @SQ lines in the BAM header", files[0].display());
+                    } else {
+                        debug!("{}: {} chromosomes in header", files[0].display(), header.len());
+                        if let Some(include) = &self.chroms.include {
+                            for chrom in include {
+                                if !header.iter().any(|(name, _)| name == chrom) {
+                                    warn!("--chroms: {} is not in the BAM header", chrom);
+                                }
+                            }
+                        }
+                        if let Some(path) = self.chrom_sizes.as_deref() {
+                            let sizes = parse_chrom_sizes(path)?;
+                            warn_chrom_mismatches(&files[0], &header, path, &sizes);
+                        }
+                    }
+                    self.prepare_bam_inputs(files)?;
+                }
+                None
+            }
+        };
+
+        // Regions are counted in chrom sizes order, so an unsorted file is sorted here
+        let regions = match (&self.regions, &chroms) {
+            (Some(path), Some((_, chrom_order, _))) => {
+                let mut regions = read_regions(path)?;
+                let total = regions.len();
+                regions.retain(|region| chrom_order.contains_key(&region.chrom));
+                if regions.len() < total {
+                    warn!(
+                        "{}: dropped {} region(s) on chromosomes not in the chrom sizes or \
+                         excluded by --chroms",
+                        path.display(),
+                        total - regions.len()
+                    );
+                }
+                if regions.is_empty() {
+                    return Err(Error::Config(format!(
+                        "No usable regions in {}",
+                        path.display()
+                    )));
+                }
+                regions.sort_by_key(|r| (chrom_order[&r.chrom], r.start, r.end));
+                info!("Counting fragments over {} regions", regions.len());
+                Some(regions)
+            }
+            _ => None,
+        };
+
+        // Intermediates live in a per-run temp directory that is removed when it goes out of
+        // scope, unless the user asked to keep them. A dry run creates nothing.
+        let keep_intermediates = match input_type {
+            InputType::Bed => self.keep_bedgraph,
+            InputType::Bam => self.keep_tmp_bam,
+        };
+        let tmp_parent = self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let tmp_root = if self.dry_run || self.count_only {
+            None
+        } else {
+            std::fs::create_dir_all(&tmp_parent).map_err(Error::io(&tmp_parent))?;
+            let tmp_root = tempfile::Builder::new()
+                .prefix("bedfragment_ds.")
+                .disable_cleanup(keep_intermediates)
+                .tempdir_in(&tmp_parent)
+                .map_err(Error::io(&tmp_parent))?;
+            if keep_intermediates {
+                info!("Keeping intermediate files in {}", tmp_root.path().display());
+            } else {
+                self.cleanup.register(tmp_root.path().to_path_buf());
+            }
+            Some(tmp_root)
+        };
+
+        let names = sample_names(files, self.name_pattern.as_ref(), &self.file_groups);
+        let groups = match self.pool {
+            PoolMode::Sum => &self.file_groups,
+            PoolMode::None => &HashMap::new(),
+        };
+        let pools = pool_samples(files, self.group_pattern.as_ref(), groups, &names);
+        for (name, files) in pools.iter().filter(|(_, files)| files.len() > 1) {
+            let files: Vec<_> = files.iter().map(|f| f.display().to_string()).collect();
+            info!("Pooling {} files into {}: {}", files.len(), name, files.join(", "));
+        }
+        // Each sample is identified by its first file from here on
+        let samples: Vec<PathBuf> = pools.iter().map(|(_, files)| files[0].clone()).collect();
+        let names: HashMap<PathBuf, String> = pools
+            .iter()
+            .map(|(name, files)| (files[0].clone(), name.clone()))
+            .collect();
+        let members: HashMap<PathBuf, Vec<PathBuf>> = pools
+            .into_iter()
+            .map(|(_, files)| (files[0].clone(), files))
+            .collect();
+        let _ = events.names.set(names.clone());
+        let layout = OutputLayout::new(
+            self.outdir.clone(),
+            tmp_root.as_ref().map_or(tmp_parent, |t| t.path().to_path_buf()),
+            names.clone(),
+            self.cleanup.clone(),
+        );
+
+        // Output and temp directories are checked before the counting pass, which can take a
+        // while on large inputs
+        if tmp_root.is_some() {
+            let mut dirs: Vec<PathBuf> =
+                samples.iter().flat_map(|f| [layout.dir_for(f), layout.tmp_dir_for(f)]).collect();
+            dirs.extend(self.outdir.clone());
+            dirs.sort();
+            dirs.dedup();
+            for dir in &dirs {
+                check_writable(dir)?;
+            }
+        }
+
+        let per_chrom = self.per_chrom_report.is_some();
+        // Without a downsampled set to take them from, the lengths come from the counting pass
+        let full_lengths = (self.length_histogram.is_some()
+            && (self.length_histogram_full || self.count_only))
+            .then_some(self.length_histogram_bin);
+        let sampled_lengths = (self.length_histogram.is_some() && full_lengths.is_none())
+            .then(|| LengthHistograms::new(self.length_histogram_bin));
+        let mito_chroms = mito_names(&self.mito_chroms, reference_chroms.as_deref());
+        let qc_regions = QcRegions {
+            mito: &mito_chroms,
+            spikein: &self.spikein_chroms,
+            blacklist: blacklist.as_ref(),
+            blacklist_path: self.blacklist.as_deref(),
+        };
+        let chrom_lengths: HashMap<String, u32> = match &chroms {
+            Some((_, _, chrom_list)) => chrom_list.iter().cloned().collect(),
+            None => HashMap::new(),
+        };
+        let bed_filter = BedLineFilter {
+            no_header: self.no_header,
+            chroms: &self.chroms,
+            lengths: &self.lengths,
+            dedup: self.dedup,
+            chrom_lengths: &chrom_lengths,
+            on_bad_coord: self.on_bad_coord,
+            weighted: self.weighted,
+            bedpe: self.bedpe,
+        };
+        let timings = Timings::new(events);
+        let counts = count_samples(
+            &samples,
+            &members,
+            &self.progress,
+            &timings,
+            |f| match input_type {
+                InputType::Bed => {
+                    let strict = self.strict;
+                    count_fragments(f, &bed_filter, per_chrom, &qc_regions, strict, full_lengths)
+                }
+                InputType::Bam => {
+                    let filter = &self.bam_filter;
+                    let mut count =
+                        count_bam_fragments(&self.tools, f, filter, &qc_regions, full_lengths)?;
+                    if per_chrom {
+                        count.per_chrom = bam_chrom_counts(&self.tools, f)?;
+                    }
+                    Ok(count)
+                }
+            },
+        )
+        .map_err(count_errors)?;
+        if let Some(path) = &self.per_chrom_report {
+            if self.dry_run {
+                info!("Dry run: not writing {}", path.display());
+            } else {
+                write_chrom_report(path, &counts, &names)?;
+                info!("Wrote per-chromosome counts to {}", path.display());
+            }
+        }
+        if let (Some(path), Some(width)) = (&self.length_histogram, full_lengths) {
+            if self.dry_run {
+                info!("Dry run: not writing {}", path.display());
+            } else {
+                let histograms: Vec<(&Path, &LengthHistogram)> =
+                    counts.iter().map(|(f, c)| (f.as_path(), &c.lengths)).collect();
+                write_length_histogram(path, &histograms, &names, width)?;
+                info!("Wrote fragment-length histograms to {}", path.display());
+            }
+        }
+        let malformed_lines: HashMap<PathBuf, usize> = counts
+            .iter()
+            .filter(|(_, c)| c.malformed > 0)
+            .map(|(f, c)| (f.clone(), c.malformed))
+            .collect();
+        let qc = self.qc_samples(&counts, &names)?;
+        let mut plan = DownsamplePlan::new(
+            &qc,
+            &self.target,
+            !self.no_downsample,
+            &layout,
+            &Track::all(&self.size_classes, self.stranded),
+            self.track_format(),
+            self.keep_downsampled_bed,
+        )?;
+        if self.quantile_normalize {
+            let format = self.track_format();
+            let tracks = Track::all(&self.size_classes, self.stranded);
+            for s in &mut plan.samples {
+                for track in &tracks {
+                    let name = qnorm_track_name(track.name().as_deref());
+                    for suffix in format.suffixes(Some(&name), !plan.downsample) {
+                        s.outputs.push(layout.path(&s.file, &suffix));
+                    }
+                }
+            }
+        }
+        let spikein_factors = if self.spikein_normalize {
+            spikein_factors(&plan, &counts)?
+        } else {
+            HashMap::new()
+        };
+        if self.count_only {
+            let outputs = [&self.manifest, &self.matrix, &self.correlation, &self.timing];
+            for path in outputs.into_iter().flatten() {
+                info!("Count only: not writing {}", path.display());
+            }
+            return Ok(RunReport {
+                seed,
+                qc,
+                plan,
+                outcomes: Vec::new(),
+                skipped: Vec::new(),
+                scale_factors: HashMap::new(),
+                malformed_lines,
+                draws: HashMap::new(),
+                timings: timings.take(),
+            });
+        }
+        // Samples whose bigWigs are all there already are skipped, so an interrupted run
+        // can be resumed. The matrix, correlations and a histogram of the sampled lengths
+        // need every sample's values, so nothing is skipped then.
+        let rerun_all = self.force || self.collects_tracks() || sampled_lengths.is_some();
+        let (skipped, to_run): (Vec<PlannedSample>, Vec<PlannedSample>) =
+            plan.samples.iter().cloned().partition(|s| !rerun_all && s.outputs_exist());
+        let skipped: Vec<PathBuf> = skipped.into_iter().map(|s| s.file).collect();
+        for file in &skipped {
+            info!(
+                "{}: outputs already exist, skipping (use --force to regenerate)",
+                file_label(file)
+            );
+        }
+        if self.dry_run {
+            plan.log();
+            let sampled_histogram =
+                self.length_histogram.as_ref().filter(|_| full_lengths.is_none());
+            let outputs = [&self.manifest, &self.matrix, &self.correlation, &self.timing];
+            for path in outputs.into_iter().flatten().chain(sampled_histogram) {
+                info!("Dry run: not writing {}", path.display());
+            }
+            return Ok(RunReport {
+                seed,
+                qc,
+                plan,
+                outcomes: Vec::new(),
+                skipped,
+                scale_factors: HashMap::new(),
+                malformed_lines,
+                draws: HashMap::new(),
+                timings: timings.take(),
+            });
+        }
+
+        let bins = chroms.as_ref().map_or(0, |(_, _, chrom_list)| {
+            let step = self.step.unwrap_or(self.bin_size) as u64;
+            chrom_list.iter().map(|(_, len)| (*len as u64).div_ceil(step)).sum()
+        });
+        self.check_space(input_type, &to_run, &members, &layout, bins)?;
+
+        // bedGraphToBigWig only reads two columns, so a .fai index is passed as a trimmed copy
+        let trimmed_sizes = match &chroms {
+            Some((path, _, chrom_list))
+                if self.needs_bedgraph_to_bigwig() && has_extra_columns(path)? =>
+            {
+                let trimmed = layout.tmpdir.join("chrom.sizes");
+                {
+                    let write_err = Error::io(&trimmed);
+                    let mut writer = BufWriter::new(create_file(&trimmed)?);
+                    for (chrom, len) in chrom_list {
+                        writeln!(writer, "{}\t{}", chrom, len).map_err(&write_err)?;
+                    }
+                    writer.flush().map_err(&write_err)?;
+                }
+                debug!("Wrote two-column chrom sizes to {}", trimmed.display());
+                Some(trimmed)
+            }
+            _ => None,
+        };
+
+        // Only BED input has chromosome sizes
+        let process = || match &chroms {
+            Some((chrom_sizes, chrom_order, chrom_list)) => {
+                let ctx = BedContext {
+                    tools: &self.tools,
+                    layout: &layout,
+                    members: &members,
+                    chrom_sizes: trimmed_sizes.as_deref().unwrap_or(chrom_sizes),
+                    chrom_order,
+                    chrom_list,
+                    chrom_lengths: &chrom_lengths,
+                    atac_shift: self.atac_shift.as_ref(),
+                    extend: self.extend,
+                    mode: self.mode,
+                    bin_size: self.bin_size,
+                    step: self.step.unwrap_or(self.bin_size),
+                    smooth_length: self.smooth_length,
+                    log2: self.log2,
+                    format: self.track_format(),
+                    regions: regions.as_deref(),
+                    seed,
+                    target: plan.target,
+                    downsample: plan.downsample,
+                    no_header: self.no_header,
+                    dedup: self.dedup,
+                    on_bad_coord: self.on_bad_coord,
+                    weighted: self.weighted,
+                    bedpe: self.bedpe,
+                    low_memory: self.low_memory,
+                    chroms: &self.chroms,
+                    blacklist: blacklist.as_ref(),
+                    lengths: self.lengths,
+                    size_classes: &self.size_classes,
+                    stranded: self.stranded,
+                    scale: self.scale,
+                    spikein_factors: &spikein_factors,
+                    native_bigwig: self.native_bigwig,
+                    keep_intermediates: self.keep_bedgraph,
+                    keep_downsampled_bed: self.keep_downsampled_bed,
+                    matrix: self.collects_tracks(),
+                    timings: &timings,
+                    length_histograms: sampled_lengths.as_ref(),
+                };
+                // Sampling and sorting, then coverage and bigWig for each track
+                let tracks = Track::all(&self.size_classes, self.stranded).len() as u64;
+                let results = run_samples(
+                    &to_run,
+                    &self.progress,
+                    events,
+                    self.fail_fast,
+                    |_| 2 + 2 * tracks,
+                    |sample, pb| process_bed_sample(&ctx, sample, pb),
+                );
+                let mut scale_factors = HashMap::new();
+                let mut draws = HashMap::new();
+                let mut columns = Vec::new();
+                let mut sources = Vec::new();
+                let outcomes = results
+                    .into_iter()
+                    .map(|(file, result)| {
+                        let result = result.map(|outcome| {
+                            if self.scale != Scale::None || self.spikein_normalize {
+                                scale_factors.insert(file.clone(), outcome.scale);
+                            }
+                            draws.insert(file.clone(), outcome.draw);
+                            for (class, values) in outcome.columns {
+                                let name = match &class {
+                                    Some(class) => format!("{}_{}", names[&file], class),
+                                    None => names[&file].clone(),
+                                };
+                                columns.push((name, values));
+                                sources.push((file.clone(), class));
+                            }
+                        });
+                        (file, result)
+                    })
+                    .collect();
+                // Failed samples have no column; excluded ones were never processed
+                if let Some(path) = &self.matrix {
+                    let step = ctx.step;
+                    write_atomic(path, |partial| {
+                        write_matrix(partial, ctx.regions, chrom_list, step, &columns)
+                            .map_err(Error::io(path))
+                    })?;
+                    info!("Wrote a matrix of {} track(s) to {}", columns.len(), path.display());
+                }
+                if let Some(path) = &self.correlation {
+                    write_atomic(path, |partial| {
+                        write_correlation(partial, &columns, self.correlation_method)
+                            .map_err(Error::io(path))
+                    })?;
+                    info!("Wrote track correlations to {}", path.display());
+                }
+                if self.quantile_normalize {
+                    let values = columns.into_iter().map(|(_, values)| values);
+                    let tracks = sources.into_iter().zip(values);
+                    write_qnorm_tracks(&ctx, tracks.map(|((f, n), v)| (f, n, v)).collect())?;
+                }
+                Ok::<_, Error>((outcomes, scale_factors, draws))
+            }
+            None => {
+                // Threads left over when there are fewer samples than workers go to the
+                // external tools of each sample
+                let concurrency = self
+                    .max_concurrent
+                    .unwrap_or(usize::MAX)
+                    .min(rayon::current_num_threads())
+                    .min(to_run.len())
+                    .max(1);
+                let threads = self
+                    .per_file_threads
+                    .unwrap_or(rayon::current_num_threads() / concurrency)
+                    .max(1);
+                debug!("Using {} threads per sample for samtools and bamCoverage", threads);
+                let ctx = BamContext {
+                    tools: &self.tools,
+                    filter: &self.bam_filter,
+                    layout: &layout,
+                    members: &members,
+                    blacklist: self.blacklist.as_deref(),
+                    bin_size: self.bin_size,
+                    output_format: self.output_format,
+                    seed,
+                    downsample: plan.downsample,
+                    target: plan.target,
+                    exact: self.exact,
+                    size_classes: &self.size_classes,
+                    stranded: self.stranded,
+                    mode: self.mode,
+                    normalize: self.normalize,
+                    effective_genome_size: self.effective_genome_size,
+                    smooth_length: self.smooth_length,
+                    extend: self.extend,
+                    spikein_factors: &spikein_factors,
+                    split_by_chrom: self.split_by_chrom,
+                    keep_intermediates: self.keep_tmp_bam,
+                    threads,
+                    timings: &timings,
+                    length_histograms: sampled_lengths.as_ref(),
+                };
+                let outcomes = run_samples(
+                    &to_run,
+                    &self.progress,
+                    events,
+                    self.fail_fast,
+                    |s| ctx.stages(&s.file),
+                    |s, pb| process_bam_sample(&ctx, s, pb),
+                );
+                let mut draws = HashMap::new();
+                let outcomes: Vec<SampleOutcome> = outcomes
+                    .into_iter()
+                    .map(|(file, result)| {
+                        let result = result.map(|draw| {
+                            draws.insert(file.clone(), draw);
+                        });
+                        (file, result)
+                    })
+                    .collect();
+                let scale_factors = outcomes
+                    .iter()
+                    .filter(|(_, result)| result.is_ok())
+                    .filter_map(|(file, _)| Some((file.clone(), *spikein_factors.get(file)?)))
+                    .collect();
+                Ok((outcomes, scale_factors, draws))
+            }
+        };
+        // Each sample runs external tools, so with --max-concurrent the samples get a smaller
+        // pool of their own rather than one worker per thread
+        let (outcomes, scale_factors, draws) = match self.max_concurrent {
+            Some(n) if n < rayon::current_num_threads() => {
+                info!("Processing at most {} samples at a time", n);
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()?
+                    .install(process)
+            }
+            _ => process(),
+        }?;
+
+        let report = RunReport {
+            seed,
+            qc,
+            plan,
+            outcomes,
+            skipped,
+            scale_factors,
+            malformed_lines,
+            draws,
+            timings: timings.take(),
+        };
+        if let (Some(path), Some(lengths)) = (&self.length_histogram, &sampled_lengths) {
+            let samples = lengths.take();
+            // A failed sample may have got as far as its histogram
+            let histograms: Vec<(&Path, &LengthHistogram)> = report
+                .outcomes
+                .iter()
+                .filter(|(_, result)| result.is_ok())
+                .filter_map(|(file, _)| Some((file.as_path(), samples.get(file)?)))
+                .collect();
+            write_length_histogram(path, &histograms, &names, lengths.width)?;
+            info!("Wrote fragment-length histograms to {}", path.display());
+        }
+        if let Some(path) = &self.manifest {
+            Manifest::new(&report, &members, self.bin_size, &self.settings).write(path)?;
+            info!("Wrote manifest to {}", path.display());
+        }
+        if let Some(path) = &self.timing {
+            report.log_timing();
+            report.write_timing(path)?;
+            info!("Wrote stage timings to {}", path.display());
+        }
+        Ok(report)
+    }
+
+    /// Check that every input BAM is coordinate-sorted and indexed. A missing index is built
+    /// with `samtools index` next to the BAM, or is an error with `--no-auto-index`; a dry
+    /// run only reports it.
+    fn prepare_bam_inputs(&self, files: &[PathBuf]) -> Result<(), Error> {
+        files.par_iter().try_for_each(|file| {
+            match read_bam_sort_order(&self.tools, file)?.as_deref() {
+                Some("coordinate") => {}
+                Some(order) => {
+                    return Err(Error::Config(format!(
+                        "{} is sorted by {}, not by coordinate; sort it first with \
+                         samtools sort -o sorted.bam {}",
+                        file.display(),
+                        order,
+                        file.display()
+                    )));
+                }
+                None => warn!(
+                    "{}: the header doesn't give a sort order; assuming it is coordinate-sorted",
+                    file.display()
+                ),
+            }
+            if let Some(index) = find_bam_index(file) {
+                debug!("{}: using index {}", file.display(), index.display());
+                return Ok(());
+            }
+            if self.no_auto_index {
+                return Err(Error::Config(format!(
+                    "{} is not indexed; run samtools index {}, or drop --no-auto-index",
+                    file.display(),
+                    file.display()
+                )));
+            }
+            if self.dry_run {
+                info!("Dry run: {} is not indexed and would be indexed", file.display());
+                return Ok(());
+            }
+            info!("{}: not indexed, running samtools index", file.display());
+            self.tools.run_step(
+                Command::new(&self.tools.samtools).arg("index").arg(file),
+                "samtools index",
+            )
+        })
+    }
+
+    /// Log the per-sample counts, run QC on them, and log and write its result.
+    fn qc_samples(
+        &self,
+        counts: &[(PathBuf, FragmentCount)],
+        names: &HashMap<PathBuf, String>,
+    ) -> Result<QcResult, Error> {
+        let mut qc_counts = Vec::new();
+        for (f, c) in counts {
+            debug!("{}: {} fragments", f.display(), c.total());
+            log_filtered(f, &self.chroms, &self.lengths, c);
+            if c.malformed > 0 {
+                warn!(
+                    "{}: skipped {} malformed line(s) (use --strict to stop on the first)",
+                    f.display(),
+                    c.malformed
+                );
+            }
+            if c.bad_coords > 0 {
+                let action = match self.on_bad_coord {
+                    BadCoords::Clamp => "clamped to the chromosome, or skipped if empty",
+                    _ => "skipped",
+                };
+                warn!(
+                    "{}: {} fragment(s) empty or past the chromosome end, {}",
+                    f.display(),
+                    c.bad_coords,
+                    action
+                );
+            }
+            if c.interchromosomal > 0 {
+                warn!(
+                    "{}: dropped {} BEDPE pair(s) with mates on different chromosomes",
+                    f.display(),
+                    c.interchromosomal
+                );
+            }
+            qc_counts.push((f.clone(), qc_count(c, self.filter_qc)));
+        }
+        // Only filtered fragments are sampled, so unfiltered counts overstate what each
+        // sample can reach
+        let filtered = counts.iter().filter(|(_, c)| c.kept < c.total()).count();
+        if !self.filter_qc && filtered > 0 {
+            warn!(
+                "The chromosome or length filters removed fragments from {} sample(s), but QC \
+                 and the downsampling target use the unfiltered counts, so those samples keep \
+                 fewer fragments than the target; add --filter-qc to use the filtered counts",
+                filtered
+            );
+        }
+        let mut qc = run_qc(&qc_counts, &self.qc, names);
+        // Shares of every counted fragment, before the chromosome and length filters
+        for (s, (_, c)) in qc.samples.iter_mut().zip(counts) {
+            let total = c.total().max(1) as f64;
+            s.mito_fraction = c.mito as f64 / total;
+            s.blacklist_fraction = self.blacklist.is_some().then(|| c.blacklisted as f64 / total);
+            let with_duplicates = (c.total() + c.duplicates).max(1) as f64;
+            s.duplicate_fraction = self.dedup.then(|| c.duplicates as f64 / with_duplicates);
+            s.spikein_fragments = (!self.spikein_chroms.is_empty()).then_some(c.spikein);
+        }
+        print_qc(&qc);
+        if let Some(report) = &self.qc_report {
+            if self.dry_run {
+                info!("Dry run: not writing the QC report to {}", report.display());
+            } else {
+                write_qc_report(report, &qc)?;
+            }
+        }
+        Ok(qc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn reservoir_keeps_each_line_at_k_over_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("population.bed");
+        let lines: String = (0..100).map(|i| format!("chr1\t{}\t{}\n", i, i + 10)).collect();
+        std::fs::write(&path, format!("chrom\tstart\tend\n{}", lines)).unwrap();
+        let (k, trials) = (10, 5000);
+        let mut rng = StdRng::seed_from_u64(42);
+        let chrom_lengths = HashMap::from([("chr1".to_string(), 1000)]);
+        let filter = BedLineFilter {
+            no_header: false,
+            chroms: &ChromFilter::default(),
+            lengths: &LengthFilter::default(),
+            dedup: false,
+            chrom_lengths: &chrom_lengths,
+            on_bad_coord: BadCoords::Clamp,
+            weighted: false,
+            bedpe: false,
+        };
+        let mut kept = [0usize; 100];
+        for _ in 0..trials {
+            let paths = std::slice::from_ref(&path);
+            let start = |_, _, line: &str| line.split('\t').nth(1).unwrap().parse::<usize>();
+            let (header, starts) =
+                reservoir_sample(paths, Some(k), &filter, &mut rng, start).unwrap();
+            assert_eq!(header.as_deref(), Some("chrom\tstart\tend"));
+            let starts: Vec<usize> = starts.into_iter().map(Result::unwrap).collect();
+            let distinct: std::collections::HashSet<usize> = starts.iter().copied().collect();
+            assert_eq!(distinct.len(), k);
+            for i in starts {
+                kept[i] += 1;
+            }
+        }
+        // Each line is expected 500 times, with a binomial SD of about 21
+        let expected = (trials * k / 100) as f64;
+        for (line, &count) in kept.iter().enumerate() {
+            let off = (count as f64 - expected).abs() / expected;
+            assert!(off < 0.25, "line {} kept {} times, expected {}", line, count, expected);
+        }
+    }
+
+    #[test]
+    fn mean_and_sd_edge_cases() {
+        assert_eq!(mean(&[]), None);
+        assert_eq!(std_dev(&[], 0.0, SdType::Population), None);
+        assert_eq!(std_dev(&[], 0.0, SdType::Sample), None);
+        // One library has no sample SD, but a population SD of zero
+        assert_eq!(mean(&[7]), Some(7.0));
+        assert_eq!(std_dev(&[7], 7.0, SdType::Sample), None);
+        assert_eq!(std_dev(&[7], 7.0, SdType::Population), Some(0.0));
+        let equal = [100, 100, 100, 100];
+        assert_eq!(mean(&equal), Some(100.0));
+        assert_eq!(std_dev(&equal, 100.0, SdType::Sample), Some(0.0));
+        assert_eq!(std_dev(&equal, 100.0, SdType::Population), Some(0.0));
+        // Squared deviations 4 and 4 over N = 2 or N - 1 = 1
+        let values = [1, 5];
+        assert_eq!(mean(&values), Some(3.0));
+        assert_eq!(std_dev(&values, 3.0, SdType::Population), Some(2.0));
+        assert_eq!(std_dev(&values, 3.0, SdType::Sample), Some(8f64.sqrt()));
+    }
+
+    #[test]
+    fn gzipped_bed_reads_like_plain() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut text = "chrom\tstart\tend\n".to_string();
+        for i in 0..60 {
+            text += &format!("chr1\t{}\t{}\n", i * 10, i * 10 + 50);
+        }
+        let plain = dir.path().join("sample.bed");
+        std::fs::write(&plain, &text).unwrap();
+        // Two gzip members back to back, as written by bgzip or `cat a.gz b.gz`
+        let (first, second) = text.split_at(text.len() / 2);
+        let mut gz = Vec::new();
+        for part in [first, second] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            gz.extend(encoder.finish().unwrap());
+        }
+        let gzipped = dir.path().join("sample.bed.gz");
+        std::fs::write(&gzipped, gz).unwrap();
+
+        let chrom_lengths = HashMap::from([("chr1".to_string(), 1000)]);
+        let filter = BedLineFilter {
+            no_header: false,
+            chroms: &ChromFilter::default(),
+            lengths: &LengthFilter::default(),
+            dedup: false,
+            chrom_lengths: &chrom_lengths,
+            on_bad_coord: BadCoords::Clamp,
+            weighted: false,
+            bedpe: false,
+        };
+        let regions = QcRegions::default();
+        let count = |path| {
+            let count = count_fragments(path, &filter, false, &regions, true, None);
+            count.unwrap().kept
+        };
+        assert_eq!(count(&plain), 60);
+        assert_eq!(count(&gzipped), 60);
+        for k in [10, 60] {
+            let sample = |path: &PathBuf| {
+                let paths = std::slice::from_ref(path);
+                let mut rng = StdRng::seed_from_u64(7);
+                reservoir_sample(paths, Some(k), &filter, &mut rng, |_, _, line| line.to_string())
+            };
+            assert_eq!(sample(&plain).unwrap(), sample(&gzipped).unwrap());
+        }
+    }
+
+    #[test]
+    fn samtools_subsample_strings() {
+        assert_eq!(samtools_subsample_arg(42, 1.0), None);
+        assert_eq!(samtools_subsample_arg(42, 1.5), None);
+        assert_eq!(samtools_subsample_arg(42, 0.25).as_deref(), Some("42.25"));
+        assert_eq!(samtools_subsample_arg(7, 0.123456789).as_deref(), Some("7.123456789"));
+        // Tiny fractions keep their digits instead of rounding to `.000`
+        assert_eq!(samtools_subsample_arg(42, 0.0004).as_deref(), Some("42.0004"));
+        assert_eq!(samtools_subsample_arg(42, 1e-12).as_deref(), Some("42.000000001"));
+        assert_eq!(samtools_subsample_arg(42, 0.9999999999).as_deref(), Some("42.999999999"));
+        // Seeds past i32 range wrap into it
+        assert_eq!(samtools_seed(i32::MAX as u64), 0);
+        assert_eq!(samtools_seed(i32::MAX as u64 + 5), 5);
+        assert_eq!(samtools_seed(u64::MAX), 3);
+        assert_eq!(samtools_subsample_arg(u64::MAX, 0.5).as_deref(), Some("3.5"));
+    }
+
+    #[test]
+    fn output_names_drop_input_extensions() {
+        assert_eq!(sample_stem(Path::new("data/sample.bed")), "sample");
+        assert_eq!(sample_stem(Path::new("data/sample.bed.gz")), "sample");
+        assert_eq!(sample_stem(Path::new("data/sample.bam")), "sample");
+        assert_eq!(sample_stem(Path::new("data/sample.cram")), "sample");
+        assert_eq!(sample_stem(Path::new("data/s.rep1.bed")), "s.rep1");
+        assert_eq!(sample_stem(Path::new("data/s.rep1.txt")), "s.rep1.txt");
+        assert_eq!(sample_stem(Path::new(".bed")), ".bed");
+
+        // Each input laid out on its own, so no stems collide
+        let layout = |file: &str| {
+            let files = [PathBuf::from(file)];
+            let names = sample_names(&files, None, &HashMap::new());
+            let layout = OutputLayout::new(
+                Some(PathBuf::from("out")),
+                PathBuf::from("tmp"),
+                names,
+                CleanupRegistry::default(),
+            );
+            (layout, files[0].clone())
+        };
+        for file in ["data/sample.bed", "data/sample.bed.gz"] {
+            let (layout, file) = layout(file);
+            let bed = layout.path(&file, "downsampled.bed");
+            assert_eq!(bed, Path::new("out/sample_downsampled.bed"));
+            let track = layout.path(&file, "50bp.bedGraph");
+            assert_eq!(track, Path::new("out/sample_50bp.bedGraph"));
+            let tmp = layout.tmp_path(&file, "sorted.bed");
+            assert_eq!(tmp, Path::new("tmp/sample_sorted.bed"));
+        }
+        for file in ["data/sample.bam", "data/sample.cram"] {
+            let (layout, file) = layout(file);
+            let bam = layout.path(&file, "downsampled.bam");
+            assert_eq!(bam, Path::new("out/sample_downsampled.bam"));
+            // Not `sample.bam_downsampled.bam`, which only disambiguates colliding stems
+            let file_name = file.file_name().unwrap().to_string_lossy();
+            assert_ne!(bam, Path::new("out").join(format!("{}_downsampled.bam", file_name)));
+        }
+        let (layout, file) = layout("data/s.rep1.bed");
+        let bed = layout.path(&file, "downsampled.bed");
+        assert_eq!(bed, Path::new("out/s.rep1_downsampled.bed"));
+        let track = layout.path(&file, "50bp.bedGraph");
+        assert_eq!(track, Path::new("out/s.rep1_50bp.bedGraph"));
+    }
+
+    fn sizes() -> Vec<(String, u32)> {
+        vec![("chrA".to_string(), 250), ("chrB".to_string(), 100)]
+    }
+
+    #[test]
+    fn bin_counts_at_boundaries() {
+        let fragments = [
+            // Spans the boundary at 100, so counts in bins 0 and 1
+            ("chrA", 90, 110, 1.0),
+            // Ends exactly on the boundary at 200, so only counts in bin 1
+            ("chrA", 100, 200, 1.0),
+            // Runs past the chromosome end into the partial last bin
+            ("chrA", 230, 260, 2.0),
+            ("chrZ", 0, 50, 1.0),
+        ];
+        let sizes = sizes();
+        let counts = compute_bin_counts(fragments, &sizes, 100, 100);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["chrA"], [1.0, 2.0, 2.0]);
+        // A chromosome without fragments still gets its bins, all zero
+        assert_eq!(counts["chrB"], [0.0]);
+        let records: Vec<_> = bin_records(&counts, &sizes, 100).collect();
+        assert_eq!(
+            records,
+            [
+                ("chrA", 0, 100, 1.0),
+                ("chrA", 100, 200, 2.0),
+                ("chrA", 200, 250, 2.0),
+                ("chrB", 0, 100, 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn bin_counts_with_sliding_bins() {
+        // 100bp bins every 50bp, each padded 25bp either side: bin i spans
+        // [50i - 25, 50i + 75)
+        let fragments = [("chrA", 0, 10, 1.0), ("chrA", 80, 90, 1.0), ("chrA", 240, 250, 1.0)];
+        let sizes = sizes();
+        let counts = compute_bin_counts(fragments, &sizes, 100, 50);
+        assert_eq!(counts["chrA"], [1.0, 1.0, 1.0, 0.0, 1.0]);
+        assert_eq!(counts["chrB"], [0.0, 0.0]);
+        let ends: Vec<_> = bin_records(&counts, &sizes, 50).map(|(_, _, end, _)| end).collect();
+        assert_eq!(ends, [50, 100, 150, 200, 250, 50, 100]);
+    }
+
+    fn test_data(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+    }
+
+    /// The name column of each line, which is unique in `unsorted.bed`
+    fn names<S: AsRef<str>>(lines: &[S]) -> Vec<&str> {
+        lines.iter().map(|line| line.as_ref().split('\t').nth(3).unwrap()).collect()
+    }
+
+    #[test]
+    fn bed_lines_sort_by_chrom_start_end_strand() {
+        let order = chrom_order(&parse_chrom_sizes(&test_data("chrom.sizes")).unwrap());
+        let bed = std::fs::read_to_string(test_data("unsorted.bed")).unwrap();
+        let mut lines: Vec<&str> = bed.lines().collect();
+        sort_bed_lines(&mut lines, &order);
+        // Equal starts order by end, then `+` before `-`; tie2 and tie1 are fully tied apart
+        // from their names and keep their input order
+        assert_eq!(names(&lines), ["a", "tie2", "tie1", "d", "c", "f", "g", "e"]);
+
+        let available = Command::new("bedtools")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        if !available {
+            eprintln!("bedtools not found, skipping");
+            return;
+        }
+        let output = Command::new("bedtools")
+            .arg("sort")
+            .arg("-faidx")
+            .arg(test_data("chrom.sizes"))
+            .arg("-i")
+            .arg(test_data("unsorted.bed"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        // bedtools leaves the order of equal starts unspecified, so compare up to the start
+        let key = |line: &str| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            (fields[0].to_string(), fields[1].to_string())
+        };
+        let expected: Vec<_> = String::from_utf8(output.stdout).unwrap().lines().map(key).collect();
+        assert_eq!(lines.iter().map(|line| key(line)).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn unknown_chroms_sort_naturally_after_known() {
+        assert!(natural_key("chrUn2") < natural_key("chrUn10"));
+        assert!(natural_key("chr2") < natural_key("chr10"));
+        assert!(natural_key("chr1") < natural_key("chr1_random"));
+        let order = chrom_order(&[("chr2".to_string(), 500), ("chr1".to_string(), 1000)]);
+        let mut lines = vec![
+            "chrUn10\t5\t10",
+            "chr1\t0\t10",
+            "chrUn2\t50\t60",
+            "chrUn10\t1\t10",
+            "chr2\t100\t110",
+            "chrUn2\t7\t10",
+            "chrM\t0\t10",
+        ];
+        sort_bed_lines(&mut lines, &order);
+        // Known chromosomes in chrom sizes order, then each unknown one as a block, never
+        // interleaved by start
+        assert_eq!(
+            lines,
+            [
+                "chr2\t100\t110",
+                "chr1\t0\t10",
+                "chrM\t0\t10",
+                "chrUn2\t7\t10",
+                "chrUn2\t50\t60",
+                "chrUn10\t1\t10",
+                "chrUn10\t5\t10",
+            ]
+        );
+    }
+
+    #[cfg(feature = "htslib")]
+    #[test]
+    fn exact_downsample_keeps_whole_pairs() {
+        use rust_htslib::bam::{self, header::HeaderRecord, Read};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut header = bam::Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"SO", "coordinate"));
+        let mut chrom = HeaderRecord::new(b"SQ");
+        header.push_record(chrom.push_tag(b"SN", "chr1").push_tag(b"LN", 50_000));
+        let view = bam::HeaderView::from_header(&header);
+        // 300 properly paired templates, mates 150bp apart, so pairs interleave once sorted
+        let (seq, qual) = ("A".repeat(50), "I".repeat(50));
+        let mut sam = Vec::new();
+        for i in 0..300 {
+            let (pos, mpos) = (100 * i + 1, 100 * i + 151);
+            let mate = |flag, pos, mpos, tlen| {
+                let fields = format!("{}\tchr1\t{}\t60\t50M\t=\t{}\t{}", flag, pos, mpos, tlen);
+                format!("p{}\t{}\t{}\t{}", i, fields, seq, qual)
+            };
+            sam.push((pos, mate(99, pos, mpos, 200)));
+            sam.push((mpos, mate(147, mpos, pos, -200)));
+        }
+        sam.sort_by_key(|(pos, _)| *pos);
+        let input = dir.path().join("pairs.bam");
+        let mut writer = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+        for (_, line) in &sam {
+            writer.write(&bam::Record::from_sam(&view, line.as_bytes()).unwrap()).unwrap();
+        }
+        drop(writer);
+
+        let filter = BamFilter::new(
+            None,
+            None,
+            false,
+            0,
+            LengthFilter::default(),
+            ChromFilter::default(),
+            false,
+        );
+        let output = dir.path().join("downsampled.bam");
+        exact_downsample_bam(&Tools::default(), &input, &output, &filter, 100, 7).unwrap();
+        let mut mates: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut positions = Vec::new();
+        for record in bam::Reader::from_path(&output).unwrap().records() {
+            let record = record.unwrap();
+            *mates.entry(record.qname().to_vec()).or_default() += 1;
+            positions.push(record.pos());
+        }
+        assert_eq!(positions.len(), 100);
+        assert_eq!(mates.len(), 50);
+        assert!(mates.values().all(|&n| n == 2), "unpaired mates in the sample");
+        assert!(positions.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
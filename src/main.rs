@@ -1,498 +1,1209 @@
-use clap::{Parser, ValueEnum};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use rand::random;
+use bedfragment_ds::{
+    parse_size_class, parse_target, read_chrom_list, write_bins, BadCoords, CorrelationMethod,
+    CoverageMode, Genome, InputType, Normalization, OutputFormat, Pipeline, PipelineBuilder,
+    PoolMode, QcMethod, QcMode, QcParams, Scale, SdType, SizeClass, Target,
+};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use log::{debug, error, info, warn, LevelFilter};
 use regex::Regex;
-use std::collections::HashMap;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::Arc;
-
-#[derive(ValueEnum, Clone)]
-enum InputType {
-    Bed,
-    Bam,
-}
+use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[clap(name = "bedfragment_ds", version = "6.3")]
-struct Args {
-    /// Input mode: 'bed' (default) or 'bam'
-    #[clap(long, value_enum, default_value_t = InputType::Bed)]
-    input_type: InputType,
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-    /// Chromosome sizes file (required if input_type=bed)
-    #[clap(long, required_if_eq("input_type", "bed"))]
-    chrom_sizes: Option<PathBuf>,
+    /// Increase log verbosity (-v for debug, which also streams the external tools' stderr,
+    /// -vv for trace; RUST_LOG overrides)
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
 
-    /// Optional blacklist BED file (only used if input_type=bam)
-    #[clap(long)]
-    blacklist: Option<PathBuf>,
+    /// Log progress as plain lines instead of drawing progress bars. Bars are also left out
+    /// when stderr isn't a terminal, e.g. in a scheduler's log file
+    #[clap(long, global = true)]
+    no_progress: bool,
 
-    /// Fragment BED or BAM files to process
+    /// Print a completion script for the given shell to stdout and exit
+    #[clap(long, value_enum, value_name = "SHELL")]
+    generate_completions: Option<Shell>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Downsample fragment BED files and write binned coverage tracks
+    Bed(BedArgs),
+    /// Downsample paired-end BAM or CRAM files and write coverage tracks with bamCoverage
+    Bam(BamArgs),
+    /// Count fragments and run the library-size QC without producing tracks
+    Qc(QcArgs),
+    /// Write the genome bins BED mode counts into, as a BED file
+    Bins(BinsArgs),
+}
+
+/// Inputs, counting filters, QC and reports, shared by `bed`, `bam` and `qc`.
+#[derive(Args)]
+struct CommonArgs {
+    /// Fragment BED, BAM or CRAM files to process; quoted glob patterns ('data/*.bed') and
+    /// directories are expanded to the input files they hold
     files: Vec<PathBuf>,
 
+    /// Read settings from this TOML file, one `long_option_name = value` per option (e.g.
+    /// `bin_size = 100`, `dedup = true`); options given on the command line or through their
+    /// environment variable take precedence
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Also process the files listed in this file, one per line; an optional second,
+    /// tab-separated column gives the sample name, or with --pool sum the group
+    #[clap(long)]
+    files_from: Option<PathBuf>,
+
+    /// Search directory inputs for input files in their subdirectories too
+    #[clap(short, long)]
+    recursive: bool,
+
+    /// Optional blacklist BED file; fragments overlapping it are left out of the tracks
+    #[clap(long)]
+    blacklist: Option<PathBuf>,
+
     /// Z-score threshold for excluding low-yield libraries (default 1.5)
     #[clap(short, long, default_value = "1.5")]
     exclude_sd: f64,
 
-    /// Keep intermediate bedGraph files (only in bed mode)
+    /// Outlier method: 'zscore' (default), 'mad' or 'iqr'; --exclude-sd is used as the multiplier k
+    #[clap(long, value_enum, default_value_t = QcMethod::Zscore)]
+    qc_method: QcMethod,
+
+    /// Standard deviation for the zscore method: 'population' (default, divides by N) or
+    /// 'sample' (divides by N - 1)
+    #[clap(long, value_enum, default_value_t = SdType::Population)]
+    sd_type: SdType,
+
+    /// Absolute minimum fragment count; samples below it are excluded before the outlier test
     #[clap(long)]
-    keep_bedgraph: bool,
+    min_fragments: Option<usize>,
+
+    /// QC mode: 'lower' (default) drops low-yield outliers, 'both' also drops high-yield ones
+    #[clap(long, value_enum, default_value_t = QcMode::Lower)]
+    qc_mode: QcMode,
+
+    /// Downsampling target: 'min' (default, the smallest passing library), 'median',
+    /// 'percentile:<p>' or 'count:<n>'; samples below it keep all their fragments
+    #[clap(long, value_parser = parse_target, default_value = "min")]
+    target: Target,
 
-    /// Whether to keep temporary downsampled BAM files (only for BAM input)
+    /// Downsample every sample to the fragment count of this reference library, given by
+    /// input file name or sample name (instead of --target)
+    #[clap(long, conflicts_with = "target")]
+    reference: Option<String>,
+
+    /// Directory for all outputs (created if needed; default: next to each input file)
     #[clap(long)]
-    keep_tmp_bam: bool,
+    outdir: Option<PathBuf>,
+
+    /// Drop fragments shorter than this many bp (BED end - start, BAM |TLEN|)
+    #[clap(long)]
+    min_length: Option<u64>,
+
+    /// Drop fragments longer than this many bp (BED end - start, BAM |TLEN|)
+    #[clap(long)]
+    max_length: Option<u64>,
+
+    /// Only process these chromosomes: a comma-separated list or a file with one name per
+    /// line
+    #[clap(long)]
+    chroms: Option<String>,
+
+    /// Drop these chromosomes: a comma-separated list or a file with one name per line
+    #[clap(long)]
+    exclude_chroms: Option<String>,
+
+    /// Name of the mitochondrial chromosome, whose share of fragments is reported in the QC
+    /// (comma-separate several); by default whichever of chrM, MT, chrMT and M the chrom sizes
+    /// or BAM header list
+    #[clap(long, alias = "mito-chroms", value_delimiter = ',')]
+    mito_name: Vec<String>,
+
+    /// Leave the mitochondrial chromosome out of the tracks, like --exclude-chroms
+    #[clap(long)]
+    exclude_mito: bool,
+
+    /// Spike-in chromosomes (e.g. E. coli or yeast contigs): a comma-separated list or a file
+    /// with one name per line. Their fragments are reported in the QC and left out of the
+    /// tracks
+    #[clap(long)]
+    spikein_chroms: Option<String>,
+
+    /// Run QC and pick the downsampling target on chromosome and length filtered fragment
+    /// counts
+    #[clap(long)]
+    filter_qc: bool,
+
+    /// Drop duplicate fragments before counting and downsampling: exact repeats of chrom,
+    /// start, end and strand in BED files, reads flagged as duplicates (-F 1024) in BAM files
+    #[clap(long)]
+    dedup: bool,
+
+    /// Retry a failed samtools, bamCoverage or bedGraphToBigWig run up to this many times,
+    /// with a short pause in between, before failing the sample
+    #[clap(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Regex whose first capture group, matched against each input file name, gives the
+    /// sample name used for outputs and QC rows (e.g. '(.*)_S\d+_L\d+')
+    #[clap(long, value_parser = Regex::new)]
+    name_pattern: Option<Regex>,
+
+    /// Regex whose first capture group, matched against each input file name, defines the
+    /// replicate group a file belongs to (used with --pool sum)
+    #[clap(long, value_parser = Regex::new)]
+    group_pattern: Option<Regex>,
+
+    /// How to combine files of the same group: 'none' keeps them separate, 'sum' pools
+    /// them into one sample that is downsampled and tracked as a whole
+    #[clap(long, value_enum, default_value_t = PoolMode::None)]
+    pool: PoolMode,
 
     /// Number of threads (0 = use all available cores)
     #[clap(short = 't', long, default_value = "0")]
     threads: usize,
+
+    /// Random seed for downsampling (a random seed is chosen and reported if omitted)
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Write a QC report of per-file counts and pass/fail (.json or .tsv)
+    #[clap(long)]
+    qc_report: Option<PathBuf>,
+
+    /// Write each sample's fragments per chromosome and their share of the total (.json or
+    /// .tsv); BAMs must be indexed
+    #[clap(long)]
+    per_chrom_report: Option<PathBuf>,
+
+    /// Write each sample's fragment-length histogram to this TSV (sample, length_bin,
+    /// count), from the downsampled fragments; BAM input needs the htslib feature
+    #[clap(long)]
+    length_histogram: Option<PathBuf>,
+
+    /// Width in bp of the --length-histogram bins
+    #[clap(long, default_value = "10")]
+    length_histogram_bin: u64,
+
+    /// Build --length-histogram from the full library rather than the downsampled fragments
+    /// (always the case for qc)
+    #[clap(long)]
+    length_histogram_full: bool,
+
+    /// Write a manifest of every input, its sample name, fragment count, QC status,
+    /// fraction and output bigWigs, with the run parameters (.json or .tsv)
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Write each sample's wall-clock time per stage (counting, sampling, sort, coverage,
+    /// writing, and the samtools steps for BAMs) to this TSV, and log the totals per stage
+    #[clap(long)]
+    timing: Option<PathBuf>,
+
+    /// Write newline-delimited JSON progress events (run, sample and stage starts and ends,
+    /// with timestamps) to this file, for workflow managers and dashboards
+    #[clap(long)]
+    events: Option<PathBuf>,
+
+    /// Write the JSON progress events to stdout instead of a file
+    #[clap(long, conflicts_with = "events")]
+    events_stdout: bool,
+}
+
+/// Downsampling and track options, shared by `bed` and `bam`.
+#[derive(Args)]
+struct TrackArgs {
+    /// Write coverage from every fragment without downsampling (outputs are named e.g.
+    /// sample_full_50bp.bw); QC still runs and excludes outliers
+    #[clap(long)]
+    no_downsample: bool,
+
+    /// Parent directory for the per-run temp directory holding intermediates (default: $TMPDIR)
+    #[clap(long)]
+    tmp_dir: Option<PathBuf>,
+
+    /// Bin size in bp for coverage tracks (default 50)
+    #[clap(long, default_value = "50")]
+    bin_size: usize,
+
+    /// What each fragment contributes to the coverage track
+    #[clap(long, value_enum, default_value_t = CoverageMode::Fragment)]
+    mode: CoverageMode,
+
+    /// Average each bin with its neighbours over a window of this many bp (at least
+    /// --bin-size) for smoother tracks; bamCoverage --smoothLength in BAM mode
+    #[clap(long)]
+    smooth_length: Option<usize>,
+
+    /// Extend single-end reads to this many bp in their 3' direction before coverage:
+    /// bamCoverage --extendReads in BAM mode, stranded lines (strand in column 6) in BED mode
+    #[clap(long)]
+    extend: Option<u64>,
+
+    /// Write one bigWig per fragment length class, e.g. nucfree=0-120,mono=150-300
+    #[clap(long, value_delimiter = ',', value_parser = parse_size_class)]
+    size_classes: Vec<SizeClass>,
+
+    /// Write a forward and a reverse strand track per sample (named _fwd and _rev), from the
+    /// BED strand column or with bamCoverage --filterRNAstrand
+    #[clap(long)]
+    stranded: bool,
+
+    /// Track file format: 'bigwig' (default), 'bedgraph' or 'both'
+    #[clap(long, value_enum, default_value_t = OutputFormat::Bigwig)]
+    output_format: OutputFormat,
+
+    /// Scale each sample's coverage by its spike-in fragments (see --spikein-chroms) instead
+    /// of by library size, so the sample with the fewest spike-in fragments is left as is
+    #[clap(long)]
+    spikein_normalize: bool,
+
+    /// Maximum number of samples processed at the same time (default: one per thread). Each
+    /// runs its own external tools, so this caps the number of child processes
+    #[clap(long)]
+    max_concurrent: Option<usize>,
+
+    /// Stop starting new samples after the first failure
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Count and QC the inputs and print the downsampling plan (target, per-sample
+    /// fractions, output paths) without processing or writing anything
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Regenerate samples whose bigWigs already exist (by default they are skipped, so an
+    /// interrupted run can be resumed)
+    #[clap(long)]
+    force: bool,
 }
 
-fn mean(values: &[usize]) -> f64 {
-    values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64
+/// How BED files are read, shared by `bed` and `qc`.
+#[derive(Args)]
+struct BedReadArgs {
+    /// Treat the first line of BED files as data (by default it is auto-detected as a header
+    /// when its start/end columns aren't integers)
+    #[clap(long)]
+    no_header: bool,
+
+    /// Fail on the first malformed BED line instead of skipping it with a warning
+    #[clap(long)]
+    strict: bool,
+
+    /// What to do with fragments that are empty (start = end) or run past the chromosome end
+    /// in the chrom sizes: cut them at the end, drop them, or stop with an error
+    #[clap(long, value_enum, default_value_t = BadCoords::Clamp)]
+    on_bad_coord: BadCoords,
+
+    /// Inputs are BEDPE mate pairs; each is read as the fragment from its leftmost start to
+    /// its rightmost end, and pairs across chromosomes are dropped
+    #[clap(long)]
+    bedpe: bool,
 }
 
-fn std_dev(values: &[usize], mean: f64) -> f64 {
-    let var = values.iter().map(|&v| {
-        let diff = v as f64 - mean;
-        diff * diff
-    }).sum::<f64>() / values.len() as f64;
-    var.sqrt()
+/// Which BAM records count as fragments, shared by `bam` and `qc`.
+#[derive(Args)]
+struct BamReadArgs {
+    /// Minimum mapping quality for BAM reads, applied to both counting and downsampling
+    #[clap(long, default_value = "0")]
+    min_mapq: u8,
+
+    /// SAM flags a BAM read must have (samtools -f; default 2, or 0 with --single-end)
+    #[clap(long)]
+    include_flags: Option<u16>,
+
+    /// SAM flags a BAM read must not have (samtools -F; default 260)
+    #[clap(long)]
+    exclude_flags: Option<u16>,
+
+    /// BAM input is single-end: don't require reads to be properly paired
+    #[clap(long)]
+    single_end: bool,
+
+    /// Fail on an input BAM without a .bai/.csi (or CRAM without a .crai) instead of
+    /// indexing it with samtools index
+    #[clap(long)]
+    no_auto_index: bool,
+
+    /// Reference FASTA the CRAM inputs were written against, passed to samtools to decode
+    /// them (required for .cram files)
+    #[clap(long)]
+    reference_fasta: Option<PathBuf>,
+
+    /// samtools executable
+    #[clap(long, env = "SAMTOOLS_PATH", default_value = "samtools")]
+    samtools_path: PathBuf,
 }
 
-fn parse_chrom_order(chrom_sizes: &PathBuf) -> Result<HashMap<String, usize>, Box<dyn Error>> {
-    let file = File::open(chrom_sizes)?;
-    let reader = BufReader::new(file);
-    let mut map = HashMap::new();
-    for (i, line) in reader.lines().enumerate() {
-        let line = line?;
-        if line.trim().is_empty() {
-            continue;
-        }
-        let chrom = line.split_whitespace().next().unwrap().to_string();
-        map.insert(chrom, i);
+#[derive(Args)]
+struct BedArgs {
+    #[clap(flatten)]
+    common: CommonArgs,
+
+    #[clap(flatten)]
+    tracks: TrackArgs,
+
+    #[clap(flatten)]
+    read: BedReadArgs,
+
+    /// Chromosome sizes file or .fai index
+    #[clap(long, required_unless_present = "config")]
+    chrom_sizes: Option<PathBuf>,
+
+    /// Hold sampled byte offsets instead of lines and read the picked lines back in a second
+    /// pass; less memory for deep libraries at the cost of reading inputs twice
+    #[clap(long)]
+    low_memory: bool,
+
+    /// Keep the intermediate downsampled BED and bedGraph files
+    #[clap(long)]
+    keep_bedgraph: bool,
+
+    /// Write each sample's downsampled, sorted fragments to the output directory as
+    /// <sample>_downsampled.bed
+    #[clap(long)]
+    keep_downsampled_bed: bool,
+
+    /// Write bigWigs natively instead of via bedGraphToBigWig
+    #[clap(long)]
+    native_bigwig: bool,
+
+    /// Scaling of bin counts: 'cpm' gives counts per million sampled fragments, 'target'
+    /// scales every sample to the downsampling target
+    #[clap(long, value_enum, default_value_t = Scale::None)]
+    scale: Scale,
+
+    /// Sum the score (fifth) column of the fragments in each bin instead of counting them,
+    /// for pre-weighted fragment files
+    #[clap(long)]
+    weighted: bool,
+
+    /// Apply the ATAC-seq Tn5 shift to fragments before computing coverage
+    #[clap(long)]
+    atac_shift: bool,
+
+    /// Shift in bp for plus-strand ends with --atac-shift
+    #[clap(long, default_value = "4", allow_negative_numbers = true)]
+    shift_plus: i64,
+
+    /// Shift in bp for minus-strand ends with --atac-shift
+    #[clap(long, default_value = "-5", allow_negative_numbers = true)]
+    shift_minus: i64,
+
+    /// Slide the bins by this many bp so they overlap, for smoother tracks (at most
+    /// --bin-size)
+    #[clap(long)]
+    step: Option<usize>,
+
+    /// Write log2(count + pseudocount) of each bin instead of the count, for signal with a
+    /// wide dynamic range; outputs are named e.g. sample_50bp_log2.bw
+    #[clap(long, conflicts_with = "regions")]
+    log2: bool,
+
+    /// Pseudocount added to each bin before --log2, which must be positive [default: 1]
+    #[clap(long, requires = "log2")]
+    pseudocount: Option<f64>,
+
+    /// BED file of regions (e.g. peaks) to count fragments over, written as a counts TSV per
+    /// sample instead of a bigWig
+    #[clap(long, conflicts_with = "step")]
+    regions: Option<PathBuf>,
+
+    /// Also write a TSV matrix of bins (or regions) by samples, for differential analysis
+    #[clap(long)]
+    matrix: Option<PathBuf>,
+
+    /// Write the pairwise correlations of the samples' tracks to this TSV, for clustering
+    /// replicates
+    #[clap(long)]
+    correlation: Option<PathBuf>,
+
+    /// How --correlation compares the tracks
+    #[clap(long, value_enum, default_value_t = CorrelationMethod::Pearson)]
+    correlation_method: CorrelationMethod,
+
+    /// Also write quantile-normalized tracks with a _qnorm suffix, giving every sample the same
+    /// distribution of bin values; holds all the samples' bins in memory
+    #[clap(long)]
+    quantile_normalize: bool,
+
+    /// bedGraphToBigWig executable
+    #[clap(long, env = "BEDGRAPHTOBIGWIG_PATH", default_value = "bedGraphToBigWig")]
+    bedgraphtobigwig_path: PathBuf,
+}
+
+#[derive(Args)]
+struct BamArgs {
+    #[clap(flatten)]
+    common: CommonArgs,
+
+    #[clap(flatten)]
+    tracks: TrackArgs,
+
+    #[clap(flatten)]
+    read: BamReadArgs,
+
+    /// Chromosome sizes file or .fai index, checked against the first BAM's header
+    #[clap(long)]
+    chrom_sizes: Option<PathBuf>,
+
+    /// Normalization of the tracks, passed to bamCoverage --normalizeUsing
+    #[clap(long, value_enum, default_value_t = Normalization::None)]
+    normalize: Normalization,
+
+    /// Effective genome size in bp for bamCoverage (required with --normalize rpgc)
+    #[clap(long)]
+    effective_genome_size: Option<u64>,
+
+    /// Genome build whose standard deepTools effective genome size to use; an explicit
+    /// --effective-genome-size wins
+    #[clap(long, value_enum)]
+    genome: Option<Genome>,
+
+    /// Whether to keep temporary downsampled BAM files
+    #[clap(long)]
+    keep_tmp_bam: bool,
+
+    /// Downsample to exactly the target read count by reservoir sampling whole read pairs,
+    /// instead of samtools' probabilistic -s (slower, and holds the sampled read names in
+    /// memory; needs the htslib feature)
+    #[clap(long)]
+    exact: bool,
+
+    /// Threads for each samtools/bamCoverage call (default: the threads left over when
+    /// there are fewer samples than threads, shared out among them)
+    #[clap(long)]
+    per_file_threads: Option<usize>,
+
+    /// Run bamCoverage once per chromosome in parallel and join the parts into each track,
+    /// to use every thread on a few large BAMs
+    #[clap(long)]
+    split_by_chrom: bool,
+
+    /// bamCoverage executable
+    #[clap(long, env = "BAMCOVERAGE_PATH", default_value = "bamCoverage")]
+    bamcoverage_path: PathBuf,
+}
+
+#[derive(Args)]
+struct QcArgs {
+    #[clap(flatten)]
+    common: CommonArgs,
+
+    #[clap(flatten)]
+    bed: BedReadArgs,
+
+    #[clap(flatten)]
+    bam: BamReadArgs,
+
+    /// Input mode: 'bed' (default) or 'bam' (BAM or CRAM)
+    #[clap(long, value_enum, default_value_t = InputType::Bed)]
+    input_type: InputType,
+
+    /// Chromosome sizes file or .fai index (required for BED input)
+    #[clap(long)]
+    chrom_sizes: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct BinsArgs {
+    /// Chromosome sizes file or .fai index
+    #[clap(long)]
+    chrom_sizes: PathBuf,
+
+    /// Bin size in bp (default 50)
+    #[clap(long, default_value = "50")]
+    bin_size: usize,
+
+    /// Write the bins to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl CommonArgs {
+    fn apply(&self, pipeline: PipelineBuilder) -> PipelineBuilder {
+        let chrom_list = |spec: &Option<String>| spec.as_deref().map(read_chrom_list).transpose();
+        let chroms = or_exit(chrom_list(&self.chroms));
+        let exclude_chroms = or_exit(chrom_list(&self.exclude_chroms)).unwrap_or_default();
+        let spikein_chroms = or_exit(chrom_list(&self.spikein_chroms)).unwrap_or_default();
+        pipeline
+            .blacklist(self.blacklist.clone())
+            .outdir(self.outdir.clone())
+            .seed(self.seed)
+            .threads(self.threads)
+            .qc(QcParams {
+                exclude_sd: self.exclude_sd,
+                method: self.qc_method,
+                mode: self.qc_mode,
+                min_fragments: self.min_fragments,
+                sd_type: self.sd_type,
+            })
+            .target(match &self.reference {
+                Some(reference) => Target::Reference(reference.clone()),
+                None => self.target.clone(),
+            })
+            .qc_report(self.qc_report.clone())
+            .per_chrom_report(self.per_chrom_report.clone())
+            .length_histogram(self.length_histogram.clone())
+            .length_histogram_bin(self.length_histogram_bin)
+            .length_histogram_full(self.length_histogram_full)
+            .manifest(self.manifest.clone())
+            .timing(self.timing.clone())
+            .events(self.events.clone())
+            .events_stdout(self.events_stdout)
+            .filter_qc(self.filter_qc)
+            .dedup(self.dedup)
+            .retries(self.retries)
+            .chroms(chroms, exclude_chroms)
+            .mito_chroms(self.mito_name.clone())
+            .exclude_mito(self.exclude_mito)
+            .spikein_chroms(spikein_chroms)
+            .fragment_lengths(self.min_length, self.max_length)
+            .name_pattern(self.name_pattern.clone())
+            .group_pattern(self.group_pattern.clone())
+            .pool(self.pool)
+            .files_from(self.files_from.clone())
+            .recursive(self.recursive)
     }
-    Ok(map)
 }
 
-fn count_fragments(path: &PathBuf) -> Result<usize, Box<dyn Error>> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut count = 0usize;
-    for (i, line) in reader.lines().enumerate() {
-        if i == 0 {
-            continue; // skip header
-        }
-        if !line?.trim().is_empty() {
-            count += 1;
-        }
+impl TrackArgs {
+    fn apply(&self, pipeline: PipelineBuilder) -> PipelineBuilder {
+        pipeline
+            .no_downsample(self.no_downsample)
+            .tmp_dir(self.tmp_dir.clone())
+            .bin_size(self.bin_size)
+            .mode(self.mode)
+            .smooth_length(self.smooth_length)
+            .extend(self.extend)
+            .size_classes(self.size_classes.clone())
+            .stranded(self.stranded)
+            .output_format(self.output_format)
+            .spikein_normalize(self.spikein_normalize)
+            .max_concurrent(self.max_concurrent)
+            .fail_fast(self.fail_fast)
+            .dry_run(self.dry_run)
+            .force(self.force)
     }
-    Ok(count)
-}
-
-fn reservoir_sample(
-    path: &PathBuf,
-    min_count: usize,
-) -> Result<(String, Vec<String>), Box<dyn Error>> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut header = String::new();
-    reader.read_line(&mut header)?;
-    header = header.trim_end().to_string();
-
-    let mut sample: Vec<String> = Vec::with_capacity(min_count);
-    for (i, line) in reader.lines().enumerate() {
-        let line = line?;
-        if i < min_count {
-            sample.push(line);
-        } else {
-            let j = random::<usize>() % (i + 1);
-            if j < min_count {
-                sample[j] = line;
-            }
-        }
+}
+
+impl BedReadArgs {
+    fn apply(&self, pipeline: PipelineBuilder) -> PipelineBuilder {
+        pipeline
+            .no_header(self.no_header)
+            .strict(self.strict)
+            .on_bad_coord(self.on_bad_coord)
+            .bedpe(self.bedpe)
     }
-    Ok((header, sample))
 }
 
-fn create_50bp_bins(chrom_sizes: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
-    let bins_path = PathBuf::from("genome_50bp_bins.bed");
-    if bins_path.exists() && bins_path.metadata()?.len() > 0 {
-        return Ok(bins_path);
+impl BamReadArgs {
+    fn apply(&self, pipeline: PipelineBuilder) -> PipelineBuilder {
+        pipeline
+            .samtools(&self.samtools_path)
+            .min_mapq(self.min_mapq)
+            .include_flags(self.include_flags)
+            .exclude_flags(self.exclude_flags)
+            .single_end(self.single_end)
+            .no_auto_index(self.no_auto_index)
+            .reference_fasta(self.reference_fasta.clone())
     }
-    let status = Command::new("bedtools")
-        .args(["makewindows", "-g"])
-        .arg(chrom_sizes)
-        .args(["-w", "50"])
-        .stdout(File::create(&bins_path)?)
-        .status()?;
-    if !status.success() {
-        return Err("bedtools makewindows failed".into());
+}
+
+impl BedArgs {
+    fn builder(&self) -> PipelineBuilder {
+        let pipeline = Pipeline::builder()
+            .bedgraph_to_bigwig(&self.bedgraphtobigwig_path)
+            .low_memory(self.low_memory)
+            .keep_bedgraph(self.keep_bedgraph)
+            .keep_downsampled_bed(self.keep_downsampled_bed)
+            .native_bigwig(self.native_bigwig)
+            .scale(self.scale)
+            .weighted(self.weighted)
+            .step(self.step)
+            .log2(self.log2)
+            .pseudocount(self.pseudocount)
+            .regions(self.regions.clone())
+            .matrix(self.matrix.clone())
+            .correlation(self.correlation.clone())
+            .correlation_method(self.correlation_method)
+            .quantile_normalize(self.quantile_normalize);
+        let pipeline = match &self.chrom_sizes {
+            Some(chrom_sizes) => pipeline.chrom_sizes(chrom_sizes),
+            None => pipeline,
+        };
+        let pipeline = if self.atac_shift {
+            pipeline.atac_shift(self.shift_plus, self.shift_minus)
+        } else {
+            pipeline
+        };
+        let pipeline = self.read.apply(self.tracks.apply(pipeline));
+        self.common.apply(pipeline)
     }
-    if bins_path.metadata()?.len() == 0 {
-        return Err("bedtools makewindows produced empty bins file".into());
+}
+
+impl BamArgs {
+    fn builder(&self) -> PipelineBuilder {
+        let pipeline = Pipeline::builder()
+            .bam_coverage(&self.bamcoverage_path)
+            .normalize(self.normalize)
+            .effective_genome_size(self.effective_genome_size)
+            .genome(self.genome)
+            .keep_tmp_bam(self.keep_tmp_bam)
+            .exact(self.exact)
+            .per_file_threads(self.per_file_threads)
+            .split_by_chrom(self.split_by_chrom);
+        let pipeline = match &self.chrom_sizes {
+            Some(chrom_sizes) => pipeline.chrom_sizes(chrom_sizes),
+            None => pipeline,
+        };
+        let pipeline = self.read.apply(self.tracks.apply(pipeline));
+        self.common.apply(pipeline)
     }
-    Ok(bins_path)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+impl QcArgs {
+    fn builder(&self) -> PipelineBuilder {
+        let pipeline = Pipeline::builder().count_only(true);
+        let pipeline = match &self.chrom_sizes {
+            Some(chrom_sizes) => pipeline.chrom_sizes(chrom_sizes),
+            None => pipeline,
+        };
+        let pipeline = self.bam.apply(self.bed.apply(pipeline));
+        self.common.apply(pipeline)
+    }
+}
 
-    let nthreads = if args.threads > 0 {
-        args.threads
-    } else {
-        num_cpus::get()
+/// Unwrap `result`, or log the error and exit with status 1.
+fn or_exit<T>(result: Result<T, impl Display>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Logger that suspends the progress bars while writing, so log lines don't tear them.
+struct ProgressLogger {
+    inner: env_logger::Logger,
+    progress: MultiProgress,
+}
+
+impl log::Log for ProgressLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.inner.matches(record) {
+            self.progress.suspend(|| self.inner.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn init_logging(verbose: u8, progress: MultiProgress) -> Result<(), Box<dyn Error>> {
+    let level = match verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
     };
+    let inner = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(ProgressLogger { inner, progress }))?;
+    Ok(())
+}
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(nthreads)
-        .build_global()
-        .unwrap();
+/// Options of a `--config` file, named like the long options (with `_` or `-`). Any of them
+/// may be left out; unknown keys are an error.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    // Global options
+    verbose: Option<u8>,
+    no_progress: Option<bool>,
 
-    if args.files.is_empty() {
-        eprintln!("No fragment files provided.");
-        std::process::exit(1);
+    // CommonArgs
+    #[serde(deserialize_with = "paths")]
+    files: Option<Vec<PathBuf>>,
+    files_from: Option<PathBuf>,
+    recursive: Option<bool>,
+    blacklist: Option<PathBuf>,
+    exclude_sd: Option<f64>,
+    #[serde(deserialize_with = "choice")]
+    qc_method: Option<QcMethod>,
+    #[serde(deserialize_with = "choice")]
+    sd_type: Option<SdType>,
+    min_fragments: Option<usize>,
+    #[serde(deserialize_with = "choice")]
+    qc_mode: Option<QcMode>,
+    #[serde(deserialize_with = "target")]
+    target: Option<Target>,
+    reference: Option<String>,
+    outdir: Option<PathBuf>,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    #[serde(deserialize_with = "name_list")]
+    chroms: Option<String>,
+    #[serde(deserialize_with = "name_list")]
+    exclude_chroms: Option<String>,
+    #[serde(deserialize_with = "names")]
+    mito_name: Option<Vec<String>>,
+    exclude_mito: Option<bool>,
+    #[serde(deserialize_with = "name_list")]
+    spikein_chroms: Option<String>,
+    filter_qc: Option<bool>,
+    dedup: Option<bool>,
+    retries: Option<u32>,
+    #[serde(deserialize_with = "regex")]
+    name_pattern: Option<Regex>,
+    #[serde(deserialize_with = "regex")]
+    group_pattern: Option<Regex>,
+    #[serde(deserialize_with = "choice")]
+    pool: Option<PoolMode>,
+    threads: Option<usize>,
+    seed: Option<u64>,
+    qc_report: Option<PathBuf>,
+    per_chrom_report: Option<PathBuf>,
+    length_histogram: Option<PathBuf>,
+    length_histogram_bin: Option<u64>,
+    length_histogram_full: Option<bool>,
+    manifest: Option<PathBuf>,
+    timing: Option<PathBuf>,
+    events: Option<PathBuf>,
+    events_stdout: Option<bool>,
+
+    // TrackArgs
+    no_downsample: Option<bool>,
+    tmp_dir: Option<PathBuf>,
+    bin_size: Option<usize>,
+    #[serde(deserialize_with = "choice")]
+    mode: Option<CoverageMode>,
+    smooth_length: Option<usize>,
+    extend: Option<u64>,
+    #[serde(deserialize_with = "size_classes")]
+    size_classes: Option<Vec<SizeClass>>,
+    stranded: Option<bool>,
+    #[serde(deserialize_with = "choice")]
+    output_format: Option<OutputFormat>,
+    spikein_normalize: Option<bool>,
+    max_concurrent: Option<usize>,
+    fail_fast: Option<bool>,
+    dry_run: Option<bool>,
+    force: Option<bool>,
+
+    // BedReadArgs
+    no_header: Option<bool>,
+    strict: Option<bool>,
+    #[serde(deserialize_with = "choice")]
+    on_bad_coord: Option<BadCoords>,
+    bedpe: Option<bool>,
+
+    // BamReadArgs
+    min_mapq: Option<u8>,
+    include_flags: Option<u16>,
+    exclude_flags: Option<u16>,
+    single_end: Option<bool>,
+    no_auto_index: Option<bool>,
+    reference_fasta: Option<PathBuf>,
+    samtools_path: Option<PathBuf>,
+
+    // Subcommand options
+    chrom_sizes: Option<PathBuf>,
+    low_memory: Option<bool>,
+    keep_bedgraph: Option<bool>,
+    keep_downsampled_bed: Option<bool>,
+    native_bigwig: Option<bool>,
+    #[serde(deserialize_with = "choice")]
+    scale: Option<Scale>,
+    weighted: Option<bool>,
+    atac_shift: Option<bool>,
+    shift_plus: Option<i64>,
+    shift_minus: Option<i64>,
+    step: Option<usize>,
+    log2: Option<bool>,
+    pseudocount: Option<f64>,
+    regions: Option<PathBuf>,
+    matrix: Option<PathBuf>,
+    correlation: Option<PathBuf>,
+    #[serde(deserialize_with = "choice")]
+    correlation_method: Option<CorrelationMethod>,
+    quantile_normalize: Option<bool>,
+    bedgraphtobigwig_path: Option<PathBuf>,
+    #[serde(deserialize_with = "choice")]
+    normalize: Option<Normalization>,
+    effective_genome_size: Option<u64>,
+    #[serde(deserialize_with = "choice")]
+    genome: Option<Genome>,
+    keep_tmp_bam: Option<bool>,
+    exact: Option<bool>,
+    per_file_threads: Option<usize>,
+    split_by_chrom: Option<bool>,
+    bamcoverage_path: Option<PathBuf>,
+    #[serde(deserialize_with = "choice")]
+    input_type: Option<InputType>,
+}
+
+/// A string, or a list of them, as given for an option taking several values.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
     }
+}
+
+/// A value-enum option, by its command-line name.
+fn choice<'de, D: Deserializer<'de>, T: ValueEnum>(d: D) -> Result<Option<T>, D::Error> {
+    let value = String::deserialize(d)?;
+    T::from_str(&value, false).map(Some).map_err(D::Error::custom)
+}
+
+fn target<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Target>, D::Error> {
+    parse_target(&String::deserialize(d)?).map(Some).map_err(D::Error::custom)
+}
 
-    match args.input_type {
-        InputType::Bed => {
-            // BED pipeline (unchanged)
-            let chrom_sizes = args.chrom_sizes.as_ref().unwrap();
-            let chrom_order = Arc::new(parse_chrom_order(&chrom_sizes)?);
-            let bins_bed = Arc::new(create_50bp_bins(&chrom_sizes)?);
-
-            let mut frag_counts = Vec::new();
-            for f in &args.files {
-                let c = count_fragments(f)?;
-                frag_counts.push((f.clone(), c));
+fn regex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    Regex::new(&String::deserialize(d)?).map(Some).map_err(D::Error::custom)
+}
+
+fn paths<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<PathBuf>>, D::Error> {
+    let values = OneOrMany::deserialize(d)?.into_vec();
+    Ok(Some(values.into_iter().map(PathBuf::from).collect()))
+}
+
+/// Names as a list or a comma-separated string.
+fn names<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    let values = OneOrMany::deserialize(d)?.into_vec();
+    Ok(Some(values.iter().flat_map(|v| v.split(',')).map(str::to_string).collect()))
+}
+
+/// A chromosome list option: a comma-separated string or file name, or a list of names.
+fn name_list<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(Some(OneOrMany::deserialize(d)?.into_vec().join(",")))
+}
+
+fn size_classes<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<SizeClass>>, D::Error> {
+    let values = OneOrMany::deserialize(d)?.into_vec();
+    let classes = values.iter().flat_map(|v| v.split(',')).map(parse_size_class);
+    classes.collect::<Result<_, _>>().map(Some).map_err(D::Error::custom)
+}
+
+/// Move each of the `fields` the config file sets into `args`, unless `set_by_user` says the
+/// command line or the environment already gave it.
+macro_rules! fill {
+    ($config:ident, $args:expr, $set_by_user:ident; $($field:ident),+ $(,)?) => {
+        $(
+            if let Some(value) = $config.$field.take() {
+                if !$set_by_user(stringify!($field)) {
+                    $args.$field = value.into();
+                }
             }
-            let counts_only: Vec<_> = frag_counts.iter().map(|(_, c)| *c).collect();
-            let mean_val = mean(&counts_only);
-            let sd_val = std_dev(&counts_only, mean_val);
-            let cutoff = (mean_val - args.exclude_sd * sd_val).max(0.0);
-            eprintln!("QC: Mean={}, SD={}, cutoff={}", mean_val, sd_val, cutoff);
-            let filtered = frag_counts
-                .iter()
-                .filter(|(_, c)| (*c as f64) >= cutoff)
-                .cloned()
-                .collect::<Vec<_>>();
-            if filtered.is_empty() {
-                eprintln!("No samples pass the QC cutoff");
-                std::process::exit(1);
+        )+
+    };
+}
+
+impl ConfigFile {
+    /// Read `path`, with keys written `bin-size` or `bin_size`. A key that only applies to
+    /// another subcommand than `subcommand` is left out, so one file can serve `qc` and
+    /// `bed` alike. Also returns the file's table of the keys that apply, normalized, and the
+    /// keys that don't.
+    fn load(
+        path: &Path,
+        subcommand: &clap::Command,
+    ) -> Result<(Self, toml::Table, Vec<String>), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read --config {}: {}", path.display(), e))?;
+        let invalid = |e: &dyn Display| format!("invalid --config {}: {}", path.display(), e);
+        let table: toml::Table = toml::from_str(&text).map_err(|e| invalid(&e))?;
+        // Aliases are renamed to their option, so the table lists the run's settings
+        let id = |key: String| {
+            let key = key.replace('-', "_");
+            let aliased = |arg: &&clap::Arg| {
+                let aliases = arg.get_all_aliases().unwrap_or_default();
+                aliases.iter().any(|alias| alias.replace('-', "_") == key)
+            };
+            match subcommand.get_arguments().find(aliased) {
+                Some(arg) => arg.get_id().to_string(),
+                None => key,
             }
-            let excluded = frag_counts
-                .iter()
-                .filter(|(_, c)| (*c as f64) < cutoff)
-                .cloned()
-                .collect::<Vec<_>>();
-            if !excluded.is_empty() {
-                eprintln!("Excluded samples with low fragment counts:");
-                for (f, c) in &excluded {
-                    eprintln!("  {} => {}", f.display(), c);
-                }
+        };
+        let table: toml::Table =
+            table.into_iter().map(|(key, value)| (id(key), value)).collect();
+        let applies = |key: &str| {
+            matches!(key, "verbose" | "no_progress")
+                || subcommand.get_arguments().any(|arg| arg.get_id() == key)
+        };
+        let config: ConfigFile =
+            toml::Value::Table(table.clone()).try_into().map_err(|e| invalid(&e))?;
+        // Parsed above so every key is checked, then dropped when it's for another subcommand
+        let (table, other): (toml::Table, toml::Table) =
+            table.into_iter().partition(|(key, _)| applies(key));
+        Ok((config, table, other.into_iter().map(|(key, _)| key).collect()))
+    }
+
+    /// Fill in the options of `cli` that `matches` shows came from the built-in defaults, so
+    /// the command line wins over the environment, which wins over the file.
+    fn apply(mut self, cli: &mut Cli, matches: &ArgMatches) {
+        let Some((_, sub_matches)) = matches.subcommand() else {
+            return;
+        };
+        let from = |matches: &ArgMatches, id: &str| {
+            let source = matches.try_get_raw(id).ok().and_then(|_| matches.value_source(id));
+            matches!(source, Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+        };
+        let global = |id: &str| from(matches, id) || from(sub_matches, id);
+        let set = |id: &str| from(sub_matches, id);
+        fill!(self, cli, global; verbose, no_progress);
+        let Some(command) = &mut cli.command else {
+            return;
+        };
+        let common = match command {
+            Command::Bed(args) => {
+                self.apply_tracks(&mut args.tracks, &set);
+                self.apply_bed_read(&mut args.read, &set);
+                fill!(self, args, set;
+                    chrom_sizes, low_memory, keep_bedgraph, keep_downsampled_bed, native_bigwig,
+                    scale, weighted, atac_shift, shift_plus, shift_minus, step, log2,
+                    pseudocount, regions, matrix, correlation, correlation_method,
+                    quantile_normalize, bedgraphtobigwig_path);
+                &mut args.common
             }
-            let min_frag_count = filtered.iter().map(|(_, c)| *c).min().unwrap();
-
-            let m = Arc::new(MultiProgress::new());
-
-            filtered.par_iter().for_each(|(file_path, _)| {
-                let pb = m.add(ProgressBar::new(6));
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{msg} {bar:40.cyan/blue} {pos}/{len} ({eta})")
-                        .expect("Progress bar template error")
-                        .progress_chars("#>-"),
-                );
-                let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
-                let msg = format!("Processing {}", filename);
-                pb.set_message(msg.clone());
-
-                if let Ok((header, mut sample)) = reservoir_sample(file_path, min_frag_count)
-                {
-                    pb.inc(1);
-
-                    let order_map = chrom_order.clone();
-                    sample.retain(|line| {
-                        let chrom = line.split('\t').next().unwrap();
-                        order_map.contains_key(chrom)
-                    });
-
-                    sample.sort_by(|a, b| {
-                        let a_parts: Vec<&str> = a.split('\t').collect();
-                        let b_parts: Vec<&str> = b.split('\t').collect();
-                        let a_rank = *order_map.get(a_parts[0]).unwrap_or(&usize::MAX);
-                        let b_rank = *order_map.get(b_parts[0]).unwrap_or(&usize::MAX);
-                        if a_rank == b_rank {
-                            let a_start = a_parts[1].parse::<u32>().unwrap_or(0);
-                            let b_start = b_parts[1].parse::<u32>().unwrap_or(0);
-                            return a_start.cmp(&b_start);
-                        }
-                        a_rank.cmp(&b_rank)
-                    });
-                    pb.inc(1);
-
-                    let out_bed = file_path.with_file_name(format!(
-                        "{}_downsampled.bed",
-                        file_path.file_stem().unwrap().to_string_lossy()
-                    ));
-                    {
-                        let out_file = File::create(&out_bed).unwrap();
-                        let mut writer = BufWriter::new(out_file);
-                        writeln!(writer, "{}", header).unwrap();
-                        for line in &sample {
-                            writeln!(writer, "{}", line).unwrap();
-                        }
-                    }
-                    pb.inc(1);
-
-                    let sorted_bed = out_bed.with_file_name(format!(
-                        "{}_sorted.bed",
-                        out_bed.file_stem().unwrap().to_string_lossy()
-                    ));
-                    let bedtools_sort_status = Command::new("bedtools")
-                        .args(["sort", "-faidx"])
-                        .arg(&*chrom_sizes)
-                        .args(["-i"])
-                        .arg(&out_bed)
-                        .stdout(File::create(&sorted_bed).unwrap())
-                        .status()
-                        .expect("bedtools sort failed");
-                    if !bedtools_sort_status.success() {
-                        eprintln!("bedtools sort failed for {}", out_bed.display());
-                        let msg = format!("Sort failed for {}", filename);
-                        pb.finish_with_message(msg);
-                        return;
-                    }
-                    pb.inc(1);
-
-                    let coverage_bed =
-                        file_path.with_file_name(format!("{}_50bp_counts.bed", filename));
-                    let coverage_status = Command::new("bedtools")
-                        .args(["coverage", "-a"])
-                        .arg(&*bins_bed)
-                        .args(["-b"])
-                        .arg(&sorted_bed)
-                        .args(["-counts"])
-                        .stdout(File::create(&coverage_bed).unwrap())
-                        .status()
-                        .expect("bedtools coverage failed");
-                    if !coverage_status.success() {
-                        eprintln!("bedtools coverage failed for {}", sorted_bed.display());
-                        let msg = format!("Coverage failed for {}", filename);
-                        pb.finish_with_message(msg);
-                        return;
-                    }
-                    pb.inc(1);
-
-                    let bedgraph =
-                        file_path.with_file_name(format!("{}_50bp.bedGraph", filename));
-                    let awk_status = Command::new("awk")
-                        .arg(r#"OFS="\t" {print $1, $2, $3, $4}"#)
-                        .stdin(File::open(&coverage_bed).unwrap())
-                        .stdout(File::create(&bedgraph).unwrap())
-                        .status()
-                        .expect("awk command failed");
-                    if !awk_status.success() {
-                        eprintln!("awk conversion failed for {}", coverage_bed.display());
-                        let msg = format!("awk failed for {}", filename);
-                        pb.finish_with_message(msg);
-                        return;
-                    }
-
-                    let sorted_bedgraph =
-                        file_path.with_file_name(format!("{}_50bp_sorted.bedGraph", filename));
-                    let sort_status = Command::new("sort")
-                        .args(["--parallel=1", "-k1,1", "-k2,2n"])
-                        .arg(&bedgraph)
-                        .stdout(File::create(&sorted_bedgraph).unwrap())
-                        .status()
-                        .expect("sort failed");
-                    if !sort_status.success() {
-                        eprintln!("Sorting bedGraph failed for {}", bedgraph.display());
-                        let msg = format!("bedGraph sort failed {}", filename);
-                        pb.finish_with_message(msg);
-                        return;
-                    }
-
-                    let bigwig = file_path.with_file_name(format!("{}_50bp.bw", filename));
-                    let bw_status = Command::new("bedGraphToBigWig")
-                        .arg(&sorted_bedgraph)
-                        .arg(&*chrom_sizes)
-                        .arg(&bigwig)
-                        .status()
-                        .expect("bedGraphToBigWig failed");
-                    if bw_status.success() {
-                        eprintln!("Wrote {}", bigwig.display());
-                        let msg = format!("Completed {}", filename);
-                        pb.finish_with_message(msg);
-                    } else {
-                        eprintln!("bedGraphToBigWig failed for {}", sorted_bedgraph.display());
-                        let msg = format!("BigWig failed {}", filename);
-                        pb.finish_with_message(msg);
-                    }
-
-                    if !args.keep_bedgraph {
-                        let _ = std::fs::remove_file(&coverage_bed);
-                        let _ = std::fs::remove_file(&bedgraph);
-                        let _ = std::fs::remove_file(&sorted_bedgraph);
-                        let _ = std::fs::remove_file(&sorted_bed);
-                        let _ = std::fs::remove_file(&out_bed);
-                    }
-                } else {
-                    let msg = format!("Sampling failed {}", file_path.display().to_string());
-                    pb.finish_with_message(msg);
-                }
-            });
+            Command::Bam(args) => {
+                self.apply_tracks(&mut args.tracks, &set);
+                self.apply_bam_read(&mut args.read, &set);
+                fill!(self, args, set;
+                    chrom_sizes, normalize, effective_genome_size, genome, keep_tmp_bam, exact,
+                    per_file_threads, split_by_chrom, bamcoverage_path);
+                &mut args.common
+            }
+            Command::Qc(args) => {
+                self.apply_bed_read(&mut args.bed, &set);
+                self.apply_bam_read(&mut args.bam, &set);
+                fill!(self, args, set; input_type, chrom_sizes);
+                &mut args.common
+            }
+            Command::Bins(_) => return,
+        };
+        fill!(self, common, set;
+            files, files_from, recursive, blacklist, exclude_sd, qc_method, sd_type,
+            min_fragments, qc_mode, target, reference, outdir, min_length, max_length, chroms,
+            exclude_chroms, mito_name, exclude_mito, spikein_chroms, filter_qc, dedup, retries,
+            name_pattern, group_pattern, pool, threads, seed, qc_report, per_chrom_report,
+            length_histogram, length_histogram_bin, length_histogram_full, manifest, timing,
+            events, events_stdout);
+        // --reference replaces --target, so a file's reference mustn't override a target
+        // given on the command line
+        if set("target") && !set("reference") {
+            common.reference = None;
+        }
+    }
+
+    fn apply_tracks(&mut self, args: &mut TrackArgs, set: &dyn Fn(&str) -> bool) {
+        fill!(self, args, set;
+            no_downsample, tmp_dir, bin_size, mode, smooth_length, extend, size_classes,
+            stranded, output_format, spikein_normalize, max_concurrent, fail_fast, dry_run,
+            force);
+    }
+
+    fn apply_bed_read(&mut self, args: &mut BedReadArgs, set: &dyn Fn(&str) -> bool) {
+        fill!(self, args, set; no_header, strict, on_bad_coord, bedpe);
+    }
+
+    fn apply_bam_read(&mut self, args: &mut BamReadArgs, set: &dyn Fn(&str) -> bool) {
+        fill!(self, args, set;
+            min_mapq, include_flags, exclude_flags, single_end, no_auto_index, reference_fasta,
+            samtools_path);
+    }
+}
+
+/// Every option of the subcommand in `matches` with the value the run uses, as TOML lines
+/// that can go back into a `--config` file: the command line's or the environment's value,
+/// then the `config` table's, then the default.
+fn effective_settings(matches: &ArgMatches, config: &toml::Table) -> Vec<(String, String)> {
+    let Some((name, matches)) = matches.subcommand() else {
+        return Vec::new();
+    };
+    let command = Cli::command();
+    let Some(subcommand) = command.find_subcommand(name) else {
+        return Vec::new();
+    };
+    let value = |raw: &str| {
+        let bare = raw.parse::<bool>().is_ok()
+            || raw.parse::<i64>().is_ok()
+            || raw.parse::<f64>().is_ok_and(f64::is_finite);
+        if bare {
+            raw.to_string()
+        } else {
+            toml::Value::String(raw.to_string()).to_string()
         }
-        InputType::Bam => {
-            if args.files.is_empty() {
-                eprintln!("No BAM files provided");
-                std::process::exit(1);
+    };
+    subcommand
+        .get_arguments()
+        .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+        .filter_map(|arg| {
+            let id = arg.get_id().as_str();
+            let list =
+                arg.is_positional() || arg.get_num_args().is_some_and(|n| n.max_values() > 1);
+            let list = list || arg.get_value_delimiter().is_some();
+            let user = matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            );
+            if let (false, Some(setting)) = (user, config.get(id)) {
+                let setting = match setting {
+                    toml::Value::Array(_) => setting.to_string(),
+                    setting if list => format!("[{}]", setting),
+                    setting => setting.to_string(),
+                };
+                return Some((id.to_string(), setting));
             }
-            let min_count = {
-                let mut counts = Vec::new();
-                for f in &args.files {
-                    let count_output = Command::new("samtools")
-                        .args(&["view", "-c", "-f", "2", "-F", "260", f.to_str().unwrap()])
-                        .output()
-                        .expect("failed to run samtools count");
-                    if !count_output.status.success() {
-                        eprintln!("samtools count failed for {}", f.display());
-                        std::process::exit(1);
-                    }
-                    let count_str = String::from_utf8_lossy(&count_output.stdout);
-                    let sample_count: usize = count_str.trim().parse().unwrap_or(0);
-                    counts.push((f.clone(), sample_count));
-                }
-                let counts_only: Vec<_> = counts.iter().map(|(_, c)| *c).collect();
-                let mean_val = mean(&counts_only);
-                let sd_val = std_dev(&counts_only, mean_val);
-                let cutoff = (mean_val - args.exclude_sd * sd_val).max(0.0);
-                eprintln!("QC: Mean={}, SD={}, cutoff={}", mean_val, sd_val, cutoff);
-                let filtered = counts
-                    .iter()
-                    .filter(|(_, c)| (*c as f64) >= cutoff)
-                    .cloned()
-                    .collect::<Vec<_>>();
-                if filtered.is_empty() {
-                    eprintln!("No BAM samples pass the QC cutoff");
-                    std::process::exit(1);
-                }
-                let excluded = counts
-                    .iter()
-                    .filter(|(_, c)| (*c as f64) < cutoff)
-                    .cloned()
-                    .collect::<Vec<_>>();
-                if !excluded.is_empty() {
-                    eprintln!("Excluded BAM samples with low fragment counts:");
-                    for (f, c) in &excluded {
-                        eprintln!("  {} => {}", f.display(), c);
-                    }
-                }
-                filtered.iter().map(|(_, c)| *c).min().unwrap()
+            let raw: Vec<String> =
+                matches.get_raw(id)?.map(|v| value(&v.to_string_lossy())).collect();
+            let value = match raw.as_slice() {
+                [single] if !list => single.clone(),
+                values => format!("[{}]", values.join(", ")),
             };
+            Some((id.to_string(), value))
+        })
+        .collect()
+}
 
-            let m = Arc::new(MultiProgress::new());
-
-            args.files.par_iter().for_each(|file_path| {
-                let file_str = file_path.to_str().unwrap();
-                let count_output = Command::new("samtools")
-                    .args(&["view", "-c", "-f", "2", "-F", "260", file_str])
-                    .output()
-                    .expect("failed to run samtools count");
-                let count_str = String::from_utf8_lossy(&count_output.stdout);
-                let sample_count = count_str.trim().parse::<f64>().unwrap_or(0.0);
-                if sample_count < min_count as f64 {
-                    return;
-                }
-
-                let pb = m.add(ProgressBar::new(1));
-                pb.set_style(
-                    ProgressStyle::default_spinner()
-                        .template("{msg} {spinner} {elapsed_precise}")
-                        .expect("Progress bar template error"),
-                );
-
-                let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
-                let msg = format!("Processing BAM {}", filename);
-                pb.set_message(msg.clone());
-
-                let fraction = (min_count as f64 / sample_count).min(1.0);
-                let seed_fraction = format!("42.{:03}", (fraction * 1000.0) as u32);
-
-                let tmp_bam = file_path.with_file_name(format!("{}_downsampled.bam", filename));
-                // Write downsampled BAM to disk
-                let samtools_status = Command::new("samtools")
-                    .args(&[
-                        "view",
-                        "-b",
-                        "-s",
-                        &seed_fraction,
-                        "-f",
-                        "2",
-                        "-F",
-                        "260",
-                        file_str,
-                    ])
-                    .stdout(File::create(&tmp_bam).unwrap())
-                    .status()
-                    .expect("samtools downsampling failed");
-                if !samtools_status.success() {
-                    eprintln!("samtools downsampling failed for {}", filename);
-                    pb.finish_with_message(format!("Failed {}", filename));
-                    return;
-                }
+/// Parse the command line, filling in the options it leaves out from `--config`. Returns the
+/// parsed CLI, the run's effective settings, and notes on config keys that were ignored, to
+/// log once logging is set up.
+fn parse_cli() -> (Cli, Vec<(String, String)>, Vec<String>) {
+    let mut command = Cli::command();
+    let matches = command.get_matches_mut();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let path = match &cli.command {
+        Some(Command::Bed(args)) => args.common.config.clone(),
+        Some(Command::Bam(args)) => args.common.config.clone(),
+        Some(Command::Qc(args)) => args.common.config.clone(),
+        Some(Command::Bins(_)) | None => None,
+    };
+    let mut table = toml::Table::new();
+    let mut notes = Vec::new();
+    if let (Some(path), Some((name, _))) = (path, matches.subcommand()) {
+        let subcommand = command.find_subcommand(name).expect("parsed subcommand");
+        let (config, settings, other) = ConfigFile::load(&path, subcommand)
+            .unwrap_or_else(|message| command.error(ErrorKind::InvalidValue, message).exit());
+        config.apply(&mut cli, &matches);
+        table = settings;
+        let note = |key| format!("--config: {} doesn't apply to {}", key, name);
+        notes = other.iter().map(note).collect();
+    }
+    (cli, effective_settings(&matches, &table), notes)
+}
 
-                // Index the downsampled BAM file
-                let samtools_index_status = Command::new("samtools")
-                    .args(&["index", tmp_bam.to_str().unwrap()])
-                    .status()
-                    .expect("samtools index failed for downsampled BAM");
-                if !samtools_index_status.success() {
-                    eprintln!("samtools index failed for {}", filename);
-                    pb.finish_with_message(format!("Failed {}", filename));
-                    return;
-                }
+fn main() -> Result<(), Box<dyn Error>> {
+    let (cli, settings, notes) = parse_cli();
+    if let Some(shell) = cli.generate_completions {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        // Generated into a buffer first, since clap_complete panics on a closed stdout
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut command, name, &mut script);
+        std::io::stdout().write_all(&script)?;
+        return Ok(());
+    }
+    let Some(command) = cli.command else {
+        let message = "a subcommand (bed, bam, qc or bins) is required";
+        Cli::command().error(ErrorKind::MissingSubcommand, message).exit();
+    };
 
-                let bamcov_out = file_path.with_file_name(format!("{}_50bp.bw", filename));
-
-                let mut bamcov_cmd = Command::new("bamCoverage");
-                bamcov_cmd.args(&[
-                    "-p", "1",
-                    "-b", tmp_bam.to_str().unwrap(),
-                    "--binSize", "50",
-                    "--normalizeUsing", "None",
-                    "-o", bamcov_out.to_str().unwrap(),
-                ]);
-                if let Some(blacklist_path) = &args.blacklist {
-                    bamcov_cmd.args(&["--blackListFileName", blacklist_path.to_str().unwrap()]);
-                }
+    let m = if cli.no_progress || !std::io::stderr().is_terminal() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+    init_logging(cli.verbose, m.clone())?;
+    for note in &notes {
+        debug!("{}", note);
+    }
+    // Logged in full when they came from a file, so the run documents its own settings
+    let from_file = settings.iter().any(|(key, _)| key == "config");
+    if from_file {
+        info!("Effective settings:");
+    }
+    for (key, value) in &settings {
+        if from_file {
+            info!("  {} = {}", key, value);
+        } else {
+            debug!("{} = {}", key, value);
+        }
+    }
 
-                let bamcov_status = bamcov_cmd.status().unwrap_or_else(|e| {
-                    eprintln!("Failed bamCoverage for {}: {}", filename, e);
-                    std::process::exit(1);
-                });
-                if bamcov_status.success() {
-                    eprintln!("Wrote {}", bamcov_out.display());
-                    pb.finish_with_message(format!("Completed {}", filename));
-                } else {
-                    eprintln!("bamCoverage failed for {}", filename);
-                    pb.finish_with_message(format!("Failed {}", filename));
-                }
+    // What to run, whether intermediates survive an interrupt, and whether the run only
+    // counts (no summary of written tracks)
+    let (pipeline, input_type, files, keep_intermediates, counts_only) = match &command {
+        Command::Bed(args) => {
+            let tracks = &args.tracks;
+            (args.builder(), InputType::Bed, &args.common.files, args.keep_bedgraph, tracks.dry_run)
+        }
+        Command::Bam(args) => {
+            let tracks = &args.tracks;
+            (args.builder(), InputType::Bam, &args.common.files, args.keep_tmp_bam, tracks.dry_run)
+        }
+        Command::Qc(args) => (args.builder(), args.input_type, &args.common.files, false, true),
+        Command::Bins(args) => {
+            or_exit(write_bins(&args.chrom_sizes, args.bin_size, args.output.as_deref()));
+            return Ok(());
+        }
+    };
+    let pipeline = or_exit(pipeline.settings(settings).progress(m).build());
 
-                if !args.keep_tmp_bam {
-                    let _ = std::fs::remove_file(&tmp_bam);
-                    let bai_path = tmp_bam.with_extension("bam.bai");
-                    let _ = std::fs::remove_file(&bai_path);
-                    let bai_path2 = tmp_bam.with_extension("bai");
-                    let _ = std::fs::remove_file(&bai_path2);
-                }
-            });
+    let cleanup = pipeline.cleanup();
+    ctrlc::set_handler(move || {
+        if keep_intermediates {
+            warn!("Interrupted, keeping intermediate files");
+        } else {
+            warn!("Interrupted, removing intermediate files");
+            cleanup.remove_all();
         }
+        std::process::exit(130);
+    })?;
+
+    let report = match input_type {
+        InputType::Bed => pipeline.run_bed(files),
+        InputType::Bam => pipeline.run_bam(files),
+    };
+    let report = or_exit(report);
+
+    if counts_only {
+        return Ok(());
+    }
+    report.log_summary();
+    if report.failures() > 0 {
+        std::process::exit(1);
     }
 
     Ok(())
@@ -0,0 +1,4 @@
+#!/bin/sh
+# Stand-in for bedGraphToBigWig that crashes after writing part of its output.
+head -n 1 "$1" > "$3"
+exit 1
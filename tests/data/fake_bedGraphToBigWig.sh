@@ -0,0 +1,4 @@
+#!/bin/sh
+# Stand-in for bedGraphToBigWig in tests: copies the bedGraph to the output path so the
+# bin counts can be read back as text.
+cp "$1" "$3"
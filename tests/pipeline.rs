@@ -0,0 +1,1429 @@
+//! End-to-end runs of the BED pipeline on the fixtures in `tests/data`.
+//!
+//! The tests use the native bigWig writer, or a stand-in for bedGraphToBigWig that copies
+//! the bedGraph, so they need no external tools. `ucsc_bigwig` runs the real
+//! bedGraphToBigWig and is skipped when it isn't on `PATH`.
+
+use bedfragment_ds::{
+    parse_target, write_bins, BadCoords, CorrelationMethod, CoverageMode, Error, Exclusion,
+    Genome, Normalization, OutputFormat, Pipeline, PipelineBuilder, PoolMode, QcParams, Scale,
+    SdType, Target,
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+fn data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+}
+
+/// A builder writing outputs and intermediates into `dir`, with a fixed seed and one thread.
+fn builder(dir: &TempDir) -> PipelineBuilder {
+    Pipeline::builder()
+        .chrom_sizes(data("chrom.sizes"))
+        .outdir(dir.path().join("out"))
+        .tmp_dir(dir.path().join("tmp"))
+        .seed(42)
+        .threads(1)
+}
+
+/// Contents of every file below `dir` whose name ends in `suffix`, keyed by name.
+fn read_outputs(dir: &Path, suffix: &str) -> Vec<(String, String)> {
+    let mut outputs = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            outputs.extend(read_outputs(&path, suffix));
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if name.ends_with(suffix) {
+            outputs.push((name, fs::read_to_string(&path).unwrap()));
+        }
+    }
+    outputs.sort();
+    outputs
+}
+
+/// Number of lines in every file below `dir` whose name ends in `suffix`, keyed by name.
+fn line_counts(dir: &Path, suffix: &str) -> Vec<(String, usize)> {
+    read_outputs(dir, suffix)
+        .into_iter()
+        .map(|(name, contents)| (name, contents.lines().count()))
+        .collect()
+}
+
+/// Bin counts of a bedGraph as `(chrom, start, end, count)`.
+fn read_bedgraph(path: &Path) -> Vec<(String, u32, u32, u32)> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            (
+                fields[0].to_string(),
+                fields[1].parse().unwrap(),
+                fields[2].parse().unwrap(),
+                fields[3].parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn downsamples_to_smallest_library() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .keep_bedgraph(true)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    // Both pass QC, so s1's 100 fragments are sampled down to mid's 60
+    let downsampled = line_counts(&dir.path().join("tmp"), "_downsampled.bed");
+    assert_eq!(
+        downsampled,
+        vec![
+            ("mid_downsampled.bed".to_string(), 60),
+            ("s1_downsampled.bed".to_string(), 60),
+        ]
+    );
+}
+
+#[test]
+fn keep_downsampled_bed_writes_fragments_to_outdir() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .mode(CoverageMode::Midpoint)
+        .keep_downsampled_bed(true)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let kept = read_outputs(&dir.path().join("out"), "_downsampled.bed");
+    let counts: Vec<(&str, usize)> =
+        kept.iter().map(|(name, bed)| (name.as_str(), bed.lines().count())).collect();
+    assert_eq!(counts, [("mid_downsampled.bed", 60), ("s1_downsampled.bed", 60)]);
+    // Whole fragments rather than the midpoints that were binned
+    let first = kept[1].1.lines().next().unwrap();
+    let coords: Vec<u64> = first.split('\t').skip(1).take(2).map(|f| f.parse().unwrap()).collect();
+    assert!(coords[1] - coords[0] > 1);
+    // The temp directory went as usual
+    assert_eq!(fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+}
+
+#[test]
+fn no_downsample_keeps_every_fragment() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .keep_bedgraph(true)
+        .no_downsample(true)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let downsampled = line_counts(&dir.path().join("tmp"), "_downsampled.bed");
+    assert_eq!(
+        downsampled,
+        vec![
+            ("mid_downsampled.bed".to_string(), 60),
+            ("s1_downsampled.bed".to_string(), 100),
+        ]
+    );
+    assert!(dir.path().join("out/s1_full_50bp.bw").exists());
+    assert!(!dir.path().join("out/s1_50bp.bw").exists());
+}
+
+#[test]
+fn low_memory_sampling_matches() {
+    let dir = TempDir::new().unwrap();
+    let gz = dir.path().join("s1.bed.gz");
+    let mut encoder = GzEncoder::new(fs::File::create(&gz).unwrap(), Compression::default());
+    encoder.write_all(&fs::read(data("s1.bed")).unwrap()).unwrap();
+    encoder.finish().unwrap();
+
+    let downsampled = |low_memory| {
+        let run = TempDir::new().unwrap();
+        let pipeline = builder(&run)
+            .native_bigwig(true)
+            .keep_bedgraph(true)
+            .low_memory(low_memory)
+            .build()
+            .unwrap();
+        let report = pipeline.run_bed(&[gz.clone(), data("mid.bed"), data("s2.bed")]).unwrap();
+        assert_eq!(report.failures(), 0);
+        read_outputs(&run.path().join("tmp"), "_downsampled.bed")
+    };
+    let (default, low_memory) = (downsampled(false), downsampled(true));
+    assert_eq!(default.len(), 3);
+    assert!(default.iter().all(|(_, bed)| bed.lines().count() == 60));
+    assert_eq!(default, low_memory);
+}
+
+#[test]
+fn qc_excludes_low_outlier() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir).native_bigwig(true).build().unwrap();
+    let files = vec![data("s1.bed"), data("s2.bed"), data("s3.bed"), data("low.bed")];
+    let report = pipeline.run_bed(&files).unwrap();
+
+    let excluded: Vec<_> = report
+        .qc
+        .samples
+        .iter()
+        .filter(|s| !s.pass)
+        .map(|s| s.sample.as_str())
+        .collect();
+    assert_eq!(excluded, vec!["low"]);
+    let low = report.qc.samples.iter().find(|s| s.sample == "low").unwrap();
+    assert!(low.excluded == Some(Exclusion::Low));
+    assert_eq!(low.fragments, 10);
+
+    assert_eq!(report.failures(), 0);
+    assert_eq!(report.outcomes.len(), 3);
+    let out = dir.path().join("out");
+    for name in ["s1", "s2", "s3"] {
+        let bigwig = fs::read(out.join(format!("{}_50bp.bw", name))).unwrap();
+        assert_eq!(bigwig[..4], 0x888F_FC26u32.to_le_bytes());
+    }
+    assert!(!out.join("low_50bp.bw").exists());
+}
+
+#[test]
+fn min_fragments_floor() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .qc(QcParams {
+            min_fragments: Some(20),
+            ..QcParams::default()
+        })
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("low.bed")]).unwrap();
+
+    let low = report.qc.samples.iter().find(|s| s.sample == "low").unwrap();
+    assert!(low.excluded == Some(Exclusion::MinFragments));
+    // With a single sample left there is nothing to compare against
+    assert!(report.qc.skipped);
+}
+
+#[test]
+fn identical_counts_skip_qc() {
+    let dir = TempDir::new().unwrap();
+    let inputs: Vec<PathBuf> = (1..=3)
+        .map(|i| {
+            let copy = dir.path().join(format!("copy{}.bed", i));
+            fs::copy(data("s1.bed"), &copy).unwrap();
+            copy
+        })
+        .collect();
+    // Even a zero-width fence around the mean keeps every sample
+    let qc = QcParams {
+        exclude_sd: 0.0,
+        ..QcParams::default()
+    };
+    let pipeline = builder(&dir).count_only(true).qc(qc).build().unwrap();
+    let report = pipeline.run_bed(&inputs).unwrap();
+    assert!(report.qc.skipped);
+    assert_eq!(report.qc.std_dev, 0.0);
+    assert!(report.qc.samples.iter().all(|s| s.pass));
+}
+
+#[test]
+fn sample_sd() {
+    let dir = TempDir::new().unwrap();
+    let inputs = [data("s1.bed"), data("low.bed"), data("mid.bed")];
+    let std_dev = |sd_type| {
+        let qc = QcParams {
+            sd_type,
+            ..QcParams::default()
+        };
+        let pipeline = builder(&dir).count_only(true).qc(qc).build().unwrap();
+        pipeline.run_bed(&inputs).unwrap().qc.std_dev
+    };
+    let (population, sample) = (std_dev(SdType::Population), std_dev(SdType::Sample));
+    assert!(population > 0.0);
+    assert!((sample - population * (3.0f64 / 2.0).sqrt()).abs() < 1e-9);
+}
+
+#[cfg(unix)]
+#[test]
+fn bin_counts() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let bins = read_bedgraph(&dir.path().join("out/exact_50bp.bw"));
+    // 20 bins on chr1 and 10 on chr2, the last bin of each ending at the chromosome end
+    assert_eq!(bins.len(), 30);
+    let nonzero: Vec<_> = bins.iter().filter(|b| b.3 > 0).cloned().collect();
+    assert_eq!(
+        nonzero,
+        vec![
+            ("chr1".to_string(), 0, 50, 2),
+            ("chr1".to_string(), 50, 100, 2),
+            ("chr2".to_string(), 0, 50, 1),
+        ]
+    );
+    assert_eq!(bins.last().unwrap(), &("chr2".to_string(), 450, 500, 0));
+}
+
+#[cfg(unix)]
+#[test]
+fn sliding_bins() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .step(10)
+        .build()
+        .unwrap();
+    pipeline.run_bed(&[data("exact.bed")]).unwrap();
+
+    // One 10 bp interval per step, each counting the 50 bp window around it
+    let bins = read_bedgraph(&dir.path().join("out/exact_50bp_step10.bw"));
+    assert_eq!(bins.len(), 150);
+    let chr1: Vec<u32> = bins[..13].iter().map(|b| b.3).collect();
+    assert_eq!(chr1, vec![1, 1, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 0]);
+    assert_eq!(bins[7], ("chr1".to_string(), 70, 80, 2));
+
+    assert!(matches!(builder(&dir).step(60).build(), Err(Error::Config(_))));
+}
+
+#[test]
+fn region_counts() {
+    let dir = TempDir::new().unwrap();
+    let regions = dir.path().join("regions.bed");
+    fs::write(&regions, "chr2\t0\t50\tpeak2\nchr1\t50\t60\nchr1\t200\t300\tempty\n").unwrap();
+    let pipeline = builder(&dir).regions(regions).build().unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    // Sorted into chrom sizes order, unnamed regions named by their coordinates
+    let counts = fs::read_to_string(dir.path().join("out/exact_regions.tsv")).unwrap();
+    assert_eq!(
+        counts,
+        "chrom\tstart\tend\tname\tcount\n\
+         chr1\t50\t60\tchr1:50-60\t2\n\
+         chr1\t200\t300\tempty\t0\n\
+         chr2\t0\t50\tpeak2\t1\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn count_matrix() {
+    let dir = TempDir::new().unwrap();
+    let matrix = dir.path().join("matrix.tsv");
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .no_downsample(true)
+        .matrix(matrix.clone())
+        .build()
+        .unwrap();
+    pipeline.run_bed(&[data("exact.bed"), data("low.bed")]).unwrap();
+
+    let matrix = fs::read_to_string(matrix).unwrap();
+    let rows: Vec<&str> = matrix.lines().collect();
+    assert_eq!(rows.len(), 31);
+    assert_eq!(rows[0], "chrom\tstart\tend\texact\tlow");
+    // Each column matches its sample's own track
+    let exact = read_bedgraph(&dir.path().join("out/exact_full_50bp.bw"));
+    let low = read_bedgraph(&dir.path().join("out/low_full_50bp.bw"));
+    for (row, (e, l)) in rows[1..].iter().zip(exact.iter().zip(&low)) {
+        assert_eq!(*row, format!("{}\t{}\t{}\t{}\t{}", e.0, e.1, e.2, e.3, l.3));
+    }
+}
+
+#[test]
+fn track_correlation() {
+    let dir = TempDir::new().unwrap();
+    let correlation = dir.path().join("correlation.tsv");
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .no_downsample(true)
+        .correlation(correlation.clone())
+        .correlation_method(CorrelationMethod::Spearman)
+        .build()
+        .unwrap();
+    pipeline
+        .run_bed(&[data("s1.bed"), data("s2.bed"), data("s3.bed")])
+        .unwrap();
+
+    let table = fs::read_to_string(correlation).unwrap();
+    let rows: Vec<Vec<&str>> = table.lines().map(|l| l.split('\t').collect()).collect();
+    assert_eq!(rows[0], vec!["", "s1", "s2", "s3"]);
+    for (i, row) in rows.iter().enumerate().skip(1) {
+        assert_eq!(row[0], rows[0][i]);
+        assert_eq!(row[i], "1.0000");
+        for (j, other) in rows.iter().enumerate().skip(1) {
+            assert_eq!(row[j], other[i]);
+        }
+    }
+}
+
+#[test]
+fn quantile_normalized_tracks() {
+    let dir = TempDir::new().unwrap();
+    let regions = dir.path().join("regions.bed");
+    fs::write(&regions, "chr1\t0\t100\tr1\nchr1\t100\t200\tr2\nchr2\t0\t100\tr3\n").unwrap();
+    // Region counts 1, 2, 3 and 2, 2, 6
+    let a = dir.path().join("a.bed");
+    let b = dir.path().join("b.bed");
+    let fragment = |chrom: &str, start: u32| format!("{}\t{}\t{}\n", chrom, start, start + 10);
+    let lines = |counts: [usize; 3]| {
+        let starts = [("chr1", 10), ("chr1", 110), ("chr2", 10)];
+        (starts.iter().zip(counts))
+            .flat_map(|(&(chrom, start), n)| (0..n).map(move |i| fragment(chrom, start + i as u32)))
+            .collect::<String>()
+    };
+    fs::write(&a, lines([1, 2, 3])).unwrap();
+    fs::write(&b, lines([2, 2, 6])).unwrap();
+    let pipeline = builder(&dir)
+        .regions(regions)
+        .no_downsample(true)
+        .quantile_normalize(true)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[a, b]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let counts = |name: &str| -> Vec<String> {
+        let tsv = fs::read_to_string(dir.path().join("out").join(name)).unwrap();
+        tsv.lines().skip(1).map(|l| l.rsplit('\t').next().unwrap().to_string()).collect()
+    };
+    // The regular tracks are unchanged
+    assert_eq!(counts("a_full_regions.tsv"), ["1", "2", "3"]);
+    // Both follow the mean sorted distribution 1.5, 2, 4.5; b's tied ranks share their mean
+    assert_eq!(counts("a_qnorm_full_regions.tsv"), ["1.5", "2", "4.5"]);
+    assert_eq!(counts("b_qnorm_full_regions.tsv"), ["1.75", "1.75", "4.5"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn crash_leaves_no_output() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("crashing_bedGraphToBigWig.sh"))
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.failures(), 1);
+
+    // Neither the truncated bigWig nor its temporary name is left behind for a re-run to
+    // mistake for a finished output
+    assert_eq!(fs::read_dir(dir.path().join("out")).unwrap().count(), 0);
+}
+
+#[test]
+fn unwritable_outdir_fails_early() {
+    let dir = TempDir::new().unwrap();
+    // A file where the output directory should go can't be created even when run as root
+    let blocker = dir.path().join("blocker");
+    fs::write(&blocker, "").unwrap();
+    let pipeline = builder(&dir)
+        .outdir(blocker.join("out"))
+        .native_bigwig(true)
+        .build()
+        .unwrap();
+    let Err(Error::Config(message)) = pipeline.run_bed(&[data("exact.bed")]) else {
+        panic!("expected a configuration error");
+    };
+    assert!(message.starts_with("Cannot write to"), "{}", message);
+    assert!(message.contains("blocker"), "{}", message);
+}
+
+#[cfg(unix)]
+#[test]
+fn failed_tool_is_retried() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    // Fails on its first run only, like a command hitting a stale NFS handle
+    let flaky = dir.path().join("flaky_bedGraphToBigWig.sh");
+    let marker = dir.path().join("failed_once");
+    let script = format!(
+        "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\n\
+         if [ ! -e {0} ]; then touch {0}; echo 'stale file handle' >&2; exit 1; fi\n\
+         cp \"$1\" \"$3\"\n",
+        marker.display()
+    );
+    fs::write(&flaky, script).unwrap();
+    fs::set_permissions(&flaky, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let run = |retries| {
+        let pipeline = builder(&dir)
+            .bedgraph_to_bigwig(&flaky)
+            .retries(retries)
+            .build()
+            .unwrap();
+        pipeline.run_bed(&[data("exact.bed")]).unwrap()
+    };
+    // Without retries the sample fails, with the tool's own message in the error
+    let report = run(0);
+    let Some((_, Err(e))) = report.outcomes.first() else {
+        panic!("expected the sample to fail");
+    };
+    assert!(e.to_string().ends_with("stale file handle"), "{}", e);
+    fs::remove_file(&marker).unwrap();
+    assert_eq!(run(1).failures(), 0);
+    assert!(dir.path().join("out/exact_50bp.bw").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn excluded_chroms_get_no_bins() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .chroms(None, vec!["chr2".to_string()])
+        .filter_qc(true)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    // The chr2 fragment is left out of the QC count as well
+    assert_eq!(report.qc.samples[0].fragments, 2);
+
+    let bins = read_bedgraph(&dir.path().join("out/exact_50bp.bw"));
+    assert_eq!(bins.len(), 20);
+    assert!(bins.iter().all(|b| b.0 == "chr1"));
+}
+
+#[test]
+fn unfiltered_qc_counts_warn() {
+    let dir = TempDir::new().unwrap();
+    let run = |filter_qc: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_bedfragment_ds"));
+        command.args(["bed", "--no-progress", "--native-bigwig", "--max-length", "50"]);
+        command.arg("--chrom-sizes").arg(data("chrom.sizes"));
+        command.arg("--outdir").arg(dir.path().join("out"));
+        if filter_qc {
+            command.arg("--filter-qc");
+        }
+        let output = command.arg(data("exact.bed")).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    // The 100bp fragment is filtered out, but still counts towards the target without
+    // --filter-qc
+    assert!(run(false).contains("add --filter-qc"));
+    assert!(!run(true).contains("add --filter-qc"));
+}
+
+#[cfg(unix)]
+#[test]
+fn blacklisted_fragments_are_dropped() {
+    let dir = TempDir::new().unwrap();
+    let blacklist = dir.path().join("blacklist.bed");
+    fs::write(&blacklist, "# comment\nchr1\t90\t95\n").unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .blacklist(blacklist.clone())
+        .build()
+        .unwrap();
+    pipeline.run_bed(&[data("exact.bed")]).unwrap();
+
+    // Only chr1:0-100 touches the blacklist; the whole fragment goes, not just the overlap
+    let bins = read_bedgraph(&dir.path().join("out/exact_50bp.bw"));
+    let nonzero: Vec<_> = bins.iter().filter(|b| b.3 > 0).cloned().collect();
+    assert_eq!(
+        nonzero,
+        vec![
+            ("chr1".to_string(), 0, 50, 1),
+            ("chr1".to_string(), 50, 100, 1),
+            ("chr2".to_string(), 0, 50, 1),
+        ]
+    );
+
+    fs::write(&blacklist, "chr1\tninety\t95\n").unwrap();
+    let result = pipeline.run_bed(&[data("exact.bed")]);
+    assert!(matches!(result, Err(Error::Parse(msg)) if msg.contains("line 1")));
+}
+
+#[test]
+fn bad_chrom_sizes_name_the_line() {
+    let dir = TempDir::new().unwrap();
+    let sizes = dir.path().join("bad.sizes");
+    for (contents, expected) in [
+        ("chr1\t1000\nchr2\t500\nchr1\t1000\n", "line 3: chr1 is already listed on line 1"),
+        ("chr1\t1000\n\nchr2\t5e2\n", "line 3: invalid length '5e2'"),
+    ] {
+        fs::write(&sizes, contents).unwrap();
+        let pipeline = builder(&dir).chrom_sizes(sizes.clone()).native_bigwig(true).build();
+        let result = pipeline.unwrap().run_bed(&[data("exact.bed")]);
+        assert!(matches!(result, Err(Error::Parse(msg)) if msg.contains(expected)));
+    }
+}
+
+#[test]
+fn malformed_lines_are_skipped_or_fatal() {
+    let dir = TempDir::new().unwrap();
+    let bed = dir.path().join("bad.bed");
+    fs::write(&bed, "chr1\t0\t100\nchr1\tx\t60\n# note\nchr2\t10\nchr2\t10\t20\n").unwrap();
+
+    let pipeline = builder(&dir).native_bigwig(true).build().unwrap();
+    let report = pipeline.run_bed(std::slice::from_ref(&bed)).unwrap();
+    assert_eq!(report.failures(), 0);
+    assert_eq!(report.malformed_lines.get(&bed), Some(&2));
+
+    let pipeline = builder(&dir).native_bigwig(true).strict(true).build().unwrap();
+    let Err(Error::Counting(errors)) = pipeline.run_bed(&[bed]) else {
+        panic!("expected a counting error");
+    };
+    assert!(matches!(&errors[..], [(_, Error::Parse(msg))] if msg.contains("line 2: malformed")));
+}
+
+#[test]
+fn bad_coords_are_clamped_skipped_or_fatal() {
+    let dir = TempDir::new().unwrap();
+    let bed = dir.path().join("edge.bed");
+    let lines = "chr1\t0\t100\nchr1\t200\t200\nchr2\t450\t600\nchr2\t600\t700\n";
+    fs::write(&bed, lines).unwrap();
+    let run = |mode| {
+        let pipeline = builder(&dir)
+            .on_bad_coord(mode)
+            .output_format(OutputFormat::Bedgraph)
+            .force(true)
+            .build()
+            .unwrap();
+        pipeline.run_bed(std::slice::from_ref(&bed))
+    };
+
+    // Clamping keeps the fragment that overhangs chr2 as chr2:450-500
+    let report = run(BadCoords::Clamp).unwrap();
+    assert_eq!(report.qc.samples[0].fragments, 2);
+    let bins = read_bedgraph(&dir.path().join("out/edge_50bp.bedGraph"));
+    assert_eq!(bins.last(), Some(&("chr2".to_string(), 450, 500, 1)));
+
+    let report = run(BadCoords::Skip).unwrap();
+    assert_eq!(report.qc.samples[0].fragments, 1);
+    let bins = read_bedgraph(&dir.path().join("out/edge_50bp.bedGraph"));
+    assert!(bins.iter().all(|bin| bin.0 == "chr1" || bin.3 == 0));
+
+    let Err(Error::Counting(errors)) = run(BadCoords::Error) else {
+        panic!("expected a counting error");
+    };
+    assert!(matches!(&errors[..], [(_, Error::Parse(msg))] if msg.contains("line 2")));
+}
+
+#[cfg(unix)]
+#[test]
+fn bedgraph_output() {
+    let dir = TempDir::new().unwrap();
+    let run = |format, tool: PathBuf| {
+        let pipeline = builder(&dir)
+            .output_format(format)
+            .bedgraph_to_bigwig(tool)
+            .force(true)
+            .build()
+            .unwrap();
+        let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+        assert_eq!(report.failures(), 0);
+    };
+    // bedGraphToBigWig isn't needed for bedGraphs alone
+    run(OutputFormat::Bedgraph, dir.path().join("missing"));
+    let out = dir.path().join("out");
+    let (bigwig, bedgraph) = (out.join("exact_50bp.bw"), out.join("exact_50bp.bedGraph"));
+    assert!(!bigwig.exists());
+    assert_eq!(read_bedgraph(&bedgraph).len(), 30);
+
+    run(OutputFormat::Both, data("fake_bedGraphToBigWig.sh"));
+    assert_eq!(fs::read(&bigwig).unwrap(), fs::read(&bedgraph).unwrap());
+}
+
+#[test]
+fn genome_bins() {
+    let dir = TempDir::new().unwrap();
+    let bins = dir.path().join("bins.bed");
+    write_bins(&data("chrom.sizes"), 300, Some(&bins)).unwrap();
+    assert_eq!(
+        fs::read_to_string(&bins).unwrap(),
+        "chr1\t0\t300\nchr1\t300\t600\nchr1\t600\t900\nchr1\t900\t1000\n\
+         chr2\t0\t300\nchr2\t300\t500\n"
+    );
+}
+
+#[test]
+fn truncated_bins_file_is_rewritten() {
+    let dir = TempDir::new().unwrap();
+    let bins = dir.path().join("bins.bed");
+    // A file cut short by a crashed run, and another run's partial file next to it
+    fs::write(&bins, "chr1\t0\t300\nchr1\t3").unwrap();
+    let other = dir.path().join("bins.bed.1.tmp");
+    fs::write(&other, "chr1\t0").unwrap();
+    write_bins(&data("chrom.sizes"), 300, Some(&bins)).unwrap();
+    assert_eq!(fs::read_to_string(&bins).unwrap().lines().count(), 6);
+    assert_eq!(fs::read_to_string(&other).unwrap(), "chr1\t0");
+}
+
+#[cfg(unix)]
+#[test]
+fn fai_as_chrom_sizes() {
+    let dir = TempDir::new().unwrap();
+    let fai = dir.path().join("genome.fa.fai");
+    fs::write(&fai, "chr1\t1000\t6\t60\t61\nchr2\t500\t1029\t60\t61\n").unwrap();
+    let pipeline = builder(&dir)
+        .chrom_sizes(fai)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let bins = read_bedgraph(&dir.path().join("out/exact_50bp.bw"));
+    assert_eq!(bins.len(), 30);
+}
+
+#[test]
+fn per_chrom_report() {
+    let dir = TempDir::new().unwrap();
+    let report = dir.path().join("chroms.tsv");
+    let pipeline = builder(&dir)
+        .count_only(true)
+        .chroms(None, vec!["chr2".to_string()])
+        .per_chrom_report(report.clone())
+        .build()
+        .unwrap();
+    pipeline.run_bed(&[data("exact.bed")]).unwrap();
+
+    // Filtered chromosomes are still reported
+    assert_eq!(
+        fs::read_to_string(report).unwrap(),
+        format!(
+            "file\tsample\tchrom\tfragments\tfraction\n\
+             {0}\texact\tchr1\t2\t0.666667\n\
+             {0}\texact\tchr2\t1\t0.333333\n",
+            data("exact.bed").display()
+        )
+    );
+}
+
+#[test]
+fn length_histogram() {
+    let dir = TempDir::new().unwrap();
+    let a = dir.path().join("a.bed");
+    let b = dir.path().join("b.bed");
+    fs::write(&a, "chr1\t0\t30\nchr1\t10\t45\nchr1\t20\t70\n").unwrap();
+    fs::write(&b, "chr1\t0\t30\n".repeat(6)).unwrap();
+    let histogram = dir.path().join("lengths.tsv");
+    let run = |full: bool| {
+        let pipeline = builder(&dir)
+            .length_histogram(histogram.clone())
+            .length_histogram_full(full)
+            .output_format(OutputFormat::Bedgraph)
+            .build()
+            .unwrap();
+        pipeline.run_bed(&[a.clone(), b.clone()]).unwrap();
+        fs::read_to_string(&histogram).unwrap()
+    };
+
+    // b is downsampled to a's 3 fragments; a's empty 40 bp bin is written as a zero
+    let sampled = "sample\tlength_bin\tcount\na\t30\t2\na\t40\t0\na\t50\t1\nb\t30\t3\n";
+    assert_eq!(run(false), sampled);
+    assert_eq!(run(true), sampled.replace("b\t30\t3", "b\t30\t6"));
+
+    let zero_width = builder(&dir).length_histogram_bin(0).build();
+    assert!(matches!(zero_width, Err(Error::Config(_))));
+}
+
+#[test]
+fn files_from_list_with_groups() {
+    let dir = TempDir::new().unwrap();
+    for (name, fragments) in [("r1.bed", 1), ("r2.bed", 2), ("other.bed", 4)] {
+        fs::write(dir.path().join(name), "chr1\t0\t100\n".repeat(fragments)).unwrap();
+    }
+    let list = dir.path().join("inputs.tsv");
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    let lines = format!(
+        "# replicates of ctrl\n{}\tctrl\n\n{}\tctrl\n{}\n",
+        path("r1.bed"),
+        path("r2.bed"),
+        path("other.bed")
+    );
+    fs::write(&list, lines).unwrap();
+    let run = |pool: PoolMode| {
+        let pipeline = builder(&dir).count_only(true).files_from(list.clone()).pool(pool);
+        let report = pipeline.build().unwrap().run_bed(&[]).unwrap();
+        let samples = report.qc.samples.iter();
+        samples.map(|s| (s.sample.clone(), s.fragments)).collect::<Vec<_>>()
+    };
+
+    let pooled = [("ctrl".to_string(), 3), ("other".to_string(), 4)];
+    assert_eq!(run(PoolMode::Sum), pooled);
+    let named = [("ctrl".to_string(), 1), ("ctrl".to_string(), 2), ("other".to_string(), 4)];
+    assert_eq!(run(PoolMode::None), named);
+}
+
+#[test]
+fn globs_and_directories_expand() {
+    let dir = TempDir::new().unwrap();
+    let inputs = dir.path().join("inputs");
+    fs::create_dir_all(inputs.join("nested")).unwrap();
+    for name in ["b.bed", "a.bed", "nested/c.bed", "notes.txt"] {
+        fs::write(inputs.join(name), "chr1\t0\t100\n").unwrap();
+    }
+    let run = |recursive: bool, files: Vec<PathBuf>| {
+        let pipeline = builder(&dir).count_only(true).recursive(recursive).build().unwrap();
+        let report = pipeline.run_bed(&files)?;
+        Ok::<_, Error>(report.qc.samples.iter().map(|s| s.sample.clone()).collect::<Vec<_>>())
+    };
+
+    assert_eq!(run(false, vec![inputs.clone()]).unwrap(), ["a", "b"]);
+    assert_eq!(run(true, vec![inputs.clone()]).unwrap(), ["a", "b", "c"]);
+    // b.bed is only counted once
+    let pattern = inputs.join("*.bed");
+    assert_eq!(run(false, vec![inputs.join("b.bed"), pattern]).unwrap(), ["b", "a"]);
+
+    let no_match = run(false, vec![inputs.join("*.bam")]);
+    assert!(matches!(no_match, Err(Error::Config(_))));
+}
+
+#[test]
+fn mito_and_blacklist_fractions() {
+    let dir = TempDir::new().unwrap();
+    let fragments = dir.path().join("sample.bed");
+    let mut bed = fs::read_to_string(data("exact.bed")).unwrap();
+    bed.push_str("chrM\t0\t10\nchrM\t5\t15\n");
+    fs::write(&fragments, bed).unwrap();
+    let blacklist = dir.path().join("blacklist.bed");
+    fs::write(&blacklist, "chr1\t90\t95\n").unwrap();
+    let pipeline = builder(&dir)
+        .count_only(true)
+        .blacklist(blacklist)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[fragments]).unwrap();
+
+    let sample = &report.qc.samples[0];
+    assert_eq!(sample.mito_fraction, 0.4);
+    assert_eq!(sample.blacklist_fraction, Some(0.2));
+}
+
+#[test]
+fn mito_name_and_exclusion() {
+    let dir = TempDir::new().unwrap();
+    let sizes = dir.path().join("chrom.sizes");
+    fs::write(&sizes, "chr1\t1000\nMT\t200\n").unwrap();
+    let fragments = dir.path().join("sample.bed");
+    fs::write(&fragments, "chr1\t0\t10\nMT\t0\t10\nMT\t20\t30\nchrM\t0\t10\n").unwrap();
+    let inputs = [fragments];
+    let run = |pipeline: PipelineBuilder| {
+        let pipeline = pipeline.chrom_sizes(sizes.clone()).build().unwrap();
+        pipeline.run_bed(&inputs).unwrap()
+    };
+
+    // MT is the one usual name in the chrom sizes, so chrM isn't counted
+    let report = run(builder(&dir).count_only(true));
+    assert_eq!(report.qc.samples[0].mito_fraction, 0.5);
+    let report = run(builder(&dir).count_only(true).mito_chroms(vec!["chrM".to_string()]));
+    assert_eq!(report.qc.samples[0].mito_fraction, 0.25);
+
+    let report = run(builder(&dir).exclude_mito(true).output_format(OutputFormat::Bedgraph));
+    assert_eq!(report.failures(), 0);
+    let track = read_bedgraph(&dir.path().join("out/sample_50bp.bedGraph"));
+    assert!(track.iter().all(|bin| bin.0 == "chr1"));
+}
+
+#[test]
+fn smoothing_averages_neighbouring_bins() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .smooth_length(150)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    // The first two bins hold 2 fragments each; the first window is cut short at chr1's start
+    let track = fs::read_to_string(dir.path().join("out/exact_50bp.bedGraph")).unwrap();
+    let values: Vec<&str> = track.lines().take(4).map(|l| l.rsplit('\t').next().unwrap()).collect();
+    assert_eq!(values, ["2", "1.3333334", "0.6666667", "0"]);
+
+    let too_short = builder(&dir).smooth_length(20).build();
+    assert!(matches!(too_short, Err(Error::Config(_))));
+}
+
+#[test]
+fn log2_transform_with_pseudocount() {
+    let dir = TempDir::new().unwrap();
+    let run = |pipeline: PipelineBuilder| {
+        let pipeline = pipeline
+            .output_format(OutputFormat::Bedgraph)
+            .no_downsample(true)
+            .build()?;
+        pipeline.run_bed(&[data("exact.bed")])
+    };
+    run(builder(&dir)).unwrap();
+    run(builder(&dir).log2(true).pseudocount(0.5)).unwrap();
+
+    let counts = read_bedgraph(&dir.path().join("out/exact_full_50bp.bedGraph"));
+    let log2 = fs::read_to_string(dir.path().join("out/exact_full_50bp_log2.bedGraph")).unwrap();
+    let values: Vec<f32> = log2
+        .lines()
+        .map(|line| line.rsplit('\t').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(values.len(), counts.len());
+    for (value, bin) in values.iter().zip(&counts) {
+        assert_eq!(*value, (bin.3 as f64 + 0.5).log2() as f32);
+    }
+
+    // log2(0) is undefined, so the pseudocount has to be positive
+    let result = run(builder(&dir).log2(true).pseudocount(0.0));
+    assert!(matches!(result, Err(Error::Config(_))));
+    let result = run(builder(&dir).pseudocount(1.0));
+    assert!(matches!(result, Err(Error::Config(_))));
+}
+
+#[test]
+fn extend_grows_reads_downstream() {
+    let dir = TempDir::new().unwrap();
+    let reads = dir.path().join("reads.bed");
+    let lines = "chr1\t100\t136\tr1\t0\t+\nchr1\t200\t236\tr2\t0\t-\nchr2\t400\t436\tr3\t0\t+\n";
+    fs::write(&reads, lines).unwrap();
+    let pipeline = builder(&dir)
+        .extend(150)
+        .keep_downsampled_bed(true)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[reads]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    // r3 stops at the end of chr2
+    let kept = fs::read_to_string(dir.path().join("out/reads_downsampled.bed")).unwrap();
+    let spans: Vec<Vec<&str>> = kept.lines().map(|l| l.split('\t').take(3).collect()).collect();
+    assert_eq!(spans, [["chr1", "86", "236"], ["chr1", "100", "250"], ["chr2", "400", "500"]]);
+}
+
+#[test]
+fn stranded_tracks_split_by_strand_column() {
+    let dir = TempDir::new().unwrap();
+    let fragments = dir.path().join("x.bed");
+    let lines = "chr1\t0\t100\tf1\t0\t+\nchr1\t10\t40\tf2\t0\t-\nchr1\t60\t90\tf3\t0\t.\n";
+    fs::write(&fragments, lines).unwrap();
+    let pipeline = builder(&dir)
+        .stranded(true)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[fragments]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    // f3 has no strand, so neither track counts it
+    let counts = |name: &str| -> Vec<u32> {
+        let bins = read_bedgraph(&dir.path().join("out").join(name));
+        bins.iter().take(2).map(|bin| bin.3).collect()
+    };
+    assert_eq!(counts("x_fwd_50bp.bedGraph"), [1, 1]);
+    assert_eq!(counts("x_rev_50bp.bedGraph"), [1, 0]);
+}
+
+#[test]
+fn dedup_drops_repeated_fragments() {
+    let dir = TempDir::new().unwrap();
+    let fragments = dir.path().join("sample.bed");
+    let mut bed = fs::read_to_string(data("exact.bed")).unwrap();
+    bed.push_str("chr1\t0\t100\nchr2\t10\t20\n");
+    fs::write(&fragments, bed).unwrap();
+    let pipeline = builder(&dir)
+        .dedup(true)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[fragments]).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let sample = &report.qc.samples[0];
+    assert_eq!(sample.fragments, 3);
+    assert_eq!(sample.duplicate_fraction, Some(0.4));
+    let bins = read_bedgraph(&dir.path().join("out/sample_50bp.bedGraph"));
+    assert_eq!(bins[0], ("chr1".to_string(), 0, 50, 2));
+}
+
+#[test]
+fn spikein_normalization() {
+    let dir = TempDir::new().unwrap();
+    let exact = fs::read_to_string(data("exact.bed")).unwrap();
+    let mut files = Vec::new();
+    for (name, spikein) in [("a", 2), ("b", 4)] {
+        let path = dir.path().join(format!("{}.bed", name));
+        fs::write(&path, exact.clone() + &"ecoli\t0\t100\n".repeat(spikein)).unwrap();
+        files.push(path);
+    }
+    let pipeline = builder(&dir)
+        .spikein_chroms(vec!["ecoli".to_string()])
+        .spikein_normalize(true)
+        .filter_qc(true)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&files).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let spikein: Vec<_> = report.qc.samples.iter().map(|s| s.spikein_fragments).collect();
+    assert_eq!(spikein, [Some(2), Some(4)]);
+    assert_eq!(report.scale_factors[&files[0]], 1.0);
+    assert_eq!(report.scale_factors[&files[1]], 0.5);
+    // Two fragments in the first bin, halved for twice the spike-in
+    let tracks = read_outputs(&dir.path().join("out"), ".bedGraph");
+    assert!(tracks.iter().all(|(_, track)| !track.contains("ecoli")));
+    let first_bins: Vec<_> = tracks.iter().map(|(_, track)| track.lines().next()).collect();
+    assert_eq!(first_bins, [Some("chr1\t0\t50\t2"), Some("chr1\t0\t50\t1")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn cpm_scaling() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .bedgraph_to_bigwig(data("fake_bedGraphToBigWig.sh"))
+        .scale(Scale::Cpm)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("exact.bed")]).unwrap();
+    assert_eq!(report.scale_factors[&data("exact.bed")], 1e6 / 3.0);
+
+    // Two of the three fragments fall in the first bin
+    let text = fs::read_to_string(dir.path().join("out/exact_50bp.bw")).unwrap();
+    let first: Vec<&str> = text.lines().next().unwrap().split('\t').collect();
+    assert_eq!(first, ["chr1", "0", "50", "666666.7"]);
+}
+
+#[test]
+fn ucsc_bigwig() {
+    let available = Command::new("bedGraphToBigWig")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !available {
+        eprintln!("bedGraphToBigWig not found, skipping");
+        return;
+    }
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir).build().unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("s2.bed")]).unwrap();
+    assert_eq!(report.failures(), 0);
+    for name in ["s1", "s2"] {
+        assert!(dir.path().join(format!("out/{}_50bp.bw", name)).exists());
+    }
+}
+
+#[test]
+fn draws_record_seed_and_retained_count() {
+    let run = || {
+        let dir = TempDir::new().unwrap();
+        let pipeline = builder(&dir).native_bigwig(true).build().unwrap();
+        let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+        let draw = |name| report.draws[&data(name)].clone();
+        (draw("s1.bed"), draw("mid.bed"))
+    };
+    let (s1, mid) = run();
+    assert_eq!((s1.retained, mid.retained), (60, 60));
+    assert_eq!(s1.samtools_fraction, None);
+    assert_ne!(s1.seed, mid.seed);
+    assert_eq!(run().0.seed, s1.seed);
+}
+
+#[test]
+fn empty_library_target_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let empty = dir.path().join("empty.bed");
+    fs::write(&empty, "chrom\tstart\tend\n").unwrap();
+    let pipeline = builder(&dir).native_bigwig(true).build().unwrap();
+    let Err(Error::Config(message)) = pipeline.run_bed(&[data("s1.bed"), empty.clone()]) else {
+        panic!("expected a configuration error");
+    };
+    assert!(message.contains("--min-fragments"), "{}", message);
+
+    // Excluding the empty library leaves a usable target
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .qc(QcParams {
+            min_fragments: Some(1),
+            ..QcParams::default()
+        })
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), empty]).unwrap();
+    assert_eq!(report.plan.target, 100);
+}
+
+#[test]
+fn dry_run_plans_without_writing() {
+    let dir = TempDir::new().unwrap();
+    let pipeline = builder(&dir)
+        .dry_run(true)
+        .qc_report(dir.path().join("qc.tsv"))
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+
+    assert_eq!(report.plan.target, 60);
+    let fractions: Vec<_> = report
+        .plan
+        .samples
+        .iter()
+        .map(|s| (s.sample.as_str(), s.fraction))
+        .collect();
+    assert_eq!(fractions, vec![("s1", 0.6), ("mid", 1.0)]);
+    assert_eq!(report.plan.samples[0].outputs, vec![dir.path().join("out/s1_50bp.bw")]);
+    assert!(report.outcomes.is_empty());
+    // Not even the output, temp or report paths are created
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn explicit_targets() {
+    let files = [data("s1.bed"), data("s2.bed"), data("mid.bed")];
+    let plan = |target: &str| {
+        let dir = TempDir::new().unwrap();
+        let pipeline = builder(&dir)
+            .dry_run(true)
+            .target(parse_target(target).unwrap())
+            .build()
+            .unwrap();
+        let report = pipeline.run_bed(&files).unwrap();
+        let fractions: Vec<f64> = report.plan.samples.iter().map(|s| s.fraction).collect();
+        (report.plan.target, fractions)
+    };
+    assert_eq!(plan("min"), (60, vec![0.6, 0.6, 1.0]));
+    // mid is below the median and keeps all of its fragments
+    assert_eq!(plan("median"), (100, vec![1.0, 1.0, 1.0]));
+    assert_eq!(plan("percentile:25"), (80, vec![0.8, 0.8, 1.0]));
+    assert_eq!(plan("count:50"), (50, vec![0.5, 0.5, 50.0 / 60.0]));
+    assert!(parse_target("percentile:101").is_err());
+    assert!(parse_target("count:0").is_err());
+}
+
+#[test]
+fn reference_target() {
+    let files = [data("s1.bed"), data("s2.bed"), data("s3.bed"), data("low.bed")];
+    let run = |reference: &str| {
+        let dir = TempDir::new().unwrap();
+        builder(&dir)
+            .dry_run(true)
+            .target(Target::Reference(reference.to_string()))
+            .build()
+            .unwrap()
+            .run_bed(&files)
+    };
+    assert_eq!(run("s2").unwrap().plan.target, 100);
+    assert_eq!(run("s3.bed").unwrap().plan.target, 100);
+    // low is excluded by QC, so it can't anchor the other samples
+    assert!(matches!(run("low"), Err(Error::Config(_))));
+    assert!(matches!(run("missing"), Err(Error::Config(_))));
+}
+
+#[test]
+fn count_only_writes_qc_report() {
+    let dir = TempDir::new().unwrap();
+    let qc_report = dir.path().join("qc.tsv");
+    let pipeline = builder(&dir)
+        .count_only(true)
+        .qc_report(qc_report.clone())
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(&[data("s1.bed"), data("mid.bed")]).unwrap();
+    assert_eq!(report.plan.target, 60);
+    assert!(report.outcomes.is_empty());
+
+    // The QC report is the only thing written; no tool is needed either
+    let rows = fs::read_to_string(&qc_report).unwrap();
+    assert_eq!(rows.lines().count(), 3);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn rerun_skips_existing_outputs() {
+    let dir = TempDir::new().unwrap();
+    let files = [data("s1.bed"), data("mid.bed")];
+    let pipeline = builder(&dir).native_bigwig(true).build().unwrap();
+    let report = pipeline.run_bed(&files).unwrap();
+    assert_eq!(report.outcomes.len(), 2);
+    assert!(report.skipped.is_empty());
+
+    // An empty bigWig, as left by an interrupted write, is regenerated
+    let mid = dir.path().join("out/mid_50bp.bw");
+    fs::write(&mid, "").unwrap();
+    let report = pipeline.run_bed(&files).unwrap();
+    assert_eq!(report.skipped, vec![data("s1.bed")]);
+    assert_eq!(report.outcomes.len(), 1);
+    assert!(fs::metadata(&mid).unwrap().len() > 0);
+
+    let pipeline = builder(&dir).native_bigwig(true).force(true).build().unwrap();
+    let report = pipeline.run_bed(&files).unwrap();
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.outcomes.len(), 2);
+}
+
+#[test]
+fn config_file_fills_in_options() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("run.toml");
+    let manifest = dir.path().join("manifest.tsv");
+    let settings = format!(
+        "chrom-sizes = {:?}\noutdir = {:?}\nmanifest = {:?}\nfiles = [{:?}]\n\
+         bin_size = 100\nseed = 7\noutput_format = \"bedgraph\"\n",
+        data("chrom.sizes"),
+        dir.path().join("out"),
+        manifest,
+        data("exact.bed")
+    );
+    fs::write(&config, settings).unwrap();
+    // The command line wins over the file
+    let status = Command::new(env!("CARGO_BIN_EXE_bedfragment_ds"))
+        .args(["bed", "--no-progress", "--bin-size", "50", "--config"])
+        .arg(&config)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(dir.path().join("out/exact_50bp.bedGraph").exists());
+    let text = fs::read_to_string(&manifest).unwrap();
+    assert!(text.contains("#seed\t7\n"));
+    assert!(text.contains("#setting.bin_size\t50\n"));
+    assert!(text.contains("#setting.output_format\t\"bedgraph\"\n"));
+
+    fs::write(&config, "bin_sise = 100\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_bedfragment_ds"))
+        .args(["bed", "--config"])
+        .arg(&config)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn config_file_globals_and_env() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("run.toml");
+    let settings = format!(
+        "chrom_sizes = {:?}\noutdir = {:?}\nfiles = {:?}\nbin_size = 100\nverbose = 1\n\
+         no_progress = true\nbedgraphtobigwig_path = \"/nonexistent/bedGraphToBigWig\"\n\
+         bamcoverage_path = \"/nonexistent/bamCoverage\"\n",
+        data("chrom.sizes"),
+        dir.path().join("out"),
+        data("exact.bed")
+    );
+    fs::write(&config, settings).unwrap();
+    // The environment wins over the file, and bamCoverage only applies to bam
+    let output = Command::new(env!("CARGO_BIN_EXE_bedfragment_ds"))
+        .args(["bed", "--config"])
+        .arg(&config)
+        .env("BEDGRAPHTOBIGWIG_PATH", data("fake_bedGraphToBigWig.sh"))
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(dir.path().join("out/exact_100bp.bw").exists());
+    // The global verbose setting turns on debug logging
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--config: bamcoverage_path doesn't apply to bed"), "{}", stderr);
+    assert!(stderr.contains("fake_bedGraphToBigWig.sh\"\n"));
+
+    // Values are checked against the option's type
+    for setting in ["bin_size = \"abc\"", "output_format = \"bigwog\"", "verbose = -1"] {
+        fs::write(&config, setting).unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_bedfragment_ds"))
+            .args(["bed", "--config"])
+            .arg(&config)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!status.success(), "{}", setting);
+    }
+}
+
+#[test]
+fn manifest_lists_samples_and_outputs() {
+    let dir = TempDir::new().unwrap();
+    let manifest = dir.path().join("manifest.tsv");
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .manifest(manifest.clone())
+        .build()
+        .unwrap();
+    let files = vec![data("s1.bed"), data("s2.bed"), data("s3.bed"), data("low.bed")];
+    pipeline.run_bed(&files).unwrap();
+
+    let text = fs::read_to_string(&manifest).unwrap();
+    let (header, rows): (Vec<&str>, Vec<&str>) = text.lines().partition(|l| l.starts_with('#'));
+    assert!(header.contains(&"#seed\t42"));
+    assert!(header.contains(&"#bin_size\t50"));
+    assert!(header.contains(&"#target\t100"));
+    let rows: Vec<Vec<&str>> = rows.iter().map(|row| row.split('\t').collect()).collect();
+    assert_eq!(
+        rows[0],
+        [
+            "file",
+            "inputs",
+            "sample",
+            "fragments",
+            "qc",
+            "fraction",
+            "samtools_fraction",
+            "seed",
+            "retained",
+            "scale_factor",
+            "status",
+            "outputs"
+        ]
+    );
+    let s1 = &rows[1];
+    assert_eq!(s1[2..7], ["s1", "100", "pass", "1", ""]);
+    assert!(s1[7].parse::<u64>().is_ok());
+    assert_eq!(s1[8..11], ["100", "", "ok"]);
+    assert_eq!(s1[11], dir.path().join("out/s1_50bp.bw").to_str().unwrap());
+    let low = rows.iter().find(|row| row[2] == "low").unwrap();
+    assert_eq!(low[3..], ["10", "low", "", "", "", "", "", "excluded", ""]);
+}
+
+#[test]
+fn timing_reports_each_stage() {
+    let dir = TempDir::new().unwrap();
+    let timing = dir.path().join("timing.tsv");
+    let pipeline = builder(&dir).native_bigwig(true).timing(timing.clone()).build().unwrap();
+    let files = vec![data("s1.bed"), data("s2.bed"), data("s3.bed"), data("low.bed")];
+    let report = pipeline.run_bed(&files).unwrap();
+
+    let stages: Vec<&str> = report.timings[&data("s1.bed")].iter().map(|(s, _)| *s).collect();
+    assert_eq!(stages, ["counting", "sampling", "sort", "coverage", "writing"]);
+    let text = fs::read_to_string(&timing).unwrap();
+    let rows: Vec<Vec<&str>> = text.lines().map(|row| row.split('\t').collect()).collect();
+    assert_eq!(
+        rows[0],
+        ["file", "sample", "counting", "sampling", "sort", "coverage", "writing", "total"]
+    );
+    assert_eq!(rows.len(), 5);
+    // The excluded sample was only counted
+    let low = rows.iter().find(|row| row[1] == "low").unwrap();
+    assert!(low[3..7].iter().all(|cell| cell.is_empty()));
+    assert!(low[2].parse::<f64>().is_ok() && low[7].parse::<f64>().is_ok());
+}
+
+#[test]
+fn events_stream_run_progress() {
+    let dir = TempDir::new().unwrap();
+    let events = dir.path().join("events.jsonl");
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .events(events.clone())
+        .build()
+        .unwrap();
+    pipeline.run_bed(&[data("s1.bed"), data("s2.bed")]).unwrap();
+
+    let lines: Vec<serde_json::Value> = fs::read_to_string(&events)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let names: Vec<&str> = lines.iter().map(|l| l["event"].as_str().unwrap()).collect();
+    assert_eq!(names.first(), Some(&"run_started"));
+    assert_eq!(lines[0]["inputs"], 2);
+    assert_eq!(names.last(), Some(&"run_finished"));
+    assert_eq!(lines.last().unwrap()["succeeded"], 2);
+    assert!(lines.iter().all(|l| l["timestamp"].as_f64().unwrap() > 0.0));
+    for sample in ["s1", "s2"] {
+        let of_sample = |event: &str| {
+            lines.iter().any(|l| l["event"] == event && l["sample"] == sample)
+        };
+        assert!(of_sample("sample_started"));
+        assert!(of_sample("sample_completed"));
+        assert!(lines.iter().any(|l| l["sample"] == sample && l["stage"] == "sampling"));
+    }
+}
+
+#[test]
+fn weighted_coverage_sums_scores() {
+    let dir = TempDir::new().unwrap();
+    let bed = dir.path().join("weights.bed");
+    fs::write(&bed, "chr1\t0\t100\ta\t1.5\t-\nchr1\t0\t100\tb\t2\t+\nchr1\t0\t50\tc\t1\t.\n")
+        .unwrap();
+    let pipeline = builder(&dir)
+        .weighted(true)
+        .keep_bedgraph(true)
+        .output_format(OutputFormat::Bedgraph)
+        .build()
+        .unwrap();
+    let report = pipeline.run_bed(std::slice::from_ref(&bed)).unwrap();
+    assert_eq!(report.failures(), 0);
+
+    let tracks = read_outputs(&dir.path().join("out"), ".bedGraph");
+    let bins: Vec<&str> = tracks[0].1.lines().take(3).collect();
+    assert_eq!(bins, ["chr1\t0\t50\t4.5", "chr1\t50\t100\t3.5", "chr1\t100\t150\t0"]);
+    // Ties on position are broken by strand
+    let sampled = read_outputs(&dir.path().join("tmp"), "_downsampled.bed");
+    let names: Vec<&str> = sampled[0].1.lines().map(|l| l.split('\t').nth(3).unwrap()).collect();
+    assert_eq!(names, ["c", "b", "a"]);
+
+    fs::write(&bed, "chr1\t0\t100\ta\t1\nchr1\t0\t100\tb\tn/a\n").unwrap();
+    let Err(Error::Counting(errors)) = pipeline.run_bed(&[bed]) else {
+        panic!("expected a counting error");
+    };
+    assert!(matches!(&errors[..], [(_, Error::Parse(msg))] if msg.contains("line 2")));
+}
+
+#[test]
+fn bedpe_pairs_become_fragments() {
+    let dir = TempDir::new().unwrap();
+    let bedpe = dir.path().join("pairs.bedpe");
+    let pairs = [
+        "chr1\t60\t100\tchr1\t0\t50\tp1\t7\t-\t+",
+        "chr1\t200\t250\tchr2\t0\t50\tp2\t7\t+\t-",
+        "chr2\t10\t20\tchr2\t0\t15",
+        "chr1\t5\t10",
+    ];
+    fs::write(&bedpe, pairs.join("\n")).unwrap();
+    for low_memory in [false, true] {
+        let pipeline = builder(&dir)
+            .bedpe(true)
+            .low_memory(low_memory)
+            .keep_bedgraph(true)
+            .output_format(OutputFormat::Bedgraph)
+            .force(true)
+            .build()
+            .unwrap();
+        let report = pipeline.run_bed(std::slice::from_ref(&bedpe)).unwrap();
+        assert_eq!(report.qc.samples[0].fragments, 2);
+        assert_eq!(report.malformed_lines.get(&bedpe), Some(&1));
+        let sampled = read_outputs(&dir.path().join("tmp"), "_downsampled.bed");
+        assert_eq!(sampled[0].1, "chr1\t0\t100\tp1\t7\nchr2\t0\t20\n");
+    }
+}
+
+#[test]
+fn normalization_checks() {
+    let dir = TempDir::new().unwrap();
+    let rpgc = builder(&dir).normalize(Normalization::Rpgc).build();
+    assert!(matches!(rpgc, Err(Error::Config(_))));
+    let rpgc = builder(&dir).normalize(Normalization::Rpgc).genome(Genome::Hg38).build();
+    assert!(rpgc.is_ok());
+
+    // bamCoverage does the normalizing, so BED input refuses it
+    let pipeline = builder(&dir)
+        .native_bigwig(true)
+        .normalize(Normalization::Cpm)
+        .build()
+        .unwrap();
+    let result = pipeline.run_bed(&[data("s1.bed")]);
+    assert!(matches!(result, Err(Error::Config(_))));
+}